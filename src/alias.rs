@@ -0,0 +1,105 @@
+use rand::RngCore;
+
+use crate::dictionary::{random_unit, sample_index};
+
+/////////////////////////////////////////////////////////////////////////////
+// AliasTable Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// AliasTable picks indices with probability proportional to their weights
+/// in constant time, after a setup linear in the number of weights, using
+/// Vose's alias method. Every index owns a column holding its own share of
+/// the probability and, to fill it up, an alias whose weight overflowed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AliasTable {
+    probability: Vec<f64>,
+    alias: Vec<usize>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// AliasTable Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl AliasTable {
+    // new builds the table for the weights, or returns None if no weight is
+    // positive. Negative weights count as 0.
+    pub fn new(weights: &[f64]) -> Option<AliasTable> {
+        let total: f64 = weights.iter().map(|w| w.max(0.0)).sum();
+        if total.is_nan() || total <= 0.0 {
+            return None;
+        }
+        let n = weights.len();
+        let mut scaled: Vec<f64> = weights
+            .iter()
+            .map(|w| w.max(0.0) * n as f64 / total)
+            .collect();
+        let mut probability = vec![1.0; n];
+        let mut alias: Vec<usize> = (0..n).collect();
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) =
+            (0..n).partition(|&i| scaled[i] < 1.0);
+        while let (Some(s), Some(&l)) = (small.pop(), large.last()) {
+            probability[s] = scaled[s];
+            alias[s] = l;
+            scaled[l] -= 1.0 - scaled[s];
+            if scaled[l] < 1.0 {
+                large.pop();
+                small.push(l);
+            }
+        }
+        // Whatever is left is 1 but for rounding errors, and keeps its column
+        // whole.
+        Some(AliasTable { probability, alias })
+    }
+
+    pub fn len(&self) -> usize {
+        self.probability.len()
+    }
+
+    // sample picks an index, with probability proportional to its weight.
+    pub fn sample(&self, rng: &mut dyn RngCore) -> usize {
+        let column =
+            sample_index(self.probability.len(), rng).expect("alias tables are never empty");
+        if random_unit(rng) < self.probability[column] {
+            column
+        } else {
+            self.alias[column]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::BorgRng;
+
+    #[test]
+    fn test_alias_table() {
+        let weights = [1.0, 2.0, 0.0, 3.0, 4.0];
+        let table = AliasTable::new(&weights).unwrap();
+        assert_eq!(5, table.len());
+
+        let mut rng = BorgRng::new(7);
+        let draws = 200_000;
+        let mut counts = vec![0usize; weights.len()];
+        for _ in 0..draws {
+            counts[table.sample(&mut rng)] += 1;
+        }
+        let total: f64 = weights.iter().sum();
+        for (count, weight) in counts.iter().zip(weights.iter()) {
+            let expected = weight / total;
+            let observed = *count as f64 / draws as f64;
+            assert!(
+                (observed - expected).abs() < 0.005,
+                "{:?} against {:?}",
+                counts,
+                weights
+            );
+        }
+        assert_eq!(0, counts[2]);
+
+        assert_eq!(None, AliasTable::new(&[]));
+        assert_eq!(None, AliasTable::new(&[0.0, -1.0]));
+        let single = AliasTable::new(&[0.5]).unwrap();
+        assert_eq!(0, single.sample(&mut rng));
+    }
+}
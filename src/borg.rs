@@ -1,9 +1,20 @@
-use crate::config::{BehaviorValueResolver, MainBehavior};
+use crate::cache::ReplyCache;
+use crate::config::{BehaviorValueResolver, LearningOnlyUntil, MainBehavior, MessageKind};
+use crate::metrics::Metrics;
+use crate::preprocess;
+use crate::rng::BorgRng;
+use crate::throttle::LearnThrottle;
 use crate::{
-    config::BehaviorOverrideValueResolver, dictionary::Dictionary, pattern, rand_core::RngCore,
+    config::BehaviorOverrideValueResolver, dictionary, dictionary::Dictionary,
+    dictionary::Provenance, pattern, rand_core::RngCore,
 };
-use rand::rngs::SmallRng;
-use rand_core::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /////////////////////////////////////////////////////////////////////////////
 // Borg Type
@@ -12,7 +23,61 @@ use rand_core::SeedableRng;
 pub struct Borg {
     dictionary: Dictionary,
     behavior: MainBehavior,
-    rng: SmallRng,
+    rng: BorgRng,
+    learn_throttle: LearnThrottle,
+    metrics: Metrics,
+    reply_cache: ReplyCache,
+    // The latest messages replied to, oldest first.
+    recent: VecDeque<String>,
+    // When the messages within the adaptive reply rate's window were seen,
+    // oldest first.
+    message_times: VecDeque<SystemTime>,
+    // The latest replies, oldest first, within the repetition window.
+    recent_replies: VecDeque<String>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// BorgState Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// Everything needed to bring a Borg back exactly as it was, e.g. on another
+/// host. Learning limits start over on restore.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BorgState {
+    pub behavior: MainBehavior,
+    pub dictionary: Dictionary,
+    pub dictionary_options: dictionary::Options,
+    pub rng: BorgRng,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// SelfTestReport Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// What Borg::self_test found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTestReport {
+    /// Number of random known words that were replied to.
+    pub samples: usize,
+    /// Number of samples that got a reply.
+    pub replies: usize,
+    /// Average number of words of the replies, or 0 if there were none.
+    pub average_words: f64,
+    /// Number of samples for which building a reply panicked.
+    pub panics: usize,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// ProvenanceRecord Struct
+/////////////////////////////////////////////////////////////////////////////
+
+// A line of the provenance log.
+#[derive(Serialize)]
+struct ProvenanceRecord<'a> {
+    at: u64,
+    input: &'a str,
+    reply: &'a str,
+    sources: &'a [(usize, String)],
 }
 
 /////////////////////////////////////////////////////////////////////////////
@@ -25,16 +90,414 @@ impl Borg {
         Borg {
             dictionary,
             behavior,
-            rng: SmallRng::from_entropy(),
+            rng: BorgRng::from_entropy(),
+            learn_throttle: LearnThrottle::new(),
+            metrics: Metrics::default(),
+            reply_cache: ReplyCache::new(),
+            recent: VecDeque::new(),
+            message_times: VecDeque::new(),
+            recent_replies: VecDeque::new(),
         }
     }
 
+    pub fn snapshot(&self) -> BorgState {
+        BorgState {
+            behavior: self.behavior.clone(),
+            dictionary: self.dictionary.clone(),
+            dictionary_options: self.dictionary.options().clone(),
+            rng: self.rng.clone(),
+        }
+    }
+
+    pub fn restore(state: BorgState) -> Borg {
+        let mut dictionary = state.dictionary;
+        dictionary.set_options(state.dictionary_options);
+        Borg {
+            dictionary,
+            behavior: state.behavior,
+            rng: state.rng,
+            learn_throttle: LearnThrottle::new(),
+            metrics: Metrics::default(),
+            reply_cache: ReplyCache::new(),
+            recent: VecDeque::new(),
+            message_times: VecDeque::new(),
+            recent_replies: VecDeque::new(),
+        }
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.metrics
+    }
+
+    // save_if_dirty saves the dictionary to the path if it changed since it
+    // was loaded or last saved.
+    pub fn save_if_dirty(&mut self, path: &Path) -> Result<bool, dictionary::Error> {
+        self.dictionary.save_if_dirty(path)
+    }
+
     pub fn respond_to(&mut self, line: &str) -> Option<String> {
-        self.dictionary.respond_to(line, &mut self.rng)
+        self.respond_to_at(line, SystemTime::now())
+    }
+
+    // respond_to_at replies to the line as if it was received at the given
+    // time. Nothing is said during quiet hours.
+    pub fn respond_to_at(&mut self, line: &str, now: SystemTime) -> Option<String> {
+        let line = self.preprocess(line);
+        self.reply_at(&line, None, now)
+    }
+
+    // respond_to_user replies to a line sent by the user, favoring what the
+    // user taught if the dictionary's user_bias option is set.
+    pub fn respond_to_user(&mut self, user: &str, line: &str) -> Option<String> {
+        let line = self.preprocess(line);
+        self.reply_at(&line, Some(user), SystemTime::now())
+    }
+
+    // preprocess applies the configured input transforms to an incoming line.
+    fn preprocess(&self, line: &str) -> String {
+        preprocess::apply_all(line, &self.behavior.input_transforms)
+    }
+
+    // reply_at replies to a line that has already been preprocessed. Replies
+    // personalized for a user are not cached, as they would be served to
+    // everyone.
+    fn reply_at(&mut self, line: &str, user: Option<&str>, now: SystemTime) -> Option<String> {
+        let context = self.remember(line);
+        if let Some(quiet_hours) = &self.behavior.quiet_hours {
+            if quiet_hours.is_quiet(now) {
+                debug!("[respond_to] Quiet hours, not replying to {:?}", line);
+                self.metrics.replies_suppressed += 1;
+                return None;
+            }
+        }
+        if line.trim().chars().count() < self.behavior.min_input_chars
+            || line.split_whitespace().count() < self.behavior.min_input_words
+        {
+            debug!("[respond_to] Input too short, not replying to {:?}", line);
+            self.metrics.replies_suppressed += 1;
+            return None;
+        }
+        if self.is_learning_only(now) {
+            debug!(
+                "[respond_to] Still learning only, not replying to {:?}",
+                line
+            );
+            self.metrics.replies_suppressed += 1;
+            return None;
+        }
+        // Replies built for a user or a conversation would be wrong for others.
+        let personal = user.is_some() && self.dictionary.options().user_bias > 0.0;
+        let reply = match self.behavior.reply_cache {
+            Some(ref config) if !personal && context.is_empty() => {
+                let input = self.dictionary.normalize_line(line);
+                match self.reply_cache.get(&input, config, now) {
+                    // The sentences a cached reply was built from were
+                    // already noted when it was first built.
+                    Some(reply) => Some(Provenance {
+                        reply: reply.to_owned(),
+                        sources: vec![],
+                    }),
+                    None => {
+                        let reply = self
+                            .dictionary
+                            .respond_to_safe(line, &[], None, &mut self.rng);
+                        if let Some(ref reply) = reply {
+                            self.reply_cache.insert(&input, &reply.reply, config, now);
+                        }
+                        reply
+                    }
+                }
+            }
+            _ => self
+                .dictionary
+                .respond_to_safe(line, &context, user, &mut self.rng),
+        };
+        let reply = reply.map(|provenance| {
+            self.dictionary.note_reply(&provenance);
+            provenance.reply
+        });
+        if let Some(ref reply) = reply {
+            self.metrics.replies += 1;
+            self.note_repetition(reply);
+            if let Some(ref path) = self.behavior.provenance_log {
+                if let Err(e) = self.log_provenance(Path::new(path), line, reply, now) {
+                    warn!(
+                        "[respond_to] Couldn't write provenance to {:?}: {}",
+                        path, e
+                    );
+                }
+            }
+        }
+        reply
+    }
+
+    // note_repetition remembers the reply among the latest ones, and has the
+    // dictionary disfavor the words they share according to the repetition
+    // penalty.
+    fn note_repetition(&mut self, reply: &str) {
+        let window = self.behavior.repetition_window;
+        if window == 0 || self.behavior.repetition_penalty <= 0.0 {
+            return;
+        }
+        self.recent_replies.push_back(reply.to_owned());
+        while self.recent_replies.len() > window {
+            self.recent_replies.pop_front();
+        }
+        let mut replies_with: HashMap<String, i32> = HashMap::new();
+        for reply in &self.recent_replies {
+            let words: HashSet<String> = self
+                .dictionary
+                .normalize_line(reply)
+                .split(' ')
+                .filter(|w| !w.is_empty())
+                .map(|w| w.to_owned())
+                .collect();
+            for word in words {
+                *replies_with.entry(word).or_default() += 1;
+            }
+        }
+        let keep = 1.0 - self.behavior.repetition_penalty.min(1.0);
+        let fatigue = replies_with
+            .into_iter()
+            .map(|(word, n)| (word, keep.powi(n)))
+            .collect();
+        self.dictionary.set_word_fatigue(fatigue);
+    }
+
+    // log_provenance appends the reply to the line, along with the sentences
+    // it was built from, to the provenance log at the path.
+    fn log_provenance(
+        &self,
+        path: &Path,
+        line: &str,
+        reply: &str,
+        now: SystemTime,
+    ) -> io::Result<()> {
+        let provenance = self.dictionary.provenance(reply);
+        let record = ProvenanceRecord {
+            at: now
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            input: line,
+            reply,
+            sources: &provenance.sources,
+        };
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        serde_json::to_writer(&mut file, &record)?;
+        writeln!(file)
+    }
+
+    // remember adds the line to the recent messages, forgetting the oldest
+    // ones beyond the configured number, and returns the messages that came
+    // before it.
+    fn remember(&mut self, line: &str) -> Vec<String> {
+        let context: Vec<String> = self.recent.iter().cloned().collect();
+        self.recent.push_back(line.to_owned());
+        while self.recent.len() > self.behavior.context_messages {
+            self.recent.pop_front();
+        }
+        context
+    }
+
+    // is_learning_only returns whether the warm-up period, during which
+    // nothing is said, is still going on at the given time.
+    fn is_learning_only(&self, now: SystemTime) -> bool {
+        match self.behavior.learning_only_until {
+            None => false,
+            Some(LearningOnlyUntil::Time(until)) => now < UNIX_EPOCH + Duration::from_secs(until),
+            Some(LearningOnlyUntil::Messages(count)) => self.metrics.messages_learned < count,
+        }
+    }
+
+    // self_test replies to the given number of random known words, bypassing
+    // the behavior settings, and reports how it went. It is meant as a quick
+    // check that a freshly loaded dictionary works. The metrics are left
+    // alone.
+    pub fn self_test(&mut self, samples: usize) -> SelfTestReport {
+        let mut report = SelfTestReport {
+            samples,
+            replies: 0,
+            average_words: 0.0,
+            panics: 0,
+        };
+        let mut words = 0;
+        for _ in 0..samples {
+            let word = match self.dictionary.random_word(&mut self.rng) {
+                Some(word) => word.to_owned(),
+                None => continue,
+            };
+            let dictionary = &self.dictionary;
+            let rng = &mut self.rng;
+            match panic::catch_unwind(AssertUnwindSafe(|| dictionary.respond_to(&word, rng))) {
+                Ok(Some(reply)) => {
+                    report.replies += 1;
+                    words += reply.split_whitespace().count();
+                }
+                Ok(None) => {}
+                Err(_) => {
+                    error!("[self_test] Panicked replying to {:?}", word);
+                    report.panics += 1;
+                }
+            }
+        }
+        if report.replies > 0 {
+            report.average_words = words as f64 / report.replies as f64;
+        }
+        report
+    }
+
+    pub fn random_sentence(&mut self) -> Option<&str> {
+        self.dictionary.random_sentence(&mut self.rng)
+    }
+
+    // random_walk walks the dictionary from word to word for text between
+    // min_words and max_words words long, as Dictionary::random_walk does.
+    pub fn random_walk(&mut self, min_words: usize, max_words: usize) -> Option<String> {
+        self.dictionary
+            .random_walk(min_words, max_words, &mut self.rng)
+    }
+
+    // handle_message replies to a message of the given kind sent by the given
+    // source in one call, learning it first, as learn_message does, if the
+    // behavior asks for it. Messages are counted as seen when deciding
+    // whether to reply to them, not here.
+    pub fn handle_message(
+        &mut self,
+        source: &str,
+        line: &str,
+        kind: MessageKind,
+    ) -> Option<String> {
+        self.handle_quoting(source, line, None, kind)
+    }
+
+    // handle_quoting is handle_message for a message that may quote another,
+    // which is learned as learn_quoting learns it.
+    pub fn handle_quoting(
+        &mut self,
+        source: &str,
+        line: &str,
+        quoted: Option<&str>,
+        kind: MessageKind,
+    ) -> Option<String> {
+        let now = SystemTime::now();
+        if self.behavior.learn_before_respond {
+            let learned = match quoted {
+                Some(quoted) => self.unquote(line, quoted),
+                None => line.to_owned(),
+            };
+            self.learn_message_at(source, &learned, kind, now);
+        }
+        let line = self.preprocess(line);
+        self.reply_at(&line, Some(source), now)
+    }
+
+    // learns_before_responding returns whether handle_message learns the
+    // messages it replies to. Platforms learn them after replying otherwise.
+    pub fn learns_before_responding(&self) -> bool {
+        self.behavior.learn_before_respond
     }
 
     pub fn learn(&mut self, line: &str) {
-        self.dictionary.learn(line);
+        let line = self.preprocess(line);
+        self.learn_at(&line, None, SystemTime::now());
+    }
+
+    // learn_at learns a line that has already been preprocessed, sent by the
+    // given source if it is known. Returns whether anything was learned.
+    fn learn_at(&mut self, line: &str, source: Option<&str>, now: SystemTime) -> bool {
+        self.metrics.messages_learned += 1;
+        let learned = match source {
+            Some(source) => self.dictionary.learn_from_user(source, line, now),
+            None => self.dictionary.learn_at(line, now),
+        };
+        if learned && !self.reply_cache.is_empty() {
+            let words: Vec<String> = self
+                .dictionary
+                .normalize_line(line)
+                .split(' ')
+                .map(|w| w.to_owned())
+                .collect();
+            self.reply_cache.invalidate(&words);
+        }
+        learned
+    }
+
+    // learn_from learns a line sent by the given source, unless the source
+    // has already taught too much recently. Returns whether the line was
+    // learned.
+    pub fn learn_from(&mut self, source: &str, line: &str) -> bool {
+        self.learn_from_at(source, line, SystemTime::now())
+    }
+
+    // learn_batch learns the lines, each sent by the source it comes with,
+    // as learn_from does. Returns the number of lines learned.
+    pub fn learn_batch(&mut self, lines: Vec<(String, String)>) -> usize {
+        let now = SystemTime::now();
+        let mut learned = 0;
+        for (source, line) in &lines {
+            if self.learn_from_at(source, line, now) {
+                learned += 1;
+            }
+        }
+        learned
+    }
+
+    // learns_kind returns whether messages of the kind are learned from.
+    pub fn learns_kind(&self, kind: MessageKind) -> bool {
+        self.behavior.learned_kinds.contains(&kind)
+    }
+
+    // learn_message learns a message of the given kind sent by the given
+    // source, as learn_from does, if messages of its kind are learned from.
+    pub fn learn_message(&mut self, source: &str, line: &str, kind: MessageKind) -> bool {
+        self.learn_message_at(source, line, kind, SystemTime::now())
+    }
+
+    fn learn_message_at(
+        &mut self,
+        source: &str,
+        line: &str,
+        kind: MessageKind,
+        now: SystemTime,
+    ) -> bool {
+        if !self.learns_kind(kind) {
+            debug!("[learn_message] Not learning {:?} message {:?}", kind, line);
+            return false;
+        }
+        self.learn_from_at(source, line, now)
+    }
+
+    // learn_quoting learns a message sent by the given source that quotes
+    // another, as learn_from does. Unless the behavior says to skip quotes,
+    // the quoted text is learned along with the rest.
+    pub fn learn_quoting(&mut self, source: &str, line: &str, quoted: &str) -> bool {
+        let line = self.unquote(line, quoted);
+        self.learn_from(source, &line)
+    }
+
+    // unquote returns the part of a message quoting another that
+    // learn_quoting would learn, for callers that learn it later.
+    pub fn unquote(&self, line: &str, quoted: &str) -> String {
+        if !self.behavior.skip_quoted {
+            return line.to_owned();
+        }
+        strip_quote(line, quoted)
+    }
+
+    pub fn learn_from_at(&mut self, source: &str, line: &str, now: SystemTime) -> bool {
+        let line = &self.preprocess(line);
+        if let Some(limit) = &self.behavior.learn_limit {
+            let sentences = dictionary::sentence_count(line);
+            if !self.learn_throttle.allow(source, sentences, limit, now) {
+                debug!(
+                    "[learn_from] Source {:?} is over its learning limit, not learning {:?}",
+                    source, line
+                );
+                return false;
+            }
+        }
+        self.learn_at(line, Some(source), now)
     }
 
     pub fn should_learn(
@@ -90,6 +553,74 @@ impl Borg {
         user_id: &str,
         input: &str,
         behavior: &Option<BehaviorOverrideValueResolver>,
+    ) -> bool {
+        self.should_reply_to_at(user_id, input, behavior, SystemTime::now())
+    }
+
+    // should_reply_to_at decides whether to reply to a message seen at the
+    // given time.
+    pub fn should_reply_to_at(
+        &mut self,
+        user_id: &str,
+        input: &str,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+        now: SystemTime,
+    ) -> bool {
+        self.metrics.messages_seen += 1;
+        self.note_message(now);
+        let reply = self.decide_reply(user_id, input, behavior, now);
+        if !reply {
+            self.metrics.replies_suppressed += 1;
+        }
+        reply
+    }
+
+    // note_message records that a message was seen at the given time, for
+    // the adaptive reply rate, forgetting those that fell out of its window.
+    fn note_message(&mut self, now: SystemTime) {
+        let window = match self.behavior.adaptive_reply_rate {
+            Some(ref adaptive) => Duration::from_secs(adaptive.window_secs),
+            None => return,
+        };
+        self.message_times.push_back(now);
+        while let Some(at) = self.message_times.front() {
+            match now.duration_since(*at) {
+                Ok(elapsed) if elapsed >= window => {
+                    self.message_times.pop_front();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    // effective_reply_rate returns the reply rate adjusted for the number of
+    // messages seen within the adaptive reply rate's window. The reply rate is
+    // the chance out of 100 of not replying, as chance() treats it, so it is
+    // the chance of replying, what is left up to 100, that is scaled down.
+    pub fn effective_reply_rate(&self, reply_rate: f32, now: SystemTime) -> f32 {
+        let adaptive = match self.behavior.adaptive_reply_rate {
+            Some(ref adaptive) => adaptive,
+            None => return reply_rate,
+        };
+        let window = Duration::from_secs(adaptive.window_secs);
+        let messages = self
+            .message_times
+            .iter()
+            .filter(|at| matches!(now.duration_since(**at), Ok(elapsed) if elapsed < window))
+            .count();
+        if messages <= adaptive.calm_messages {
+            reply_rate
+        } else {
+            100.0 - (100.0 - reply_rate) * adaptive.calm_messages as f32 / messages as f32
+        }
+    }
+
+    fn decide_reply(
+        &mut self,
+        user_id: &str,
+        input: &str,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+        now: SystemTime,
     ) -> bool {
         let b = BehaviorValueResolver::new(&self.behavior, behavior);
         debug!(
@@ -146,7 +677,7 @@ impl Borg {
             }
         }
 
-        let reply_rate = b.reply_rate();
+        let reply_rate = self.effective_reply_rate(b.reply_rate(), now);
         debug!("[should_reply_to] Reply rate: {:?}", reply_rate);
         return if chance(reply_rate, &mut self.rng) {
             debug!("[should_reply_to] Decided to reply to reply rate");
@@ -158,7 +689,574 @@ impl Borg {
     }
 }
 
-fn chance(chance: f32, rng: &mut SmallRng) -> bool {
+fn chance(chance: f32, rng: &mut BorgRng) -> bool {
     let p = rng.next_u32() % 100;
     p as f32 > chance || p == 100
 }
+
+// strip_quote removes the quoted text from the message, whether it is
+// included as is, within quotation marks or as lines starting with ">", as
+// Markdown quotes are.
+fn strip_quote(line: &str, quoted: &str) -> String {
+    let quoted = quoted.trim();
+    if quoted.is_empty() {
+        return line.to_owned();
+    }
+    let unquoted = line
+        .lines()
+        .filter(|l| match l.trim_start().strip_prefix('>') {
+            Some(rest) => !quoted.contains(rest.trim()),
+            None => true,
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let forms = [
+        format!("\"{}\"", quoted),
+        format!("\u{201c}{}\u{201d}", quoted),
+        quoted.to_owned(),
+    ];
+    match forms.iter().find(|form| unquoted.contains(form.as_str())) {
+        Some(form) => unquoted.replacen(form.as_str(), "", 1).trim().to_owned(),
+        None => unquoted.trim().to_owned(),
+    }
+}
+
+// test_behavior is a behavior that speaks and learns but never replies on
+// its own, for tests to build on.
+#[cfg(test)]
+pub(crate) fn test_behavior() -> MainBehavior {
+    MainBehavior {
+        speaking: true,
+        learning: true,
+        reply_rate: 0.0,
+        reply_nick: 0.0,
+        reply_magic: 0.0,
+        nick_patterns: vec![],
+        magic_patterns: vec![],
+        blacklisted_patterns: vec![],
+        ignored_users: vec![],
+        learn_before_respond: false,
+        learn_limit: None,
+        quiet_hours: None,
+        reply_cache: None,
+        input_transforms: vec![],
+        learning_only_until: None,
+        min_input_chars: 0,
+        min_input_words: 0,
+        context_messages: 0,
+        provenance_log: None,
+        skip_quoted: false,
+        adaptive_reply_rate: None,
+        repetition_window: 0,
+        repetition_penalty: 0.0,
+        learned_kinds: vec![MessageKind::Text, MessageKind::Caption],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_borg(behavior: MainBehavior) -> Borg {
+        Borg {
+            dictionary: Dictionary::new_empty(),
+            behavior,
+            rng: BorgRng::new(0),
+            learn_throttle: LearnThrottle::new(),
+            metrics: Metrics::default(),
+            reply_cache: ReplyCache::new(),
+            recent: VecDeque::new(),
+            message_times: VecDeque::new(),
+            recent_replies: VecDeque::new(),
+        }
+    }
+
+    #[test]
+    fn test_handle_message() {
+        let message = "Hello world. Hello world!";
+
+        let mut borg = test_borg(test_behavior());
+        assert_eq!(
+            None,
+            borg.handle_message("someone", message, MessageKind::Text)
+        );
+        assert_eq!(None, borg.respond_to(message));
+
+        let mut borg = test_borg(MainBehavior {
+            learn_before_respond: true,
+            ..test_behavior()
+        });
+        assert!(borg
+            .handle_message("someone", message, MessageKind::Text)
+            .is_some());
+        assert!(borg.respond_to(message).is_some());
+
+        // Messages are learned as learn_message learns them, within the
+        // source's learning limit and only if their kind is learned from.
+        let mut borg = test_borg(MainBehavior {
+            learn_before_respond: true,
+            learn_limit: Some(crate::config::LearnLimit {
+                max_sentences: 1,
+                window_secs: 60,
+            }),
+            ..test_behavior()
+        });
+        borg.handle_message("spammer", "Cats purr.", MessageKind::Text);
+        borg.handle_message("spammer", "Dogs bark.", MessageKind::Text);
+        borg.handle_message("someone", "Josh joined the group.", MessageKind::System);
+        assert!(borg.dictionary.contains_word("cats"));
+        assert!(!borg.dictionary.contains_word("dogs"));
+        assert!(!borg.dictionary.contains_word("joined"));
+
+        // Quoted text is learned as learn_quoting learns it.
+        let mut borg = test_borg(MainBehavior {
+            learn_before_respond: true,
+            skip_quoted: true,
+            ..test_behavior()
+        });
+        let quoted = "Pineapple on pizza is great.";
+        borg.handle_quoting(
+            "someone",
+            "> Pineapple on pizza is great.\nNo, it is terrible.",
+            Some(quoted),
+            MessageKind::Text,
+        );
+        assert!(borg.dictionary.contains_sentence("no, it is terrible."));
+        assert!(!borg.dictionary.contains_word("pineapple"));
+    }
+
+    #[test]
+    fn test_learn_limit() {
+        use crate::config::LearnLimit;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let mut borg = test_borg(MainBehavior {
+            learn_limit: Some(LearnLimit {
+                max_sentences: 2,
+                window_secs: 60,
+            }),
+            ..test_behavior()
+        });
+        let t0 = UNIX_EPOCH + Duration::from_secs(1000);
+
+        assert!(borg.learn_from_at("spammer", "One. Two.", t0));
+        assert!(!borg.learn_from_at("spammer", "Three.", t0 + Duration::from_secs(30)));
+        assert!(borg.learn_from_at("someone", "Four.", t0 + Duration::from_secs(30)));
+        assert!(borg.learn_from_at("spammer", "Five.", t0 + Duration::from_secs(60)));
+
+        assert!(borg.dictionary.last_seen("two").is_some());
+        assert!(borg.dictionary.last_seen("three").is_none());
+        assert!(borg.dictionary.last_seen("four").is_some());
+        assert!(borg.dictionary.last_seen("five").is_some());
+    }
+
+    #[test]
+    fn test_learn_message() {
+        let mut borg = test_borg(MainBehavior {
+            learned_kinds: vec![MessageKind::Caption],
+            ..test_behavior()
+        });
+        assert!(borg.learn_message("user", "My cat on the sofa.", MessageKind::Caption));
+        assert!(!borg.learn_message("user", "Josh joined the group.", MessageKind::System));
+        assert!(!borg.learn_message("user", "Random chatter.", MessageKind::Text));
+        assert!(borg.dictionary.contains_sentence("my cat on the sofa."));
+        assert!(!borg.dictionary.contains_word("joined"));
+        assert!(!borg.dictionary.contains_word("chatter"));
+
+        let mut borg = test_borg(test_behavior());
+        assert!(borg.learn_message("user", "Random chatter.", MessageKind::Text));
+        assert!(!borg.learn_message("user", "Josh joined the group.", MessageKind::System));
+        // Nothing is learned from a sentence already known.
+        assert!(!borg.learn_message("user", "Random chatter.", MessageKind::Text));
+    }
+
+    #[test]
+    fn test_learn_quoting() {
+        let quoted = "Pineapple on pizza is great.";
+        let message = "> Pineapple on pizza is great.\nNo, it is terrible.";
+
+        let mut borg = test_borg(MainBehavior {
+            skip_quoted: true,
+            ..test_behavior()
+        });
+        assert_eq!("No, it is terrible.", borg.unquote(message, quoted));
+        assert!(borg.learn_quoting("user", message, quoted));
+        assert!(borg.dictionary.contains_sentence("no, it is terrible."));
+        assert!(!borg.dictionary.contains_word("pineapple"));
+
+        borg.learn_quoting("user", &format!("\"{}\" Nope, gross.", quoted), quoted);
+        assert!(borg.dictionary.contains_sentence("nope, gross."));
+        assert!(!borg.dictionary.contains_word("pineapple"));
+
+        let mut borg = test_borg(test_behavior());
+        assert_eq!(message, borg.unquote(message, quoted));
+        borg.learn_quoting("user", message, quoted);
+        assert!(borg.dictionary.contains_word("pineapple"));
+    }
+
+    #[test]
+    fn test_learn_queue() {
+        use crate::learn_queue::LearnQueue;
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+
+        let borg = Arc::new(Mutex::new(test_borg(test_behavior())));
+        let queue = LearnQueue::new();
+        let producers: Vec<_> = (0..4)
+            .map(|p| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    for i in 0..25 {
+                        queue.push(&format!("user{}", p), &format!("word{}x{} is here.", p, i));
+                    }
+                })
+            })
+            .collect();
+        let flusher = {
+            let (borg, queue) = (borg.clone(), queue.clone());
+            thread::spawn(move || {
+                let mut learned = 0;
+                for _ in 0..50 {
+                    learned += queue.flush(&mut borg.lock().unwrap());
+                    thread::yield_now();
+                }
+                learned
+            })
+        };
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let learned = flusher.join().unwrap() + queue.flush(&mut borg.lock().unwrap());
+
+        assert_eq!(100, learned);
+        assert!(queue.is_empty());
+        let borg = borg.lock().unwrap();
+        for p in 0..4 {
+            for i in 0..25 {
+                let word = format!("word{}x{}", p, i);
+                assert!(borg.dictionary.contains_word(&word), "{}", word);
+            }
+        }
+        assert_eq!(100, borg.metrics.messages_learned);
+    }
+
+    #[test]
+    fn test_context_messages() {
+        let mut borg = test_borg(MainBehavior {
+            context_messages: 2,
+            ..test_behavior()
+        });
+        borg.learn("Cats sleep all day. Cats are cute. I love pizza.");
+        assert_eq!(None, borg.respond_to("what do you think"));
+        assert!(borg.respond_to("my cats are sleeping").is_some());
+        let reply = borg.respond_to("what do you think").unwrap();
+        assert!(reply.contains("cats"), "{:?}", reply);
+
+        borg.respond_to("one");
+        borg.respond_to("two");
+        assert_eq!(None, borg.respond_to("what do you think"));
+    }
+
+    #[test]
+    fn test_quiet_hours() {
+        use crate::schedule::{QuietHours, QuietPeriod, TimeOfDay};
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let mut borg = test_borg(MainBehavior {
+            quiet_hours: Some(QuietHours {
+                utc_offset_minutes: 0,
+                periods: vec![QuietPeriod {
+                    days: vec![],
+                    start: TimeOfDay::new(22, 0).unwrap(),
+                    end: TimeOfDay::new(6, 0).unwrap(),
+                }],
+            }),
+            ..test_behavior()
+        });
+        let midnight = UNIX_EPOCH + Duration::from_secs(86400);
+        let noon = midnight + Duration::from_secs(12 * 3600);
+        let message = "Hello world. Hello world!";
+
+        assert!(borg.learn_from_at("someone", message, midnight));
+        assert_eq!(None, borg.respond_to_at(message, midnight));
+        assert!(borg.respond_to_at(message, noon).is_some());
+    }
+
+    #[test]
+    fn test_adaptive_reply_rate() {
+        use crate::config::AdaptiveReplyRate;
+        use std::time::UNIX_EPOCH;
+
+        let mut borg = test_borg(MainBehavior {
+            reply_rate: 40.0,
+            adaptive_reply_rate: Some(AdaptiveReplyRate {
+                window_secs: 60,
+                calm_messages: 2,
+            }),
+            ..test_behavior()
+        });
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let mut rates = vec![];
+        for secs in &[0, 10, 20, 25, 30, 35] {
+            borg.should_reply_to_at("someone", "hello", &None, at(*secs));
+            rates.push(borg.effective_reply_rate(40.0, at(*secs)));
+        }
+        assert_eq!(vec![40.0, 40.0, 60.0, 70.0, 76.0, 80.0], rates);
+
+        // The channel calms down as messages leave the window.
+        assert_eq!(70.0, borg.effective_reply_rate(40.0, at(75)));
+        assert_eq!(40.0, borg.effective_reply_rate(40.0, at(95)));
+
+        // The bot replies less in a busy channel than in a calm one.
+        let replies = |adaptive_reply_rate| {
+            let mut borg = test_borg(MainBehavior {
+                reply_rate: 20.0,
+                adaptive_reply_rate,
+                ..test_behavior()
+            });
+            (0..200)
+                .filter(|i| borg.should_reply_to_at("someone", "hello", &None, at(i / 10)))
+                .count()
+        };
+        let calm = replies(None);
+        let busy = replies(Some(AdaptiveReplyRate {
+            window_secs: 60,
+            calm_messages: 10,
+        }));
+        assert!(calm > 140, "{}", calm);
+        assert!(busy < calm / 4, "{} against {}", busy, calm);
+
+        let mut borg = test_borg(test_behavior());
+        for secs in 0..10 {
+            borg.should_reply_to_at("someone", "hello", &None, at(secs));
+        }
+        assert_eq!(40.0, borg.effective_reply_rate(40.0, at(10)));
+        assert!(borg.message_times.is_empty());
+    }
+
+    #[test]
+    fn test_repetition_guard() {
+        let text = "Pizza is great. Pizza rocks hard. Pasta tastes fine. Pasta seems nice.";
+        let topics = |borg: &mut Borg| -> Vec<bool> {
+            (0..12)
+                .map(|_| borg.respond_to("pizza or pasta").unwrap().contains("pizza"))
+                .collect()
+        };
+
+        let mut borg = test_borg(test_behavior());
+        borg.learn(text);
+        let unguarded = topics(&mut borg);
+        assert!(unguarded.windows(2).any(|w| w[0] == w[1]));
+
+        let mut borg = test_borg(MainBehavior {
+            repetition_window: 1,
+            repetition_penalty: 1.0,
+            ..test_behavior()
+        });
+        borg.learn(text);
+        let guarded = topics(&mut borg);
+        assert!(guarded.windows(2).all(|w| w[0] != w[1]), "{:?}", guarded);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut borg = test_borg(MainBehavior {
+            reply_rate: 50.0,
+            ..test_behavior()
+        });
+        borg.dictionary.set_options(dictionary::Options {
+            recency_bias: true,
+            ..Default::default()
+        });
+        borg.learn("I love pizza. Pizza is great. I love you. You are great.");
+        borg.respond_to("pizza");
+
+        let mut restored = Borg::restore(borg.snapshot());
+        assert!(restored.dictionary.options().recency_bias);
+        for line in &["pizza", "i love", "great", "you"] {
+            assert_eq!(borg.respond_to(line), restored.respond_to(line));
+            assert_eq!(
+                borg.should_reply_to("someone", line, &None),
+                restored.should_reply_to("someone", line, &None)
+            );
+        }
+    }
+
+    #[test]
+    fn test_snapshot_json_round_trip() {
+        let mut borg = test_borg(test_behavior());
+        borg.learn("I love pizza. Pizza is great.");
+
+        let json = serde_json::to_string(&borg.snapshot()).unwrap();
+        let mut restored = Borg::restore(serde_json::from_str(&json).unwrap());
+        assert_eq!(borg.dictionary, restored.dictionary);
+        assert_eq!(borg.respond_to("pizza"), restored.respond_to("pizza"));
+    }
+
+    #[test]
+    fn test_metrics() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let mut borg = test_borg(MainBehavior {
+            speaking: false,
+            ..test_behavior()
+        });
+        borg.learn("Hello world. Hello world!");
+        assert!(!borg.should_reply_to("someone", "hello", &None));
+        assert!(borg.respond_to("hello").is_some());
+        assert!(borg.respond_to("nothing").is_none());
+
+        assert_eq!(
+            Metrics {
+                messages_seen: 1,
+                messages_learned: 1,
+                replies: 1,
+                replies_suppressed: 1,
+            },
+            borg.metrics()
+        );
+
+        let mut borg = test_borg(MainBehavior {
+            learn_before_respond: true,
+            ..test_behavior()
+        });
+        borg.should_reply_to("someone", "Hello world. Hello world!", &None);
+        borg.handle_message("someone", "Hello world. Hello world!", MessageKind::Text);
+        borg.learn_from_at("someone", "Goodbye.", UNIX_EPOCH + Duration::from_secs(1));
+        assert_eq!(
+            Metrics {
+                messages_seen: 1,
+                messages_learned: 2,
+                replies: 1,
+                replies_suppressed: 0,
+            },
+            borg.metrics()
+        );
+    }
+
+    #[test]
+    fn test_reply_cache() {
+        use crate::config::ReplyCacheConfig;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let mut borg = test_borg(MainBehavior {
+            reply_cache: Some(ReplyCacheConfig {
+                capacity: 10,
+                ttl_secs: 60,
+            }),
+            ..test_behavior()
+        });
+        let t0 = UNIX_EPOCH + Duration::from_secs(1000);
+        borg.learn_from_at("someone", "Cats sleep. Cats eat fish. Big cats roar.", t0);
+
+        let first = borg.respond_to_at("cats", t0).unwrap();
+        for i in 0..10 {
+            let t = t0 + Duration::from_secs(i * 5);
+            assert_eq!(Some(&first), borg.respond_to_at("Cats!", t).as_ref());
+        }
+        assert_eq!(1, borg.reply_cache.len());
+
+        // Learning about something else keeps the reply, learning about cats
+        // drops it.
+        borg.learn_from_at("someone", "Dogs bark.", t0);
+        assert_eq!(1, borg.reply_cache.len());
+        borg.learn_from_at("someone", "Cats purr.", t0);
+        assert!(borg.reply_cache.is_empty());
+    }
+
+    #[test]
+    fn test_input_transforms() {
+        use crate::preprocess::InputTransform;
+
+        let mut borg = test_borg(MainBehavior {
+            input_transforms: vec![InputTransform::ReplaceWord {
+                from: "u".to_string(),
+                to: "you".to_string(),
+            }],
+            ..test_behavior()
+        });
+        borg.learn("I love u. U are great.");
+        assert!(borg.dictionary.last_seen("you").is_some());
+        assert!(borg.dictionary.last_seen("u").is_none());
+
+        // "u" is matched as "you", which is known.
+        assert!(borg.respond_to("what about u").is_some());
+        assert!(borg.respond_to("what about me").is_none());
+    }
+
+    #[test]
+    fn test_learning_only_until() {
+        let t0 = UNIX_EPOCH + Duration::from_secs(1000);
+        let message = "Hello world. Hello world!";
+
+        let mut borg = test_borg(MainBehavior {
+            learning_only_until: Some(LearningOnlyUntil::Time(2000)),
+            ..test_behavior()
+        });
+        borg.learn_from_at("someone", message, t0);
+        assert_eq!(None, borg.respond_to_at(message, t0));
+        assert_eq!(
+            None,
+            borg.respond_to_at(message, t0 + Duration::from_secs(999))
+        );
+        assert!(borg
+            .respond_to_at(message, t0 + Duration::from_secs(1000))
+            .is_some());
+
+        let mut borg = test_borg(MainBehavior {
+            learning_only_until: Some(LearningOnlyUntil::Messages(2)),
+            ..test_behavior()
+        });
+        borg.learn_from_at("someone", message, t0);
+        assert_eq!(None, borg.respond_to_at(message, t0));
+        borg.learn_from_at("someone", "Goodbye.", t0);
+        assert!(borg.respond_to_at(message, t0).is_some());
+    }
+
+    #[test]
+    fn test_min_input_length() {
+        let mut borg = test_borg(MainBehavior {
+            learn_before_respond: true,
+            min_input_chars: 4,
+            min_input_words: 2,
+            ..test_behavior()
+        });
+        let text = MessageKind::Text;
+        assert!(borg
+            .handle_message("someone", "Ok then. Ok then!", text)
+            .is_some());
+        assert_eq!(None, borg.handle_message("someone", "ok", text));
+        assert!(borg.dictionary.contains_sentence("ok"));
+        assert_eq!(None, borg.handle_message("someone", "okay", text));
+        assert!(borg.dictionary.contains_sentence("okay"));
+        // Enough words, but too few characters.
+        assert_eq!(None, borg.handle_message("someone", "a b", text));
+        assert!(borg.dictionary.contains_sentence("a b"));
+    }
+
+    #[test]
+    fn test_self_test() {
+        let mut borg = test_borg(test_behavior());
+        assert_eq!(
+            SelfTestReport {
+                samples: 5,
+                replies: 0,
+                average_words: 0.0,
+                panics: 0,
+            },
+            borg.self_test(5)
+        );
+
+        borg.learn("Hello world. Hello world!");
+        assert_eq!(
+            SelfTestReport {
+                samples: 20,
+                replies: 20,
+                average_words: 2.0,
+                panics: 0,
+            },
+            borg.self_test(20)
+        );
+        assert_eq!(Metrics::default().replies, borg.metrics().replies);
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use crate::config::ReplyCacheConfig;
+
+/////////////////////////////////////////////////////////////////////////////
+// ReplyCache Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// ReplyCache remembers the replies given to recent inputs, so that an input
+/// that is repeated shortly after gets the same reply without building it
+/// again. When full, the least recently used reply is forgotten.
+#[derive(Debug, Default)]
+pub struct ReplyCache {
+    entries: HashMap<String, CacheEntry>,
+    // Counts lookups and insertions, to tell which entry was used last.
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    reply: String,
+    created_at: SystemTime,
+    last_used: u64,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// ReplyCache Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl ReplyCache {
+    pub fn new() -> ReplyCache {
+        ReplyCache::default()
+    }
+
+    // get returns the reply cached for the input, unless there is none or it
+    // is older than the configured time to live.
+    pub fn get(&mut self, input: &str, config: &ReplyCacheConfig, now: SystemTime) -> Option<&str> {
+        self.clock += 1;
+        let ttl = Duration::from_secs(config.ttl_secs);
+        let expired = match self.entries.get(input) {
+            None => return None,
+            Some(entry) => match now.duration_since(entry.created_at) {
+                Ok(age) => age >= ttl,
+                Err(_) => false,
+            },
+        };
+        if expired {
+            self.entries.remove(input);
+            return None;
+        }
+        let clock = self.clock;
+        self.entries.get_mut(input).map(|entry| {
+            entry.last_used = clock;
+            entry.reply.as_str()
+        })
+    }
+
+    pub fn insert(&mut self, input: &str, reply: &str, config: &ReplyCacheConfig, now: SystemTime) {
+        if config.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        while self.entries.len() >= config.capacity && !self.entries.contains_key(input) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(input, _)| input.clone());
+            match oldest {
+                Some(oldest) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+        self.entries.insert(
+            input.to_owned(),
+            CacheEntry {
+                reply: reply.to_owned(),
+                created_at: now,
+                last_used: self.clock,
+            },
+        );
+    }
+
+    // invalidate forgets the replies to the inputs that contain any of the
+    // given words, since what was learned could change them. Inputs are
+    // expected to be normalized words separated by spaces.
+    pub fn invalidate(&mut self, words: &[String]) {
+        self.entries
+            .retain(|input, _| !input.split(' ').any(|w| words.iter().any(|x| x == w)));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_reply_cache() {
+        let config = ReplyCacheConfig {
+            capacity: 2,
+            ttl_secs: 60,
+        };
+        let t0 = UNIX_EPOCH + Duration::from_secs(1000);
+        let mut cache = ReplyCache::new();
+
+        cache.insert("hello world", "hi", &config, t0);
+        cache.insert("good night", "bye", &config, t0);
+        assert_eq!(Some("hi"), cache.get("hello world", &config, t0));
+        // "good night" is the least recently used, so it makes room.
+        cache.insert("pizza", "yum", &config, t0);
+        assert_eq!(2, cache.len());
+        assert_eq!(None, cache.get("good night", &config, t0));
+
+        assert_eq!(
+            None,
+            cache.get("pizza", &config, t0 + Duration::from_secs(60))
+        );
+        cache.invalidate(&["world".to_string()]);
+        assert!(cache.is_empty());
+    }
+}
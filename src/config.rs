@@ -1,6 +1,9 @@
 use std::{error, fmt, fs, io, path::Path};
 
+use crate::dictionary;
 use crate::pattern::{CompilationError, Pattern};
+use crate::preprocess::InputTransform;
+use crate::schedule::QuietHours;
 
 use serde::{Deserialize, Serialize};
 
@@ -64,9 +67,23 @@ trait PatternOwner {
 pub struct Config {
     pub dictionary_path: String,
     pub auto_save_period: i64,
+    #[serde(default)]
+    pub dictionary_options: dictionary::Options,
+    /// Whether a new dictionary, made when there is none at dictionary_path,
+    /// learns the bundled default corpus so that the bot is not mute at first.
+    #[serde(default)]
+    pub seed_dictionary: bool,
+    /// When set, the lines to learn are queued and learned together every
+    /// this many milliseconds, instead of one at a time as they arrive.
+    #[serde(default)]
+    pub learn_queue_interval_ms: Option<u64>,
     pub behavior: MainBehavior,
     pub telegram: Option<TelegramPlatform>,
     pub discord: Option<DiscordPlatform>,
+    /// Every platform to serve. The telegram and discord sections above are
+    /// moved into this list when the configuration is loaded.
+    #[serde(default)]
+    pub platforms: Vec<PlatformConfig>,
 }
 
 /////////////////////////////////////////////////////////////////////////////
@@ -81,16 +98,38 @@ pub struct Config {
 impl Config {
     pub fn load(path: &Path) -> Result<Self, ConfigError> {
         let data = fs::read_to_string(&path)?;
-        let config = serde_yaml::from_str(&data)?;
+        Config::from_yaml(&data)
+    }
+
+    pub fn from_yaml(data: &str) -> Result<Self, ConfigError> {
+        let mut config: Config = serde_yaml::from_str(data)?;
+        config.collect_platforms();
         Ok(config)
     }
+
+    // collect_platforms moves the single-platform sections into the list of
+    // platforms, so that there is only one place to look for them.
+    fn collect_platforms(&mut self) {
+        if let Some(telegram) = self.telegram.take() {
+            self.platforms.insert(0, PlatformConfig::Telegram(telegram));
+        }
+        if let Some(discord) = self.discord.take() {
+            self.platforms.push(PlatformConfig::Discord(discord));
+        }
+    }
+
+    // enabled_platforms returns the configuration of every platform that
+    // should be started.
+    pub fn enabled_platforms(&self) -> Vec<&PlatformConfig> {
+        self.platforms.iter().filter(|p| p.is_enabled()).collect()
+    }
 }
 
 /////////////////////////////////////////////////////////////////////////////
 // MainBehavior Struct
 /////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MainBehavior {
     pub speaking: bool,
     pub learning: bool,
@@ -101,6 +140,139 @@ pub struct MainBehavior {
     pub magic_patterns: Vec<Pattern>,
     pub blacklisted_patterns: Vec<Pattern>,
     pub ignored_users: Vec<Pattern>,
+    /// Whether Borg::handle_message learns a message before replying to it.
+    #[serde(default)]
+    pub learn_before_respond: bool,
+    /// Caps how much a single source can teach in a period of time.
+    #[serde(default)]
+    pub learn_limit: Option<LearnLimit>,
+    /// Periods during which nothing is said, although learning goes on.
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+    /// When set, replies are reused for inputs that are repeated shortly
+    /// after.
+    #[serde(default)]
+    pub reply_cache: Option<ReplyCacheConfig>,
+    /// Rewrites applied, in order, to every incoming line before it is
+    /// learned or replied to. Ignore and blacklist patterns still see the
+    /// line as it was received.
+    #[serde(default)]
+    pub input_transforms: Vec<InputTransform>,
+    /// When set, nothing is said until this point, so that the bot quietly
+    /// learns for a while first.
+    #[serde(default)]
+    pub learning_only_until: Option<LearningOnlyUntil>,
+    /// Inputs shorter than this many characters, not counting surrounding
+    /// whitespace, are learned but not replied to.
+    #[serde(default)]
+    pub min_input_chars: usize,
+    /// Inputs with fewer words than this are learned but not replied to.
+    #[serde(default)]
+    pub min_input_words: usize,
+    /// How many of the messages replied to before are remembered as the
+    /// conversation's context. Replies may be built around the words of the
+    /// context, the latest messages being the most likely. 0 turns this off.
+    #[serde(default)]
+    pub context_messages: usize,
+    /// When set, every reply is written to the file at this path along with
+    /// the input and the sentences the reply was built from, one JSON object
+    /// per line, for moderators to review.
+    #[serde(default)]
+    pub provenance_log: Option<String>,
+    /// When set, the part of a message that quotes another, as given to
+    /// Borg::learn_quoting, is not learned, so that only what the message
+    /// adds is.
+    #[serde(default)]
+    pub skip_quoted: bool,
+    /// Scales the reply rate down as messages come in faster, so that busy
+    /// channels aren't spammed.
+    #[serde(default)]
+    pub adaptive_reply_rate: Option<AdaptiveReplyRate>,
+    /// How many of the latest replies are checked for words the bot keeps
+    /// repeating. 0 turns the check off.
+    #[serde(default)]
+    pub repetition_window: usize,
+    /// Fraction, between 0 and 1, by which the weight of a word shrinks, as
+    /// a pivot and in the sentences replies are built from, for each of the
+    /// latest replies it appeared in.
+    #[serde(default)]
+    pub repetition_penalty: f64,
+    /// The kinds of messages learned from by Borg::learn_message. Text
+    /// messages and captions by default.
+    #[serde(default = "learned_kinds_by_default")]
+    pub learned_kinds: Vec<MessageKind>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// MessageKind Enum
+/////////////////////////////////////////////////////////////////////////////
+
+/// What an incoming message is, on platforms that tell apart text typed by
+/// users from other text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    /// A message typed by a user.
+    Text,
+    /// The caption of a photo, video or other media.
+    Caption,
+    /// A message sent by the platform, e.g. when someone joins.
+    System,
+}
+
+fn learned_kinds_by_default() -> Vec<MessageKind> {
+    vec![MessageKind::Text, MessageKind::Caption]
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// LearnLimit Struct
+/////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LearnLimit {
+    /// Maximum number of sentences learned from a source per window.
+    pub max_sentences: usize,
+    /// Length of the window, in seconds.
+    pub window_secs: u64,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// AdaptiveReplyRate Struct
+/////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdaptiveReplyRate {
+    /// Length of the window messages are counted in, in seconds.
+    pub window_secs: u64,
+    /// Up to this many messages per window, the reply rate is used as is.
+    /// Beyond, the chance of replying is multiplied by this number divided by
+    /// the number of messages.
+    pub calm_messages: usize,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// LearningOnlyUntil Enum
+/////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LearningOnlyUntil {
+    /// Unix time, in seconds.
+    Time(u64),
+    /// Number of messages learned since the bot was started.
+    Messages(u64),
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// ReplyCacheConfig Struct
+/////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplyCacheConfig {
+    /// Maximum number of replies kept.
+    pub capacity: usize,
+    /// How long a reply is reused for, in seconds.
+    pub ttl_secs: u64,
 }
 
 /////////////////////////////////////////////////////////////////////////////
@@ -173,12 +345,58 @@ pub struct ChatBehaviorOverrides {
     pub behavior: BehaviorOverride,
 }
 
+/////////////////////////////////////////////////////////////////////////////
+// PlatformConfig Enum
+/////////////////////////////////////////////////////////////////////////////
+
+/// The configuration of one platform, tagged with the platform's name, e.g.
+/// `type: telegram`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PlatformConfig {
+    Telegram(TelegramPlatform),
+    Discord(DiscordPlatform),
+    Irc(IrcPlatform),
+    Tcp(TcpPlatform),
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// PlatformConfig Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl PlatformConfig {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PlatformConfig::Telegram(_) => "Telegram",
+            PlatformConfig::Discord(_) => "Discord",
+            PlatformConfig::Irc(_) => "IRC",
+            PlatformConfig::Tcp(_) => "TCP",
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        match *self {
+            PlatformConfig::Telegram(ref p) => p.enabled,
+            PlatformConfig::Discord(ref p) => p.enabled,
+            PlatformConfig::Irc(ref p) => p.enabled,
+            PlatformConfig::Tcp(ref p) => p.enabled,
+        }
+    }
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
 /////////////////////////////////////////////////////////////////////////////
 // Telegram Struct
 /////////////////////////////////////////////////////////////////////////////
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TelegramPlatform {
+    /// Lets a platform be switched off without removing its configuration.
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
     pub token: String,
     pub behavior: Option<BehaviorOverride>,
     pub chat_behaviors: Option<Vec<ChatBehaviorOverrides>>,
@@ -190,11 +408,46 @@ pub struct TelegramPlatform {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DiscordPlatform {
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
     pub token: String,
     pub behavior: Option<BehaviorOverride>,
     pub chat_behaviors: Option<Vec<ChatBehaviorOverrides>>,
 }
 
+/////////////////////////////////////////////////////////////////////////////
+// IRC Struct
+/////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IrcPlatform {
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    pub server: String,
+    pub port: u16,
+    pub nick: String,
+    pub channels: Vec<String>,
+    pub behavior: Option<BehaviorOverride>,
+    pub chat_behaviors: Option<Vec<ChatBehaviorOverrides>>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Tcp Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// A line-based TCP server, for bots that use Borg as a backend. Needs the
+/// tcp_server feature.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TcpPlatform {
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    /// Address to listen on, e.g. "127.0.0.1:7878".
+    pub address: String,
+    /// Whether to learn every line received before replying to it.
+    #[serde(default)]
+    pub learn: bool,
+}
+
 /////////////////////////////////////////////////////////////////////////////
 // BehaviorValues Struct
 /////////////////////////////////////////////////////////////////////////////
@@ -372,3 +625,53 @@ impl<'a> BehaviorOverrideValueResolver<'a> {
             .unwrap_or(self.behavior.ignored_users.as_ref())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_yaml_multiple_platforms() {
+        let config = Config::from_yaml(
+            r##"
+dictionary_path: dictionary.json
+auto_save_period: 300
+behavior:
+  speaking: true
+  learning: true
+  reply_rate: 1.0
+  reply_nick: 1.0
+  reply_magic: 1.0
+  nick_patterns: []
+  magic_patterns: []
+  blacklisted_patterns: []
+  ignored_users: []
+telegram:
+  token: telegram-token
+platforms:
+  - type: irc
+    server: irc.example.com
+    port: 6667
+    nick: borg
+    channels: ["#borg"]
+  - type: discord
+    enabled: false
+    token: discord-token
+"##,
+        )
+        .unwrap();
+
+        assert!(config.telegram.is_none());
+        assert_eq!(3, config.platforms.len());
+        let enabled: Vec<&str> = config
+            .enabled_platforms()
+            .iter()
+            .map(|p| p.name())
+            .collect();
+        assert_eq!(vec!["Telegram", "IRC"], enabled);
+        match config.enabled_platforms()[1] {
+            PlatformConfig::Irc(irc) => assert_eq!(vec!["#borg"], irc.channels),
+            p => panic!("expected IRC, got {:?}", p),
+        }
+    }
+}
@@ -0,0 +1,55 @@
+// CONFUSABLES maps lowercase letters from other scripts to the Latin letters
+// they are visually indistinguishable from. It only covers the lookalikes
+// that are commonly used to dodge filters, not the full Unicode table.
+const CONFUSABLES: &[(char, char)] = &[
+    // Cyrillic
+    ('а', 'a'),
+    ('е', 'e'),
+    ('о', 'o'),
+    ('р', 'p'),
+    ('с', 'c'),
+    ('у', 'y'),
+    ('х', 'x'),
+    ('і', 'i'),
+    ('ј', 'j'),
+    ('ѕ', 's'),
+    ('һ', 'h'),
+    ('ԁ', 'd'),
+    ('ԛ', 'q'),
+    ('ԝ', 'w'),
+    ('ӏ', 'l'),
+    // Greek
+    ('α', 'a'),
+    ('ο', 'o'),
+    ('ρ', 'p'),
+    ('ν', 'v'),
+    ('ι', 'i'),
+    ('κ', 'k'),
+];
+
+// fold replaces every confusable letter of the word with the Latin letter it
+// looks like.
+pub fn fold(word: &str) -> String {
+    word.chars()
+        .map(|c| {
+            CONFUSABLES
+                .iter()
+                .find(|(confusable, _)| *confusable == c)
+                .map(|(_, latin)| *latin)
+                .unwrap_or(c)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fold() {
+        // The first word is spelled with a Cyrillic "а" and "о".
+        assert_eq!("banana boat", fold("bаnana bоat"));
+        assert_eq!("plain", fold("plain"));
+        assert_eq!("日本", fold("日本"));
+    }
+}
@@ -1,12 +1,63 @@
+use crate::alias::AliasTable;
+use crate::confusables;
+use crate::gzip;
+use crate::indices::{intersect_sorted, union_sorted, Indices, Postings};
+use crate::language::{self, Language};
+use crate::pii;
+use crate::stemmer;
+use crate::storage::{FileStorage, Storage};
 use onig::Regex;
 use rand::RngCore;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize, Serializer};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::error;
 use std::fmt;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::io::Write;
+use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Names of the files a dictionary is made of when stored in the split format.
+const SPLIT_SENTENCES_FILE: &str = "sentences.json";
+const SPLIT_INDICES_FILE: &str = "indices.json";
+const SPLIT_LAST_SEEN_FILE: &str = "last_seen.json";
+const SPLIT_SENTENCE_META_FILE: &str = "sentence_meta.json";
+
+// DEFAULT_CORPUS is learned by new dictionaries when seeding is asked for.
+const DEFAULT_CORPUS: &str = include_str!("default_corpus.txt");
+
+// How much less likely each recent message is to have its words replied to
+// than the message after it.
+const CONTEXT_DECAY: f64 = 0.5;
+
+// How many times a reply is built before giving up on one that passes the
+// options that reject replies, such as max_word_repeats.
+const MAX_REPLY_ATTEMPTS: usize = 10;
+
+// The most words a reply of the interleave strategy has, pivot included.
+const MAX_INTERLEAVE_WORDS: usize = 16;
+
+// Input words shorter than this many characters aren't matched as prefixes,
+// as they would match too many words.
+const MIN_PREFIX_LEN: usize = 3;
+
+// The most words word_path goes through between its two ends, so that
+// searching a large dictionary for words that aren't related stays cheap.
+const MAX_WORD_PATH_HOPS: usize = 6;
+
+// Words that make an awkward opening for a reply, used when no function
+// words are configured.
+const DEFAULT_FUNCTION_WORDS: &[&str] = &[
+    "a", "an", "the", "and", "but", "or", "nor", "so", "yet", "because", "then", "of", "to", "in",
+    "on", "at", "for", "with", "from", "by", "about", "as", "into", "than",
+];
 
 #[derive(Debug)]
 pub enum Error {
@@ -44,12 +95,451 @@ impl From<serde_json::Error> for Error {
     }
 }
 
-type Indices = HashMap<String, Vec<usize>>;
+/// Tunables for how the dictionary learns and builds replies.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Options {
+    /// When set, pivots are picked among the most recently learned words
+    /// of the input instead of uniformly among all known words.
+    pub recency_bias: bool,
+    pub terminal_punctuation: TerminalPunctuation,
+    /// When set, runs of three or more repeated letters are shortened to
+    /// this many letters when indexing and matching words, so that with a
+    /// limit of 1 "soooo" and "so" are the same word. Stored sentences, and
+    /// therefore replies, keep the original spelling. Changing this requires
+    /// the indices to be rebuilt.
+    pub elongation_limit: Option<usize>,
+    /// What to try, in order, when none of the input's words are known.
+    /// When empty, nothing is said.
+    pub unknown_input_fallbacks: Vec<UnknownInputFallback>,
+    /// Words shorter than this many characters are only used as pivots when
+    /// none of the input's known words is long enough.
+    pub min_pivot_len: usize,
+    /// When set, several replies are generated and the one whose length is
+    /// closest to the target is said.
+    pub length_target: Option<LengthTarget>,
+    /// When set, letters from other scripts that look like Latin letters,
+    /// such as the Cyrillic "а", are read as the Latin letter when indexing
+    /// and matching words. Changing this requires the indices to be rebuilt.
+    pub fold_confusables: bool,
+    /// When not empty, replies are only spliced from sentences containing
+    /// at least one of these words.
+    pub topic_filter: Vec<String>,
+    /// Whether to stay silent, rather than ignore the topic filter, when no
+    /// sentence containing the pivot is on topic.
+    pub strict_topic: bool,
+    /// When set, plural endings are stripped from words when indexing and
+    /// matching them, so that "crab" and "crabs" are the same word. Replies
+    /// keep the original words. Changing this requires the indices to be
+    /// rebuilt.
+    pub stem_words: bool,
+    /// When set, sentences ending in a question mark are not learned.
+    pub skip_questions: bool,
+    /// When set and the input has two or more known words, replies join a
+    /// sentence containing one of them to a sentence containing another, so
+    /// that they touch on both.
+    pub bridge_pivots: bool,
+    /// When set, the left side of replies is taken from a sentence where the
+    /// pivot is not the first word, if there is one, so that replies don't
+    /// open abruptly with the pivot.
+    pub prefer_lead_in: bool,
+    /// When set, the left side of replies is taken from a sentence that
+    /// doesn't start with a function word, if there is one, so that replies
+    /// don't open with e.g. "and" or "of".
+    pub prefer_content_openings: bool,
+    /// The function words for prefer_content_openings. When empty, a
+    /// built-in list of English articles, conjunctions and prepositions is
+    /// used.
+    pub function_words: Vec<String>,
+    /// Sentences shorter than this many characters are not learned. The
+    /// length is measured on the sentence's normalized words, separated by
+    /// single spaces, so punctuation and elongations don't count.
+    pub min_learn_chars: usize,
+    /// When set, replies in which any word appears more than this many times
+    /// are thrown away and built again, up to a few times.
+    pub max_word_repeats: Option<usize>,
+    pub line_breaks: LineBreaks,
+    /// Nothing is said unless at least this fraction of the input's words
+    /// are known, e.g. 0.5 for half of them. The fallbacks for unknown input
+    /// are still used when no word is known.
+    pub min_overlap_ratio: f64,
+    pub code: CodeHandling,
+    /// Scales how likely each word is to be picked as a pivot. Words that are
+    /// not listed have a weight of 1, and a weight of 0 keeps a word from
+    /// being picked while any other word can be.
+    pub word_weights: HashMap<String, f64>,
+    /// When set, replies are sometimes followed by a second sentence that
+    /// shares a word with them.
+    pub follow_up: Option<FollowUp>,
+    /// When set, every emoji is a word of its own, even when written next to
+    /// other words or emoji. Changing this requires the indices to be
+    /// rebuilt.
+    pub emoji_tokens: bool,
+    /// When set, runs of punctuation such as "!!!" are words of their own
+    /// instead of being thrown away, so that the way people punctuate is
+    /// learned and repeated. Replies then end with the punctuation of the
+    /// sentence they end with, whatever terminal_punctuation says. Changing
+    /// this requires the indices to be rebuilt.
+    pub punctuation_tokens: bool,
+    /// Ways of replying to try in order, until one of them gives a reply.
+    /// When empty, replies are spliced as if it were [splice].
+    pub fallback_chain: Vec<ReplyStrategy>,
+    /// When set, duplicate sentences are removed from the dictionary when it
+    /// is loaded.
+    pub dedup_on_load: bool,
+    /// How strongly replies favor recently learned sentences. At 0 every
+    /// sentence containing the pivot is as likely to be used; above 0, the
+    /// n-th oldest of them is weighted n to the power of this bias.
+    pub sentence_recency_bias: f64,
+    /// No reply is spliced until at least this many words can be used as
+    /// pivots, since a young dictionary repeats itself. Random sentences and
+    /// static replies from the fallbacks are still said.
+    pub min_reply_space: usize,
+    /// When set, sentences are stored with their original capitalization,
+    /// so that names such as "Josh" keep it in replies. Words are matched
+    /// regardless of case either way.
+    pub preserve_case: bool,
+    /// When set, email addresses, phone numbers and credit card numbers are
+    /// replaced with placeholders such as "[email]" before sentences are
+    /// learned.
+    pub mask_pii: bool,
+    /// When set, each splice flips a coin to decide whether the two
+    /// sentences it picked swap sides, so that either can give the left side
+    /// of the reply.
+    pub swap_splice_sides: bool,
+    /// When set, the oldest sentences are forgotten before the dictionary is
+    /// written to disk, as many as needed to keep the file within this many
+    /// bytes.
+    pub max_file_bytes: Option<u64>,
+    /// When above 1, this many candidate replies are built and the one using
+    /// the most distinct known words of the input is said. Combined with a
+    /// length target, relevance comes first.
+    pub relevance_candidates: usize,
+    /// When set, replies that look like they are in another language are
+    /// thrown away and built again, up to a few times. Replies that are too
+    /// short to tell are kept.
+    pub reply_language: Option<Language>,
+    /// When set, the right side of replies is taken from a sentence that ends
+    /// with terminal punctuation, if there is one, so that replies don't end
+    /// abruptly on a fragment.
+    pub prefer_complete_endings: bool,
+    /// How much more likely respond_to_user is to build replies from the
+    /// sentences the user taught: their weight is 1 + user_bias, against 1
+    /// for the others. 0 turns this off.
+    pub user_bias: f64,
+    /// Phrases replies must never contain, e.g. slurs that splicing two
+    /// harmless sentences may happen to put together. Replies containing one
+    /// are thrown away and built again, up to a few times. Phrases are
+    /// matched on whole, normalized words.
+    pub blocked_phrases: Vec<String>,
+    /// Learning a known sentence again within this many seconds of the last
+    /// time it was counted doesn't add to its count, so that spamming a
+    /// sentence doesn't make it look popular. 0 counts every time.
+    pub count_debounce_secs: u64,
+    /// How many of the latest replies noted with note_reply have their
+    /// source sentences remembered. 0 turns tracking sources off.
+    pub source_use_window: usize,
+    /// Fraction, between 0 and 1, by which the weight of a sentence shrinks
+    /// for each of the remembered replies it was a source of, so that no
+    /// sentence dominates the replies. 0 turns this off.
+    pub source_use_penalty: f64,
+    /// When set, random sentences, e.g. those of the random sentence
+    /// strategy, are picked with a chance proportional to the number of
+    /// times each was learned, instead of uniformly.
+    pub weighted_random_sentences: bool,
+    /// When set, input words that aren't known are matched as prefixes of
+    /// known words, so that e.g. "pizz" is replied to as "pizza". This is
+    /// slower, unless the dictionary was warmed up.
+    pub prefix_matching: bool,
+}
+
+/// A preferred range of reply lengths, in words.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LengthTarget {
+    pub min_words: usize,
+    pub max_words: usize,
+    /// Number of candidate replies to choose from.
+    pub candidates: usize,
+}
+
+impl LengthTarget {
+    // deviation returns how many words the reply is away from the target
+    // range.
+    fn deviation(&self, reply: &str) -> usize {
+        let words = split_words(reply).len();
+        if words < self.min_words {
+            self.min_words - words
+        } else {
+            words.saturating_sub(self.max_words)
+        }
+    }
+}
+
+/// How line breaks in learned messages are treated.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LineBreaks {
+    /// Line breaks are like any other whitespace.
+    #[default]
+    Ignore,
+    /// Every line ends a sentence.
+    Line,
+    /// Blank lines end a sentence.
+    Paragraph,
+}
+
+/// What to do with code, written between backticks, in learned messages.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CodeHandling {
+    /// Code is learned like any other text.
+    #[default]
+    Learn,
+    /// Messages containing code are not learned at all.
+    Skip,
+    /// Code is removed from messages before they are learned.
+    Strip,
+}
+
+/// How replies are followed up with a related sentence.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FollowUp {
+    /// Probability, between 0 and 1, of following up a reply.
+    pub chance: f64,
+    /// Replies are only followed up if the result is at most this many
+    /// words long.
+    pub max_words: usize,
+}
+
+/// A way of replying to an input.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplyStrategy {
+    /// Splice a reply around a known word of the input, trying the fallbacks
+    /// for unknown input if there is none.
+    Splice,
+    /// Splice a reply around the known words closest in spelling to the
+    /// input's words.
+    Fuzzy,
+    /// Reply with a random known sentence.
+    RandomSentence,
+    /// Alternate the words of two sentences sharing a known word of the
+    /// input, on both sides of it, for a glitchy reply.
+    Interleave,
+    /// Reply with text made by walking from word to word, as random_walk
+    /// does, ending where a sentence ends once the walk has between
+    /// min_words and max_words words.
+    RandomWalk { min_words: usize, max_words: usize },
+    /// Reply with the given text.
+    Static(String),
+}
+
+/// A way of replying to an input whose words are all unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownInputFallback {
+    /// Reply off the known words closest in spelling to the input's words.
+    Fuzzy,
+    /// Reply with a random known sentence.
+    RandomSentence,
+}
+
+/// How the terminal punctuation of the sentence a reply ends with is carried
+/// over to the reply.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TerminalPunctuation {
+    /// Replies end without punctuation.
+    #[default]
+    Drop,
+    /// Replies end with the punctuation of their source, as written.
+    Keep,
+    /// Like Keep, but repeated marks such as "!!!" are collapsed into one.
+    Collapse,
+}
+
+/// Where a reply came from, for moderators to review.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Provenance {
+    pub reply: String,
+    /// Index and text of the sentences the words of the reply were taken
+    /// from, in the order they appear in the reply. Empty if the reply could
+    /// not be traced back, e.g. because it was not built from sentences.
+    pub sources: Vec<(usize, String)>,
+}
+
+/// A reply as it is built, along with the indices of the sentences its words
+/// were taken from, in the order they appear in it.
+struct Built {
+    text: String,
+    sources: Vec<usize>,
+}
+
+impl Built {
+    fn new(text: String, sources: Vec<usize>) -> Built {
+        Built { text, sources }
+    }
+}
+
+/// A change made to the list of sentences, which reindex_incremental brings
+/// the indices up to date with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SentenceChange {
+    /// A sentence was inserted at the index, moving the ones from there on up.
+    Added(usize),
+    /// The sentence was removed from the index, moving the ones after it
+    /// down.
+    Removed(usize, String),
+}
+
+/// Bookkeeping kept for every sentence, next to the sentence itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SentenceMeta {
+    /// Unix time, in seconds, at which the sentence was first learned, or 0
+    /// if it is not known.
+    pub learned_at: u64,
+    /// Number of times the sentence has been learned.
+    pub count: u32,
+    /// Session the sentence was first learned in, if it was learned with
+    /// learn_in_session.
+    pub session: Option<String>,
+    /// Users who taught the sentence, if it was learned with
+    /// learn_from_user.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<String>,
+    /// Unix time, in seconds, at which the sentence was last counted, or 0
+    /// if it is not known.
+    pub counted_at: u64,
+}
+
+impl Default for SentenceMeta {
+    fn default() -> Self {
+        SentenceMeta {
+            learned_at: 0,
+            count: 1,
+            session: None,
+            users: vec![],
+            counted_at: 0,
+        }
+    }
+}
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Dictionary {
     sentences: Vec<String>,
     indices: Indices,
+    /// Metadata of each sentence, in the same order as the sentences.
+    /// Dictionaries saved before it existed get default metadata on load.
+    #[serde(default)]
+    sentence_meta: Vec<SentenceMeta>,
+    /// Unix time, in seconds, at which each word was last learned.
+    #[serde(default, serialize_with = "serialize_sorted")]
+    last_seen: HashMap<String, u64>,
+    #[serde(skip)]
+    options: Options,
+    /// Structures derived from the sentences and indices, precomputed by
+    /// warm_up. Anything that changes the dictionary drops them.
+    #[serde(skip)]
+    warm: Option<WarmCache>,
+    /// Checksum of the dictionary when it was last loaded or saved by
+    /// save_if_dirty.
+    #[serde(skip)]
+    saved_checksum: Option<u64>,
+    /// Position of every sentence, to find sentences without scanning them.
+    /// Filled in on load and when the indices are rebuilt; until then, the
+    /// sentences are scanned.
+    #[serde(skip)]
+    sentence_positions: HashMap<String, usize>,
+    /// When case is preserved, the spellings each word was learned with and
+    /// how many times each was used.
+    #[serde(default, serialize_with = "serialize_sorted")]
+    word_forms: HashMap<String, Vec<(String, u32)>>,
+    /// Source sentences of the latest replies noted with note_reply, oldest
+    /// first.
+    #[serde(skip)]
+    source_uses: VecDeque<Vec<String>>,
+    /// Picks random sentences by their counts when weighted random
+    /// sentences are on. Dropped when the options are set and on learning,
+    /// and rebuilt when the next random sentence is picked.
+    #[serde(skip)]
+    sentence_sampler: RefCell<Option<AliasTable>>,
+    /// The words that come after each word in the sentences, for random
+    /// walks. Dropped along with the indices, and rebuilt when the next walk
+    /// is taken.
+    #[serde(skip)]
+    transitions: RefCell<Option<Transitions>>,
+    /// Multipliers of the weight of words overused in recent replies, as
+    /// pivots and in the sentences replies are built from. Words that are
+    /// missing have a multiplier of 1.
+    #[serde(skip)]
+    word_fatigue: HashMap<String, f64>,
+}
+
+/// AutoSaveGuard holds a dictionary and saves it when dropped, if it changed,
+/// so that what was learned is not lost when the program exits.
+pub struct AutoSaveGuard {
+    dictionary: Dictionary,
+    path: PathBuf,
+}
+
+impl AutoSaveGuard {
+    pub fn new(dictionary: Dictionary, path: &Path) -> AutoSaveGuard {
+        AutoSaveGuard {
+            dictionary,
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl Deref for AutoSaveGuard {
+    type Target = Dictionary;
+
+    fn deref(&self) -> &Dictionary {
+        &self.dictionary
+    }
+}
+
+impl DerefMut for AutoSaveGuard {
+    fn deref_mut(&mut self) -> &mut Dictionary {
+        &mut self.dictionary
+    }
+}
+
+impl Drop for AutoSaveGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.dictionary.save_if_dirty(&self.path) {
+            error!("Couldn't save dictionary on drop, error: {:?}", e);
+        }
+    }
+}
+
+/// Counts describing the size of a dictionary.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VocabularyStats {
+    pub words: usize,
+    pub sentences: usize,
+    /// Total number of word and sentence pairs in the indices.
+    pub postings: usize,
+}
+
+/// The words that come after each normalized word, or None where a sentence
+/// ends, along with the index of the sentence they were found in.
+type Transitions = HashMap<String, Vec<(Option<String>, usize)>>;
+
+#[derive(Debug, Default, Clone)]
+struct WarmCache {
+    // Words that appear in at least two sentences, i.e. that can be spliced
+    // around.
+    pivots: HashSet<String>,
+    stats: VocabularyStats,
+    // Every word with the number of sentences it appears in, the most
+    // frequent first.
+    doc_frequencies: Vec<(String, usize)>,
+    // Position of every word in doc_frequencies.
+    doc_frequency_ranks: HashMap<String, usize>,
+    // Every word, sorted, to find the words starting with a prefix.
+    sorted_words: Vec<String>,
 }
 
 impl PartialEq for Dictionary {
@@ -65,32 +555,303 @@ impl Dictionary {
     // If there is no file at the specified path, it will create a blank
     // dictionary at that location.
     pub fn load(path: &Path) -> Result<Self, Error> {
-        if !path.is_file() {
-            let d = Dictionary::new_empty();
-            d.write_to_disk(&path)?;
-            Ok(d)
-        } else {
-            let data = fs::read_to_string(path)?;
-            let dict: Dictionary = serde_json::from_str(&data)?;
-            Ok(dict)
+        Dictionary::load_or_seed(path, false)
+    }
+
+    // load_or_seed loads the dictionary at the path like load does. When there
+    // is none and seed is set, the new dictionary learns the bundled default
+    // corpus first, so that a fresh install has something to say.
+    pub fn load_or_seed(path: &Path, seed: bool) -> Result<Self, Error> {
+        Dictionary::load_from(&mut FileStorage::new(path), seed)
+    }
+
+    // load_from loads the dictionary kept in the storage. If there is none, a
+    // blank dictionary is saved to the storage and returned, seeded with the
+    // default corpus if asked to.
+    pub fn load_from(storage: &mut dyn Storage, seed: bool) -> Result<Self, Error> {
+        match storage.load()? {
+            Some(mut dict) => {
+                dict.fill_sentence_meta();
+                dict.index_sentences();
+                dict.saved_checksum = Some(dict.checksum());
+                Ok(dict)
+            }
+            None => {
+                let mut d = Dictionary::new_empty();
+                if seed {
+                    for line in DEFAULT_CORPUS.lines() {
+                        d.learn(line);
+                    }
+                }
+                d.save_to(storage)?;
+                Ok(d)
+            }
+        }
+    }
+
+    // write_to_disk writes the dictionary to the path, first pruning it to
+    // fit the maximum file size if one is set.
+    pub fn write_to_disk(&mut self, path: &Path) -> Result<(), Error> {
+        self.save_to(&mut FileStorage::new(path))
+    }
+
+    // save_to saves the dictionary to the storage, first pruning it to fit
+    // the maximum file size if one is set.
+    pub fn save_to(&mut self, storage: &mut dyn Storage) -> Result<(), Error> {
+        if let Some(max_bytes) = self.options.max_file_bytes {
+            let pruned = self.prune_to_fit(max_bytes)?;
+            if pruned > 0 {
+                warn!(
+                    "Forgot the {} oldest sentences to keep the dictionary within {} bytes.",
+                    pruned, max_bytes
+                );
+            }
+        }
+        storage.save(self)
+    }
+
+    // prune_to_fit forgets the oldest sentences, those whose learning time is
+    // not known first, until the dictionary serializes to at most max_bytes
+    // of JSON. Returns the number of sentences forgotten.
+    fn prune_to_fit(&mut self, max_bytes: u64) -> Result<usize, Error> {
+        let before = self.sentences.len();
+        let mut size = serialized_size(self)?;
+        while size > max_bytes && !self.sentences.is_empty() {
+            // Subtract what each sentence adds to the file from the size until
+            // it fits, then rebuild the indices and measure again once. The
+            // estimate leaves out the words that are no longer used at all, so
+            // a second pass is rarely needed.
+            self.fill_sentence_meta();
+            let mut by_age: Vec<usize> = (0..self.sentences.len()).collect();
+            by_age.sort_by_key(|&i| self.sentence_meta[i].learned_at);
+            let mut dropped: HashSet<usize> = HashSet::new();
+            for i in by_age {
+                if size <= max_bytes {
+                    break;
+                }
+                size = size.saturating_sub(self.serialized_sentence_size(i)?);
+                dropped.insert(i);
+            }
+            let mut kept_sentences = vec![];
+            let mut kept_meta = vec![];
+            let pairs = self.sentences.drain(..).zip(self.sentence_meta.drain(..));
+            for (i, (sentence, meta)) in pairs.enumerate() {
+                if !dropped.contains(&i) {
+                    kept_sentences.push(sentence);
+                    kept_meta.push(meta);
+                }
+            }
+            self.sentences = kept_sentences;
+            self.sentence_meta = kept_meta;
+            self.rebuild_indices();
+            size = serialized_size(self)?;
+        }
+        Ok(before - self.sentences.len())
+    }
+
+    // serialized_sentence_size estimates the bytes the sentence at index i
+    // takes in the dictionary's JSON: the sentence, its metadata and its id in
+    // the postings of each of its words, each with a separating comma.
+    fn serialized_sentence_size(&self, i: usize) -> Result<u64, Error> {
+        let words: HashSet<Cow<str>> = index_keys(&self.sentences[i], &self.options)
+            .into_iter()
+            .collect();
+        let id_size = i.to_string().len() as u64 + 1;
+        Ok(serialized_size(&self.sentences[i])?
+            + serialized_size(&self.sentence_meta[i])?
+            + 2
+            + words.len() as u64 * id_size)
+    }
+
+    // save_if_dirty writes the dictionary to the path if its sentences or
+    // indices changed since it was loaded or last saved by this function.
+    // Changes to metadata alone, such as when a word was last seen, are
+    // written with the next change to the sentences. Returns whether the
+    // dictionary was written.
+    pub fn save_if_dirty(&mut self, path: &Path) -> Result<bool, Error> {
+        let checksum = self.checksum();
+        if self.saved_checksum == Some(checksum) {
+            return Ok(false);
         }
+        self.write_to_disk(path)?;
+        // Writing may have pruned the dictionary.
+        self.saved_checksum = Some(self.checksum());
+        Ok(true)
     }
 
-    pub fn write_to_disk(&self, path: &Path) -> Result<(), Error> {
-        let json = serde_json::to_string(&self)?;
-        fs::write(path, json)?;
+    // write_split writes the dictionary to the given directory, storing the
+    // sentences and the indices in separate files so that the indices can be
+    // read on their own. The directory is created if it does not exist.
+    pub fn write_split(&self, dir: &Path) -> Result<(), Error> {
+        fs::create_dir_all(dir)?;
+        write_json(&dir.join(SPLIT_SENTENCES_FILE), &self.sentences)?;
+        write_json(&dir.join(SPLIT_INDICES_FILE), &self.indices)?;
+        write_json(&dir.join(SPLIT_LAST_SEEN_FILE), &sorted(&self.last_seen))?;
+        write_json(&dir.join(SPLIT_SENTENCE_META_FILE), &self.sentence_meta)?;
         Ok(())
     }
 
+    // load_split loads a dictionary written by write_split.
+    pub fn load_split(dir: &Path) -> Result<Self, Error> {
+        let mut dict = Dictionary {
+            sentences: read_json(&dir.join(SPLIT_SENTENCES_FILE))?,
+            indices: Dictionary::load_split_indices(dir)?,
+            last_seen: read_json_or_default(&dir.join(SPLIT_LAST_SEEN_FILE))?,
+            sentence_meta: read_json_or_default(&dir.join(SPLIT_SENTENCE_META_FILE))?,
+            ..Default::default()
+        };
+        dict.fill_sentence_meta();
+        dict.index_sentences();
+        Ok(dict)
+    }
+
+    // load_split_indices loads only the indices of a dictionary written by
+    // write_split, without reading its sentences.
+    pub fn load_split_indices(dir: &Path) -> Result<Indices, Error> {
+        read_json(&dir.join(SPLIT_INDICES_FILE))
+    }
+
+    // fill_sentence_meta gives default metadata to the sentences that have
+    // none, e.g. because they were saved before metadata was kept.
+    fn fill_sentence_meta(&mut self) {
+        self.sentence_meta
+            .resize(self.sentences.len(), SentenceMeta::default());
+    }
+
+    pub fn sentence_meta(&self, i: usize) -> Option<&SentenceMeta> {
+        self.sentence_meta.get(i)
+    }
+
     pub fn new_empty() -> Dictionary {
-        Dictionary {
-            sentences: vec![],
-            indices: HashMap::new(),
-        }
+        Dictionary::default()
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    pub fn set_options(&mut self, options: Options) {
+        self.options = options;
+        self.warm = None;
+        *self.sentence_sampler.get_mut() = None;
+        *self.transitions.get_mut() = None;
+    }
+
+    // build_sentence_sampler builds the table random sentences are picked
+    // from when weighted random sentences are on. Sentences without metadata
+    // count as learned once.
+    fn build_sentence_sampler(&self) -> Option<AliasTable> {
+        let counts: Vec<f64> = (0..self.sentences.len())
+            .map(|i| {
+                self.sentence_meta
+                    .get(i)
+                    .map_or(1, |meta| meta.count.max(1)) as f64
+            })
+            .collect();
+        AliasTable::new(&counts)
     }
 
     fn reset_indices(&mut self) {
-        self.indices = HashMap::new();
+        self.indices = Indices::new();
+        self.warm = None;
+        *self.transitions.get_mut() = None;
+    }
+
+    // warm_up precomputes the structures that replies are built from, so that
+    // the first reply after loading is as fast as the following ones.
+    pub fn warm_up(&mut self) {
+        let pivots = self
+            .indices
+            .iter()
+            .filter(|(word, _)| self.sentences_with_word(word).len() >= 2)
+            .map(|(word, _)| word.to_string())
+            .collect();
+        let doc_frequencies = self.compute_doc_frequencies();
+        let doc_frequency_ranks = doc_frequencies
+            .iter()
+            .enumerate()
+            .map(|(rank, (word, _))| (word.clone(), rank))
+            .collect();
+        let mut sorted_words: Vec<String> = self
+            .indices
+            .iter()
+            .map(|(word, _)| word.to_string())
+            .collect();
+        sorted_words.sort();
+        self.warm = Some(WarmCache {
+            pivots,
+            stats: self.compute_stats(),
+            doc_frequencies,
+            doc_frequency_ranks,
+            sorted_words,
+        });
+    }
+
+    // doc_frequency returns the number of sentences the word appears in.
+    pub fn doc_frequency(&self, word: &str) -> usize {
+        let word = normalize_word(&word.to_lowercase(), &self.options).into_owned();
+        match self.warm {
+            Some(ref warm) => warm
+                .doc_frequency_ranks
+                .get(&word)
+                .map(|&rank| warm.doc_frequencies[rank].1)
+                .unwrap_or(0),
+            None => self.indices.get(&word).map(|ys| ys.len()).unwrap_or(0),
+        }
+    }
+
+    // auto_stopwords returns the top_percent percent of the words that
+    // appear in the most sentences, rounded up, which are mostly words such
+    // as "the" that say little about what a sentence is about. Words as
+    // frequent as each other are taken in alphabetical order.
+    pub fn auto_stopwords(&self, top_percent: f64) -> HashSet<String> {
+        let computed;
+        let frequencies = match self.warm {
+            Some(ref warm) => &warm.doc_frequencies,
+            None => {
+                computed = self.compute_doc_frequencies();
+                &computed
+            }
+        };
+        let top = (frequencies.len() as f64 * top_percent / 100.0).ceil();
+        frequencies
+            .iter()
+            .take(top as usize)
+            .map(|(word, _)| word.clone())
+            .collect()
+    }
+
+    fn compute_doc_frequencies(&self) -> Vec<(String, usize)> {
+        let mut frequencies: Vec<(String, usize)> = self
+            .indices
+            .iter()
+            .map(|(word, ys)| (word.to_string(), ys.len()))
+            .collect();
+        frequencies.sort_by(|(w1, f1), (w2, f2)| f2.cmp(f1).then_with(|| w1.cmp(w2)));
+        frequencies
+    }
+
+    pub fn vocabulary_stats(&self) -> VocabularyStats {
+        match self.warm {
+            Some(ref warm) => warm.stats,
+            None => self.compute_stats(),
+        }
+    }
+
+    fn compute_stats(&self) -> VocabularyStats {
+        VocabularyStats {
+            words: self.indices.len(),
+            sentences: self.sentences.len(),
+            postings: self.indices.iter().map(|(_, ys)| ys.len()).sum(),
+        }
+    }
+
+    // can_pivot returns whether the word appears in at least two sentences.
+    fn can_pivot(&self, word: &str) -> bool {
+        match self.warm {
+            Some(ref warm) => warm.pivots.contains(word),
+            None => self.sentences_with_word(word).len() >= 2,
+        }
     }
 
     pub fn needs_to_build_indices(&self) -> bool {
@@ -99,25 +860,147 @@ impl Dictionary {
 
     pub fn rebuild_indices(&mut self) {
         self.reset_indices();
-        sort_sentences(&mut self.sentences);
+        self.fill_sentence_meta();
+        sort_sentences(&mut self.sentences, &mut self.sentence_meta);
+        self.index_sentences();
 
-        let mut indices: Indices = HashMap::new();
+        let mut indices = Indices::new();
+        let options = &self.options;
         self.sentences
             .iter()
             .enumerate()
             .map(|(i, sentence)| (i, sentence.to_lowercase()))
             .for_each(|(i, sentence)| {
                 println!("Indexing: {:?}", sentence);
-                let words = split_words(&sentence);
-                for word in words {
-                    insert_word_into_indices(&mut indices, word, i);
+                for key in index_keys(&sentence, options) {
+                    indices.insert(&key, i);
                 }
             });
-        self.indices = indices
+        self.indices = indices;
+
+        self.word_forms.clear();
+        if self.options.preserve_case {
+            for i in 0..self.sentences.len() {
+                self.count_word_forms(i);
+            }
+        }
+    }
+
+    // count_word_forms counts the spelling of every word of the sentence at
+    // the given index towards its display form.
+    fn count_word_forms(&mut self, i: usize) {
+        for word in split_tokens(&self.sentences[i], &self.options) {
+            let forms = self
+                .word_forms
+                .entry(normalize_word(word, &self.options).into_owned())
+                .or_default();
+            match forms.iter_mut().find(|(form, _)| form == word) {
+                Some((_, count)) => *count += 1,
+                None => forms.push((word.to_owned(), 1)),
+            }
+        }
+    }
+
+    // uncount_word_forms takes back the spellings counted for the words of a
+    // sentence that is being forgotten, dropping those no longer used.
+    fn uncount_word_forms(&mut self, sentence: &str) {
+        for word in split_tokens(sentence, &self.options) {
+            let key = normalize_word(word, &self.options);
+            let forms = match self.word_forms.get_mut(key.as_ref()) {
+                Some(forms) => forms,
+                None => continue,
+            };
+            if let Some(i) = forms.iter().position(|(form, _)| form == word) {
+                forms[i].1 -= 1;
+                if forms[i].1 == 0 {
+                    forms.remove(i);
+                }
+            }
+            if forms.is_empty() {
+                self.word_forms.remove(key.as_ref());
+            }
+        }
+    }
+
+    // display_words joins the words with single spaces, writing each in its
+    // most common spelling when case is preserved, so that a word is spelled
+    // the same in every reply whichever sentence it was taken from. Ties go
+    // to the spelling that sorts first, which favors capitals. Punctuation
+    // kept as tokens is written right after the word before it.
+    fn display_words(&self, words: &[&str]) -> String {
+        let forms = self.display_forms(words);
+        if !self.options.punctuation_tokens {
+            return forms.join(" ");
+        }
+        let mut text = String::new();
+        for form in forms {
+            if !text.is_empty() && !form.chars().all(is_word_punctuation) {
+                text.push(' ');
+            }
+            text.push_str(form);
+        }
+        text
+    }
+
+    fn display_forms<'a>(&'a self, words: &[&'a str]) -> Vec<&'a str> {
+        if self.word_forms.is_empty() {
+            return words.to_vec();
+        }
+        words
+            .iter()
+            .map(|word| {
+                let key = normalize_word(word, &self.options);
+                let display = self.word_forms.get(key.as_ref()).and_then(|forms| {
+                    forms
+                        .iter()
+                        .min_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)))
+                        .map(|(form, _)| form.as_str())
+                });
+                match display {
+                    Some(form) if form.to_lowercase() == word.to_lowercase() => form,
+                    _ => word,
+                }
+            })
+            .collect()
     }
 
     fn knows_sentence(&self, sentence: &str) -> bool {
-        self.sentences.iter().any(|x| x == sentence)
+        self.sentence_position(sentence).is_some()
+    }
+
+    fn sentence_position(&self, sentence: &str) -> Option<usize> {
+        if self.sentence_positions.len() == self.sentences.len() {
+            self.sentence_positions.get(sentence).copied()
+        } else {
+            self.sentences.iter().position(|x| x == sentence)
+        }
+    }
+
+    // index_sentences records the position of every sentence. The first
+    // position is kept for duplicate sentences, which leaves the positions
+    // short and makes sentence_position scan until they are removed.
+    fn index_sentences(&mut self) {
+        self.sentence_positions.clear();
+        for (i, sentence) in self.sentences.iter().enumerate() {
+            self.sentence_positions.entry(sentence.clone()).or_insert(i);
+        }
+    }
+
+    // contains_sentence returns whether the dictionary has learned the
+    // sentence. Unless case is preserved, sentences are stored in lowercase,
+    // so case is ignored.
+    pub fn contains_sentence(&self, sentence: &str) -> bool {
+        if self.options.preserve_case {
+            self.knows_sentence(sentence)
+        } else {
+            self.knows_sentence(&sentence.to_lowercase())
+        }
+    }
+
+    // contains_word returns whether the word appears in any sentence of the
+    // dictionary, once normalized as words are when they are learned.
+    pub fn contains_word(&self, word: &str) -> bool {
+        self.knows_word(&normalize_word(&word.to_lowercase(), &self.options))
     }
 
     fn knows_word(&self, word: &str) -> bool {
@@ -125,514 +1008,4987 @@ impl Dictionary {
     }
 
     pub fn learn(&mut self, line: &str) -> bool {
+        self.learn_at(line, SystemTime::now())
+    }
+
+    // learn_at learns a line as if it had been seen at the given time.
+    pub fn learn_at(&mut self, line: &str, now: SystemTime) -> bool {
+        self.learn_with(line, now, None, None)
+    }
+
+    // learn_in_session learns a line, tagging the sentences it adds with the
+    // session so that they can be forgotten together with delete_session.
+    // Sentences that were already known keep the session they had.
+    pub fn learn_in_session(&mut self, line: &str, session: &str) -> bool {
+        self.learn_with(line, SystemTime::now(), Some(session), None)
+    }
+
+    // learn_from_user learns a line sent by the user at the given time,
+    // recording the user on the sentences it adds or counts, so that
+    // respond_to_user can favor them.
+    pub fn learn_from_user(&mut self, user: &str, line: &str, now: SystemTime) -> bool {
+        self.learn_with(line, now, None, Some(user))
+    }
+
+    fn learn_with(
+        &mut self,
+        line: &str,
+        now: SystemTime,
+        session: Option<&str>,
+        user: Option<&str>,
+    ) -> bool {
+        let timestamp = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.fill_sentence_meta();
+        self.warm = None;
+        *self.transitions.get_mut() = None;
         let mut learned_something = false;
-        for sentence in split_sentences(&line.to_lowercase()) {
-            if self.knows_sentence(sentence) {
+        for sentence in self.sentences_to_learn(line) {
+            for key in index_keys(&sentence, &self.options) {
+                self.last_seen.insert(key.into_owned(), timestamp);
+            }
+            if let Some(i) = self.sentence_position(&sentence) {
+                let debounce = self.options.count_debounce_secs;
+                let meta = &mut self.sentence_meta[i];
+                let last_counted = meta.counted_at.max(meta.learned_at);
+                if debounce == 0 || timestamp >= last_counted.saturating_add(debounce) {
+                    meta.count += 1;
+                    meta.counted_at = timestamp;
+                }
+                if let Some(user) = user {
+                    if !meta.users.iter().any(|u| u == user) {
+                        meta.users.push(user.to_owned());
+                    }
+                }
                 continue;
             }
-            self.sentences.push(sentence.to_owned());
-            let sentence_index = self.sentences.len() - 1;
-
             // Update the indices with the sentence's words
-            for word in split_words(&sentence) {
-                insert_word_into_indices(&mut self.indices, &word, sentence_index);
+            let sentence_index = self.sentences.len();
+            for key in index_keys(&sentence, &self.options) {
+                self.indices.insert(&key, sentence_index);
+            }
+            if self.sentence_positions.len() == sentence_index {
+                self.sentence_positions
+                    .insert(sentence.clone(), sentence_index);
+            }
+            self.sentences.push(sentence);
+            self.sentence_meta.push(SentenceMeta {
+                learned_at: timestamp,
+                count: 1,
+                session: session.map(|s| s.to_owned()),
+                users: user.iter().map(|u| u.to_string()).collect(),
+                counted_at: timestamp,
+            });
+            if self.options.preserve_case {
+                self.count_word_forms(sentence_index);
             }
             learned_something = true;
         }
+        *self.sentence_sampler.get_mut() = None;
         learned_something
     }
 
-    pub fn respond_to(&self, line: &str, rng: &mut dyn RngCore) -> Option<String> {
-        let known_words = self.known_words(line);
-        if known_words.is_empty() {
-            None
+    // sentences_to_learn splits the line into the sentences that learning it
+    // would store or count, after the options' filters are applied.
+    fn sentences_to_learn(&self, line: &str) -> Vec<String> {
+        let line = sanitize(line);
+        let line = match self.options.code {
+            CodeHandling::Learn => line,
+            CodeHandling::Skip if find_code(&line).is_some() => return vec![],
+            CodeHandling::Skip => line,
+            CodeHandling::Strip => Cow::Owned(strip_code(&line).into_owned()),
+        };
+        let line = if self.options.mask_pii {
+            Cow::Owned(pii::mask(&line))
         } else {
-            let pivot = &known_words[rng.next_u64() as usize % known_words.len()];
-            let sentences_with_word = self.sentences_with_word(pivot);
-            if sentences_with_word.len() < 2 {
-                None
-            } else {
-                let s1 = *pick_random(&sentences_with_word, rng);
-                let s2 = *pick_random(&sentences_with_word, rng);
-                let left = get_words_left_of_pivot(s1, pivot)
-                    .unwrap_or_else(|| vec![""])
-                    .join(" ");
-                let right = get_words_right_of_pivot_inclusive(s2, pivot)
-                    .unwrap()
-                    .join(" ");
-                if left == "" {
-                    Some(right)
-                } else {
-                    Some(format!("{} {}", left, right))
-                }
-            }
+            line
+        };
+        let line = if self.options.preserve_case {
+            line.into_owned()
+        } else {
+            line.to_lowercase()
+        };
+        split_lines(&line, self.options.line_breaks)
+            .into_iter()
+            .flat_map(split_sentences)
+            .filter(|sentence| {
+                !(self.options.skip_questions && terminal_punctuation(sentence).contains('?'))
+            })
+            .filter(|sentence| {
+                normalized_length(sentence, &self.options) >= self.options.min_learn_chars
+            })
+            .map(|sentence| sentence.to_owned())
+            .collect()
+    }
+
+    // learn_from_file learns every line of the text file at the path, which
+    // is decompressed first if it is gzipped, as told by a ".gz" extension or
+    // by its contents. Returns the number of sentences added.
+    pub fn learn_from_file(&mut self, path: &Path) -> Result<usize, Error> {
+        let data = fs::read(path)?;
+        let gzipped =
+            path.extension().and_then(|e| e.to_str()) == Some("gz") || gzip::is_gzip(&data);
+        let data = if gzipped {
+            gzip::decompress(&data)?
+        } else {
+            data
+        };
+        let before = self.sentences.len();
+        for line in String::from_utf8_lossy(&data).lines() {
+            self.learn(line);
         }
+        Ok(self.sentences.len() - before)
     }
 
-    fn known_words(&self, line: &str) -> Vec<String> {
-        split_words(&line.to_lowercase())
-            .iter()
-            .filter(|s| self.knows_word(s))
-            .map(|s| s.to_string())
-            .collect::<Vec<_>>()
+    // preview_learn returns the sentences that learning the line would add to
+    // the dictionary, without learning it.
+    pub fn preview_learn(&self, line: &str) -> Vec<String> {
+        let mut new_sentences: Vec<String> = vec![];
+        for sentence in self.sentences_to_learn(line) {
+            if !self.knows_sentence(&sentence) && !new_sentences.contains(&sentence) {
+                new_sentences.push(sentence);
+            }
+        }
+        new_sentences
     }
 
-    fn sentences_with_word(&self, word: &str) -> Vec<&str> {
-        self.indices
-            .get(word)
-            .map(|ys| ys.iter().map(|y| self.sentences[*y].as_str()).collect())
-            .unwrap_or_else(Vec::new)
+    // expire_older_than forgets the sentences first learned more than max_age
+    // before now, and rebuilds the indices if any were forgotten. Sentences
+    // whose learning time is not known are kept. Returns the number of
+    // sentences forgotten.
+    pub fn expire_older_than(&mut self, max_age: Duration, now: SystemTime) -> usize {
+        let cutoff = match now.checked_sub(max_age) {
+            Some(t) => t
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            None => return 0,
+        };
+        self.fill_sentence_meta();
+        let before = self.sentences.len();
+        let mut kept_sentences = vec![];
+        let mut kept_meta = vec![];
+        for (sentence, meta) in self.sentences.drain(..).zip(self.sentence_meta.drain(..)) {
+            if meta.learned_at == 0 || meta.learned_at >= cutoff {
+                kept_sentences.push(sentence);
+                kept_meta.push(meta);
+            }
+        }
+        self.sentences = kept_sentences;
+        self.sentence_meta = kept_meta;
+        let expired = before - self.sentences.len();
+        if expired > 0 {
+            self.rebuild_indices();
+        }
+        expired
     }
-}
 
-fn split_sentences(s: &str) -> Vec<&str> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"(?<=[.!?]+)\s+").unwrap();
+    // delete_session forgets the sentences first learned in the session, and
+    // rebuilds the indices if any were forgotten. Returns the number of
+    // sentences forgotten.
+    pub fn delete_session(&mut self, id: &str) -> usize {
+        self.fill_sentence_meta();
+        let before = self.sentences.len();
+        let mut kept_sentences = vec![];
+        let mut kept_meta = vec![];
+        for (sentence, meta) in self.sentences.drain(..).zip(self.sentence_meta.drain(..)) {
+            if meta.session.as_deref() != Some(id) {
+                kept_sentences.push(sentence);
+                kept_meta.push(meta);
+            }
+        }
+        self.sentences = kept_sentences;
+        self.sentence_meta = kept_meta;
+        let deleted = before - self.sentences.len();
+        if deleted > 0 {
+            self.rebuild_indices();
+        }
+        deleted
     }
-    RE.split(s).filter(|s| !s.is_empty()).collect()
-}
 
-fn split_words(s: &str) -> Vec<&str> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"[,.!?:\s]+").unwrap();
+    // forget_sentence forgets the sentence, updating only the index entries
+    // of its words. Returns whether the sentence was known.
+    pub fn forget_sentence(&mut self, sentence: &str) -> bool {
+        let sentence = if self.options.preserve_case {
+            sentence.to_owned()
+        } else {
+            sentence.to_lowercase()
+        };
+        let i = match self.sentence_position(&sentence) {
+            Some(i) => i,
+            None => return false,
+        };
+        self.fill_sentence_meta();
+        let sentence = self.sentences.remove(i);
+        self.sentence_meta.remove(i);
+        self.reindex_incremental(SentenceChange::Removed(i, sentence));
+        true
     }
-    RE.split(s).filter(|s| !s.is_empty()).collect()
-}
 
-fn sort_sentences(sentences: &mut Vec<String>) {
-    sentences.sort_by(|a, b| a.to_lowercase().cmp(&b.to_lowercase()))
-}
+    // reindex_incremental updates the indices after a single sentence was
+    // added or removed, touching only the entries of the sentence's words and
+    // the indices of the sentences after it, instead of rebuilding them all.
+    // Unlike rebuild_indices, it leaves the sentences in the order they are.
+    pub fn reindex_incremental(&mut self, change: SentenceChange) {
+        self.warm = None;
+        *self.transitions.get_mut() = None;
+        match change {
+            SentenceChange::Added(i) => {
+                if i + 1 < self.sentences.len() {
+                    self.indices.shift_sentences(i, true);
+                    for position in self.sentence_positions.values_mut() {
+                        if *position >= i {
+                            *position += 1;
+                        }
+                    }
+                }
+                for key in index_keys(&self.sentences[i], &self.options) {
+                    self.indices.insert(&key, i);
+                }
+                self.sentence_positions
+                    .entry(self.sentences[i].clone())
+                    .or_insert(i);
+                if self.options.preserve_case {
+                    self.count_word_forms(i);
+                }
+            }
+            SentenceChange::Removed(i, sentence) => {
+                if self.options.preserve_case {
+                    self.uncount_word_forms(&sentence);
+                }
+                for key in index_keys(&sentence, &self.options) {
+                    if self.indices.remove_entry(&key, i) {
+                        self.last_seen.remove(key.as_ref());
+                        self.word_forms.remove(key.as_ref());
+                    }
+                }
+                self.indices.shift_sentences(i + 1, false);
+                if self.sentence_positions.get(&sentence) == Some(&i) {
+                    self.sentence_positions.remove(&sentence);
+                }
+                for position in self.sentence_positions.values_mut() {
+                    if *position > i {
+                        *position -= 1;
+                    }
+                }
+            }
+        }
+    }
 
-fn insert_word_into_indices(indices: &mut Indices, word: &str, sentence_index: usize) {
-    let entry = indices.entry(word.to_owned()).or_insert_with(Vec::new);
-    if !entry.contains(&sentence_index) {
-        entry.push(sentence_index);
+    // forget_word forgets every sentence containing the word, e.g. to purge a
+    // topic, and rebuilds the indices if any were forgotten. Sentences are
+    // found through the index, so the word is normalized as when learning.
+    // Returns the number of sentences forgotten.
+    pub fn forget_word(&mut self, word: &str) -> usize {
+        let key = normalize_word(&word.to_lowercase(), &self.options).into_owned();
+        let doomed: HashSet<usize> = match self.indices.get(&key) {
+            Some(postings) => postings.iter().map(|i| i as usize).collect(),
+            None => return 0,
+        };
+        self.fill_sentence_meta();
+        let sentences = self.sentences.drain(..);
+        let meta = self.sentence_meta.drain(..);
+        let (kept_sentences, kept_meta): (Vec<String>, Vec<SentenceMeta>) = sentences
+            .zip(meta)
+            .enumerate()
+            .filter(|(i, _)| !doomed.contains(i))
+            .map(|(_, pair)| pair)
+            .unzip();
+        self.sentences = kept_sentences;
+        self.sentence_meta = kept_meta;
+        self.rebuild_indices();
+        let indices = &self.indices;
+        self.last_seen.retain(|word, _| indices.contains_key(word));
+        doomed.len()
     }
-}
 
-fn pick_random<'a, T>(v: &'a [T], rng: &mut dyn RngCore) -> &'a T {
-    &v[rng.next_u64() as usize % v.len()]
-}
+    // dedup removes the sentences that are exact duplicates of an earlier
+    // one, e.g. from dictionaries saved before learning skipped known
+    // sentences, and rebuilds the indices if any were removed. The metadata
+    // of duplicates is merged into the sentence that is kept. Returns the
+    // number of sentences removed.
+    pub fn dedup(&mut self) -> usize {
+        self.fill_sentence_meta();
+        let before = self.sentences.len();
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        let mut kept_sentences: Vec<String> = vec![];
+        let mut kept_meta: Vec<SentenceMeta> = vec![];
+        for (sentence, meta) in self.sentences.drain(..).zip(self.sentence_meta.drain(..)) {
+            match positions.get(&sentence) {
+                Some(&i) => {
+                    let kept = &mut kept_meta[i];
+                    kept.count = kept.count.saturating_add(meta.count);
+                    kept.counted_at = kept.counted_at.max(meta.counted_at);
+                    if kept.learned_at == 0
+                        || (meta.learned_at != 0 && meta.learned_at < kept.learned_at)
+                    {
+                        kept.learned_at = meta.learned_at;
+                    }
+                    for user in meta.users {
+                        if !kept.users.contains(&user) {
+                            kept.users.push(user);
+                        }
+                    }
+                }
+                None => {
+                    positions.insert(sentence.clone(), kept_sentences.len());
+                    kept_sentences.push(sentence);
+                    kept_meta.push(meta);
+                }
+            }
+        }
+        self.sentences = kept_sentences;
+        self.sentence_meta = kept_meta;
+        let removed = before - self.sentences.len();
+        if removed > 0 {
+            self.rebuild_indices();
+        }
+        removed
+    }
 
-fn get_words_left_of_pivot<'a>(line: &'a str, pivot: &'a str) -> Option<Vec<&'a str>> {
-    let words = split_words(line);
-    words
+    // shard splits the sentences among n dictionaries, each with its own
+    // indices, so that they can be served by different processes. The
+    // sentences are dealt out in turn, so the shards are of about the same
+    // size. Returns no dictionaries if n is 0.
+    pub fn shard(&self, n: usize) -> Vec<Dictionary> {
+        let mut shards: Vec<Dictionary> = (0..n)
+            .map(|_| Dictionary {
+                options: self.options.clone(),
+                ..Default::default()
+            })
+            .collect();
+        if n == 0 {
+            return shards;
+        }
+        for (i, sentence) in self.sentences.iter().enumerate() {
+            let shard = &mut shards[i % n];
+            shard.sentences.push(sentence.clone());
+            shard
+                .sentence_meta
+                .push(self.sentence_meta.get(i).cloned().unwrap_or_default());
+        }
+        for shard in &mut shards {
+            shard.rebuild_indices();
+            let last_seen = self
+                .last_seen
+                .iter()
+                .filter(|(word, _)| shard.knows_word(word))
+                .map(|(word, t)| (word.clone(), *t))
+                .collect();
+            shard.last_seen = last_seen;
+        }
+        shards
+    }
+
+    // last_seen returns the last time the word was learned, if it is known.
+    pub fn last_seen(&self, word: &str) -> Option<SystemTime> {
+        self.last_seen
+            .get(normalize_word(&word.to_lowercase(), &self.options).as_ref())
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(*secs))
+    }
+
+    pub fn respond_to(&self, line: &str, rng: &mut dyn RngCore) -> Option<String> {
+        self.respond(line, &[], None, rng).map(|built| built.text)
+    }
+
+    // respond_to_user replies to a line sent by the user, favoring the
+    // sentences the user taught according to the user_bias option. Any
+    // sentence may still be used, so users who taught nothing get the same
+    // replies as with respond_to.
+    pub fn respond_to_user(&self, user: &str, line: &str, rng: &mut dyn RngCore) -> Option<String> {
+        self.respond(line, &[], Some(user), rng)
+            .map(|built| built.text)
+    }
+
+    // respond_in_context replies to the line as the latest of a conversation,
+    // given the messages that came before it, oldest first. The reply may be
+    // built around the words of one of those messages instead of the line's,
+    // the more recent ones being the more likely, so that the words of a
+    // conversation keep coming back even when the line doesn't use them.
+    pub fn respond_in_context(
+        &self,
+        line: &str,
+        context: &[String],
+        user: Option<&str>,
+        rng: &mut dyn RngCore,
+    ) -> Option<String> {
+        self.respond(line, context, user, rng)
+            .map(|built| built.text)
+    }
+
+    fn respond(
+        &self,
+        line: &str,
+        context: &[String],
+        user: Option<&str>,
+        rng: &mut dyn RngCore,
+    ) -> Option<Built> {
+        if self.options.fallback_chain.is_empty() {
+            return self.splice_reply(line, context, user, rng);
+        }
+        for strategy in &self.options.fallback_chain {
+            let reply = match strategy {
+                ReplyStrategy::Splice => self.splice_reply(line, context, user, rng),
+                ReplyStrategy::Fuzzy => self.fuzzy_reply(line, user, rng),
+                ReplyStrategy::RandomSentence => self.random_sentence_reply(rng),
+                ReplyStrategy::Interleave => self.interleave_reply(line, user, rng),
+                ReplyStrategy::RandomWalk {
+                    min_words,
+                    max_words,
+                } => self.walk(*min_words, *max_words, rng),
+                ReplyStrategy::Static(text) => Some(Built::new(text.clone(), vec![])),
+            };
+            if reply.is_some() {
+                return reply;
+            }
+        }
+        None
+    }
+
+    // splice_reply builds a reply around the known words of the line, or of
+    // a message of the context, or tries the fallbacks for unknown input if
+    // none are known.
+    fn splice_reply(
+        &self,
+        line: &str,
+        context: &[String],
+        user: Option<&str>,
+        rng: &mut dyn RngCore,
+    ) -> Option<Built> {
+        let known_words = self.known_words(line);
+        if !known_words.is_empty() {
+            let total_words = split_tokens(line, &self.options).len();
+            if (known_words.len() as f64) < self.options.min_overlap_ratio * total_words as f64 {
+                debug!(
+                    "[respond_to] Only {} of {} words are known in {:?}",
+                    known_words.len(),
+                    total_words,
+                    line
+                );
+                return None;
+            }
+        }
+        let known_words = self.pick_known_words(known_words, context, rng);
+        if !known_words.is_empty() {
+            return self.respond_from(&known_words, user, rng);
+        }
+        for fallback in &self.options.unknown_input_fallbacks {
+            let reply = match fallback {
+                UnknownInputFallback::Fuzzy => self.fuzzy_reply(line, user, rng),
+                UnknownInputFallback::RandomSentence => self.random_sentence_reply(rng),
+            };
+            if reply.is_some() {
+                return reply;
+            }
+        }
+        None
+    }
+
+    // interleave_reply picks two sentences sharing a known word of the line
+    // and alternates their words, going outwards from that word on both of
+    // its sides. Words beyond MAX_INTERLEAVE_WORDS, the farthest from the
+    // pivot first, are left out.
+    fn interleave_reply(
+        &self,
+        line: &str,
+        user: Option<&str>,
+        rng: &mut dyn RngCore,
+    ) -> Option<Built> {
+        let known_words = self.known_words(line);
+        if known_words.is_empty() {
+            return None;
+        }
+        let pivot = self.pick_pivot(&known_words, rng)?;
+        if !self.can_pivot(pivot) {
+            return None;
+        }
+        let mut ids = self.on_topic(pivot);
+        if ids.len() < 2 {
+            return None;
+        }
+        let i1 = self.pick_sentence(&ids, user, rng)?;
+        ids.retain(|&i| i != i1);
+        let i2 = self.pick_sentence(&ids, user, rng)?;
+        let (s1, s2) = (self.sentences[i1].as_str(), self.sentences[i2].as_str());
+
+        let mut left1 = get_words_left_of_pivot(s1, pivot, &self.options)?;
+        let mut left2 = get_words_left_of_pivot(s2, pivot, &self.options)?;
+        let right1 = get_words_right_of_pivot_inclusive(s1, pivot, &self.options)?;
+        let right2 = get_words_right_of_pivot_inclusive(s2, pivot, &self.options)?;
+        left1.reverse();
+        left2.reverse();
+        let mut left = alternate(&left1, &left2);
+        let right = alternate(&right1[1..], &right2[1..]);
+
+        let room = MAX_INTERLEAVE_WORDS - 1;
+        let left_room = (room / 2).max(room.saturating_sub(right.len()));
+        left.truncate(left_room);
+        left.reverse();
+        let right_room = room.saturating_sub(left.len());
+        let mut words = left;
+        words.push(right1[0]);
+        words.extend(right.into_iter().take(right_room));
+
+        let ending = if right1.len() > right2.len() { s1 } else { s2 };
+        Some(Built::new(
+            self.punctuate(self.display_words(&words), ending),
+            vec![i1, i2],
+        ))
+    }
+
+    // pick_known_words picks the known words of either the line or one of the
+    // messages of the context to build a reply around. The line is picked
+    // with weight 1, and each message before it with CONTEXT_DECAY times the
+    // weight of the one after it. Messages without known words are skipped.
+    fn pick_known_words(
+        &self,
+        line_words: Vec<String>,
+        context: &[String],
+        rng: &mut dyn RngCore,
+    ) -> Vec<String> {
+        if context.is_empty() {
+            return line_words;
+        }
+        let mut choices: Vec<Vec<String>> = vec![];
+        let mut weights: Vec<f64> = vec![];
+        if !line_words.is_empty() {
+            choices.push(line_words);
+            weights.push(1.0);
+        }
+        let mut weight = 1.0;
+        for message in context.iter().rev() {
+            weight *= CONTEXT_DECAY;
+            let words = self.known_words(message);
+            if !words.is_empty() {
+                choices.push(words);
+                weights.push(weight);
+            }
+        }
+        match pick_weighted(&weights, rng) {
+            Some(i) => choices.swap_remove(i),
+            None => vec![],
+        }
+    }
+
+    // fuzzy_reply builds a reply around the known words closest in spelling
+    // to the words of the line.
+    fn fuzzy_reply(&self, line: &str, user: Option<&str>, rng: &mut dyn RngCore) -> Option<Built> {
+        let similar_words = self.similar_words(line);
+        if similar_words.is_empty() {
+            None
+        } else {
+            self.respond_from(&similar_words, user, rng)
+        }
+    }
+
+    // respond_with_pivot builds a reply around the given word, as if it had
+    // been picked as the pivot of an input. Returns None if the word can't be
+    // used as a pivot.
+    pub fn respond_with_pivot(&self, pivot: &str, rng: &mut dyn RngCore) -> Option<String> {
+        let pivot = normalize_word(&pivot.to_lowercase(), &self.options).into_owned();
+        if !self.can_pivot(&pivot) {
+            return None;
+        }
+        self.respond_from(&[pivot], None, rng)
+            .map(|built| built.text)
+    }
+
+    // random_walk generates text by walking from word to word, following
+    // the words that come after each other in the sentences, starting at the
+    // first word of a random sentence. A walk only ends where a sentence
+    // ends, once it has between min_words and max_words words; walks that
+    // can't are thrown away, and None is returned if a few in a row fail.
+    pub fn random_walk(
+        &self,
+        min_words: usize,
+        max_words: usize,
+        rng: &mut dyn RngCore,
+    ) -> Option<String> {
+        self.walk(min_words, max_words, rng).map(|built| built.text)
+    }
+
+    fn walk(&self, min_words: usize, max_words: usize, rng: &mut dyn RngCore) -> Option<Built> {
+        let mut transitions = self.transitions.borrow_mut();
+        let next = transitions.get_or_insert_with(|| self.build_transitions());
+        for _ in 0..MAX_REPLY_ATTEMPTS {
+            let start = self.random_sentence_id(rng)?;
+            let mut words: Vec<&str> =
+                match split_tokens(&self.sentences[start], &self.options).first() {
+                    Some(word) => vec![*word],
+                    None => continue,
+                };
+            let mut sources = vec![start];
+            while words.len() <= max_words {
+                let last = normalize_word(words[words.len() - 1], &self.options);
+                let choices = match next.get(last.as_ref()) {
+                    Some(choices) => choices,
+                    None => break,
+                };
+                let ending = choices.iter().find(|(word, _)| word.is_none());
+                if words.len() >= min_words {
+                    if let Some(&(_, i)) = ending {
+                        if sources.last() != Some(&i) {
+                            sources.push(i);
+                        }
+                        let text = self.punctuate(self.display_words(&words), &self.sentences[i]);
+                        return Some(Built::new(text, sources));
+                    }
+                }
+                let going_on: Vec<(&str, usize)> = choices
+                    .iter()
+                    .filter_map(|(word, i)| word.as_deref().map(|word| (word, *i)))
+                    .collect();
+                match pick_random(&going_on, rng) {
+                    Some(&(word, i)) => {
+                        words.push(word);
+                        if sources.last() != Some(&i) {
+                            sources.push(i);
+                        }
+                    }
+                    None => break,
+                }
+            }
+        }
+        None
+    }
+
+    // build_transitions reads the words that come after each word from the
+    // sentences, for random walks.
+    fn build_transitions(&self) -> Transitions {
+        let mut next = Transitions::new();
+        for (i, sentence) in self.sentences.iter().enumerate() {
+            let words = split_tokens(sentence, &self.options);
+            for (j, word) in words.iter().enumerate() {
+                next.entry(normalize_word(word, &self.options).into_owned())
+                    .or_default()
+                    .push((words.get(j + 1).map(|word| word.to_string()), i));
+            }
+        }
+        next
+    }
+
+    // respond_with_provenance replies to the line like respond_to, along with
+    // the sentences the reply was built from.
+    pub fn respond_with_provenance(&self, line: &str, rng: &mut dyn RngCore) -> Option<Provenance> {
+        self.respond_to(line, rng)
+            .map(|reply| self.provenance(&reply))
+    }
+
+    // provenance traces every sentence of the reply back to the sentences it
+    // could have been built from: a sentence starting with its beginning and,
+    // unless that sentence is the whole of it, a second one ending with its
+    // end, either sharing the pivot word where they meet or not, as bridged
+    // replies don't. When several sentences fit, the first found is given.
+    pub fn provenance(&self, reply: &str) -> Provenance {
+        let mut sources: Vec<usize> = vec![];
+        for sentence in split_sentences(reply) {
+            sources.extend(self.trace(&self.normalize_line(sentence)));
+        }
+        Provenance {
+            reply: reply.to_owned(),
+            sources: sources
+                .into_iter()
+                .filter_map(|i| self.get_sentence(i).map(|s| (i, s.to_owned())))
+                .collect(),
+        }
+    }
+
+    // trace returns the sentences the normalized words of one sentence of a
+    // reply were taken from, or none if it can't be traced back. Indices of
+    // sentences that don't exist, as stale indices may have, are skipped.
+    fn trace(&self, normalized: &str) -> Vec<usize> {
+        let words: Vec<&str> = normalized.split(' ').filter(|w| !w.is_empty()).collect();
+        let with_word = |word: &str| -> Vec<usize> {
+            if self.knows_word(word) {
+                self.sentence_ids_with_word(word)
+            } else {
+                (0..self.sentences.len()).collect()
+            }
+        };
+        let sentence_words = |i: usize| self.get_sentence(i).map(|s| self.normalize_line(s));
+        let first = match words.first() {
+            Some(first) => with_word(first),
+            None => return vec![],
+        };
+        for k in (1..=words.len()).rev() {
+            let head = words[..k].join(" ");
+            for &s1 in &first {
+                let s1_words = match sentence_words(s1) {
+                    Some(s1_words) => s1_words,
+                    None => continue,
+                };
+                if !(s1_words == head || s1_words.starts_with(&format!("{} ", head))) {
+                    continue;
+                }
+                if k == words.len() {
+                    return vec![s1];
+                }
+                for start in &[k - 1, k] {
+                    let tail = words[*start..].join(" ");
+                    for s2 in with_word(words[*start]) {
+                        let s2_words = match sentence_words(s2) {
+                            Some(s2_words) => s2_words,
+                            None => continue,
+                        };
+                        if s2_words == tail || s2_words.ends_with(&format!(" {}", tail)) {
+                            return vec![s1, s2];
+                        }
+                    }
+                }
+            }
+        }
+        vec![]
+    }
+
+    // respond_with_confidence replies to the line like respond_to, along with
+    // a score between 0 and 1 of how well the reply matches the line. See
+    // confidence for how the score is computed.
+    pub fn respond_with_confidence(
+        &self,
+        line: &str,
+        rng: &mut dyn RngCore,
+    ) -> Option<(String, f64)> {
+        self.respond_to(line, rng).map(|reply| {
+            let confidence = self.confidence(line, &reply);
+            (reply, confidence)
+        })
+    }
+
+    // confidence scores a reply to the line as the average of three parts,
+    // each between 0 and 1:
+    //  - how rare the pivot is among all sentences,
+    //  - how many sentences the pivot could have been spliced from,
+    //  - how many of the line's known words the reply contains.
+    // The pivot is taken to be the rarest known word shared by the line and
+    // the reply. A reply sharing no known word with the line scores 0.
+    fn confidence(&self, line: &str, reply: &str) -> f64 {
+        let mut known = self.known_words(line);
+        known.sort();
+        known.dedup();
+        let reply_words = self.known_words(reply);
+        let shared: Vec<&String> = known.iter().filter(|w| reply_words.contains(w)).collect();
+        let frequency = match shared
+            .iter()
+            .map(|w| self.sentences_with_word(w).len())
+            .min()
+        {
+            Some(f) if f > 0 => f as f64,
+            _ => return 0.0,
+        };
+        let rarity = 1.0 - frequency / self.sentences.len() as f64;
+        let support = 1.0 - 1.0 / frequency;
+        let overlap = shared.len() as f64 / known.len() as f64;
+        (rarity + support + overlap) / 3.0
+    }
+
+    // set_word_fatigue sets how much less likely replies are to be built
+    // around or from sentences containing each of the normalized words, with
+    // a multiplier of their weight between 0 and 1.
+    pub fn set_word_fatigue(&mut self, fatigue: HashMap<String, f64>) {
+        self.word_fatigue = fatigue;
+    }
+
+    fn fatigue(&self, word: &str) -> f64 {
+        self.word_fatigue.get(word).copied().unwrap_or(1.0)
+    }
+
+    // note_reply remembers the sentences the reply was built from, as given
+    // by respond_to_safe, so that replies use them less according to the
+    // source use penalty. Only the replies in the source use window are
+    // remembered.
+    pub fn note_reply(&mut self, reply: &Provenance) {
+        let window = self.options.source_use_window;
+        if window == 0 || self.options.source_use_penalty <= 0.0 {
+            self.source_uses.clear();
+            return;
+        }
+        let sources = reply
+            .sources
+            .iter()
+            .map(|(_, sentence)| sentence.clone())
+            .collect();
+        self.source_uses.push_back(sources);
+        while self.source_uses.len() > window {
+            self.source_uses.pop_front();
+        }
+    }
+
+    // respond_to_safe is like respond_to, but a panic anywhere while building
+    // the reply is logged and treated as having nothing to say, instead of
+    // unwinding into the caller. The reply comes with the sentences it was
+    // built from.
+    pub fn respond_to_safe(
+        &self,
+        line: &str,
+        context: &[String],
+        user: Option<&str>,
+        rng: &mut dyn RngCore,
+    ) -> Option<Provenance> {
+        let respond = || self.respond(line, context, user, rng);
+        match panic::catch_unwind(AssertUnwindSafe(respond)) {
+            Ok(reply) => reply.map(|built| Provenance {
+                reply: built.text,
+                sources: built
+                    .sources
+                    .into_iter()
+                    .map(|i| (i, self.sentences[i].clone()))
+                    .collect(),
+            }),
+            Err(cause) => {
+                let message = cause
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| cause.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown cause".to_string());
+                error!(
+                    "[respond_to_safe] Panicked replying to {:?}: {}",
+                    line, message
+                );
+                None
+            }
+        }
+    }
+
+    // respond_from builds a reply around a pivot picked among the given
+    // known words.
+    fn respond_from(
+        &self,
+        known_words: &[String],
+        user: Option<&str>,
+        rng: &mut dyn RngCore,
+    ) -> Option<Built> {
+        if self.options.min_reply_space > 0 && self.reply_space() < self.options.min_reply_space {
+            debug!(
+                "[respond_from] Fewer than {} pivots, not splicing a reply",
+                self.options.min_reply_space
+            );
+            return None;
+        }
+        let candidates = match self.options.length_target {
+            None => self.options.relevance_candidates,
+            Some(ref target) => target.candidates.max(self.options.relevance_candidates),
+        };
+        let reply = (0..candidates.max(1))
+            .filter_map(|_| self.build_reply(known_words, user, rng))
+            .min_by_key(|reply| {
+                let coverage = if self.options.relevance_candidates > 1 {
+                    self.coverage(&reply.text, known_words)
+                } else {
+                    0
+                };
+                let deviation = match self.options.length_target {
+                    Some(ref target) => target.deviation(&reply.text),
+                    None => 0,
+                };
+                (Reverse(coverage), deviation)
+            })?;
+        match self.options.follow_up {
+            Some(ref follow_up) if random_unit(rng) < follow_up.chance => {
+                Some(self.follow_up(reply, follow_up.max_words, rng))
+            }
+            _ => Some(reply),
+        }
+    }
+
+    // coverage returns how many of the distinct known words are in the reply.
+    fn coverage(&self, reply: &str, known_words: &[String]) -> usize {
+        let keys: HashSet<Cow<str>> = index_keys(reply, &self.options).into_iter().collect();
+        let known: HashSet<&str> = known_words.iter().map(|w| w.as_str()).collect();
+        known.iter().filter(|w| keys.contains(**w)).count()
+    }
+
+    // follow_up appends to the reply a sentence that shares a word with it,
+    // isn't already part of it, and fits within max_words. The reply is
+    // returned as is if there is no such sentence.
+    fn follow_up(&self, mut reply: Built, max_words: usize, rng: &mut dyn RngCore) -> Built {
+        let room = max_words.saturating_sub(split_tokens(&reply.text, &self.options).len());
+        let mut related: Vec<usize> = vec![];
+        for word in self.known_words(&reply.text) {
+            for i in self.on_topic(&word) {
+                let words = split_tokens(&self.sentences[i], &self.options);
+                if words.len() <= room
+                    && !reply.text.contains(&words.join(" "))
+                    && !related
+                        .iter()
+                        .any(|&r| self.sentences[r] == self.sentences[i])
+                {
+                    related.push(i);
+                }
+            }
+        }
+        let i = match pick_random(&related, rng) {
+            Some(&i) => i,
+            None => return reply,
+        };
+        let sentence = &self.sentences[i];
+        let text = self.punctuate(
+            self.display_words(&split_tokens(sentence, &self.options)),
+            sentence,
+        );
+        reply.text = if terminal_punctuation(&reply.text).is_empty() {
+            format!("{}. {}", reply.text, text)
+        } else {
+            format!("{} {}", reply.text, text)
+        };
+        reply.sources.push(i);
+        reply
+    }
+
+    // build_reply builds a single reply, retrying while it repeats a word
+    // too many times or is in the wrong language.
+    fn build_reply(
+        &self,
+        known_words: &[String],
+        user: Option<&str>,
+        rng: &mut dyn RngCore,
+    ) -> Option<Built> {
+        if self.options.max_word_repeats.is_none()
+            && self.options.reply_language.is_none()
+            && self.options.blocked_phrases.is_empty()
+        {
+            return self.build_reply_once(known_words, user, rng);
+        }
+        for _ in 0..MAX_REPLY_ATTEMPTS {
+            let built = self.build_reply_once(known_words, user, rng)?;
+            let reply = &built.text;
+            if let Some(max_repeats) = self.options.max_word_repeats {
+                if self.most_repeats(reply) > max_repeats {
+                    debug!("[build_reply] Too many repeated words in {:?}", reply);
+                    continue;
+                }
+            }
+            if let Some(target) = self.options.reply_language {
+                match language::detect(reply) {
+                    Some(detected) if detected != target => {
+                        debug!("[build_reply] {:?} looks like {:?}", reply, detected);
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(phrase) = self.blocked_phrase(reply) {
+                debug!("[build_reply] {:?} contains {:?}", reply, phrase);
+                continue;
+            }
+            return Some(built);
+        }
+        None
+    }
+
+    // blocked_phrase returns the first blocked phrase found in the reply, if
+    // any.
+    fn blocked_phrase(&self, reply: &str) -> Option<&str> {
+        if self.options.blocked_phrases.is_empty() {
+            return None;
+        }
+        let reply = format!(" {} ", self.normalize_line(reply));
+        self.options
+            .blocked_phrases
+            .iter()
+            .find(|phrase| {
+                let phrase = self.normalize_line(phrase);
+                !phrase.is_empty() && reply.contains(&format!(" {} ", phrase))
+            })
+            .map(|phrase| phrase.as_str())
+    }
+
+    // most_repeats returns the number of times the most repeated word of the
+    // reply appears in it.
+    fn most_repeats(&self, reply: &str) -> usize {
+        let mut counts: HashMap<Cow<str>, usize> = HashMap::new();
+        for word in split_tokens(reply, &self.options) {
+            *counts
+                .entry(normalize_word(word, &self.options))
+                .or_default() += 1;
+        }
+        counts.values().copied().max().unwrap_or(0)
+    }
+
+    fn build_reply_once(
+        &self,
+        known_words: &[String],
+        user: Option<&str>,
+        rng: &mut dyn RngCore,
+    ) -> Option<Built> {
+        if self.options.bridge_pivots {
+            if let Some(reply) = self.bridge(known_words, user, rng) {
+                return Some(reply);
+            }
+        }
+        self.splice(known_words, user, rng)
+    }
+
+    // bridge joins a sentence containing one pivot, up to and including the
+    // pivot, with the right side of a sentence containing a second pivot. The
+    // pivots keep the order they have in the input. Returns None if there are
+    // not two different known words to use, or if either has no sentences on
+    // topic.
+    fn bridge(
+        &self,
+        known_words: &[String],
+        user: Option<&str>,
+        rng: &mut dyn RngCore,
+    ) -> Option<Built> {
+        let first = self.pick_pivot(known_words, rng)?;
+        let others: Vec<String> = known_words
+            .iter()
+            .filter(|w| *w != first)
+            .cloned()
+            .collect();
+        if others.is_empty() {
+            return None;
+        }
+        let second = self.pick_pivot(&others, rng)?;
+        let position = |w: &str| known_words.iter().position(|k| k == w);
+        let (first, second) = if position(second) < position(first) {
+            (second, first)
+        } else {
+            (first, second)
+        };
+
+        let with_first = self.on_topic(first);
+        let mut with_second = self.on_topic(second);
+        if with_first.is_empty() || with_second.is_empty() {
+            return None;
+        }
+        let i1 = self.pick_sentence(&self.content_openings(&with_first), user, rng)?;
+        let s1 = self.sentences[i1].as_str();
+        if with_second.len() > 1 {
+            with_second.retain(|&i| self.sentences[i] != s1);
+        }
+        let i2 = self.pick_sentence(&with_second, user, rng)?;
+        let s2 = self.sentences[i2].as_str();
+        let left = self.display_words(&get_words_left_of_pivot_inclusive(
+            s1,
+            first,
+            &self.options,
+        )?);
+        let right = self.display_words(&get_words_right_of_pivot_inclusive(
+            s2,
+            second,
+            &self.options,
+        )?);
+        Some(Built::new(
+            self.punctuate(format!("{} {}", left, right), s2),
+            vec![i1, i2],
+        ))
+    }
+
+    // splice joins the left side of a sentence containing a pivot with the
+    // right side of another sentence containing the same pivot.
+    fn splice(
+        &self,
+        known_words: &[String],
+        user: Option<&str>,
+        rng: &mut dyn RngCore,
+    ) -> Option<Built> {
+        let pivot = self.pick_pivot(known_words, rng)?;
+        if !self.can_pivot(pivot) {
+            return None;
+        }
+        let sentences_with_word = self.on_topic(pivot);
+        if sentences_with_word.len() < 2 {
+            None
+        } else {
+            let s1_candidates = self.lead_ins(&sentences_with_word, pivot);
+            let i1 = self.pick_sentence(&self.content_openings(&s1_candidates), user, rng)?;
+            let s1 = self.sentences[i1].as_str();
+            let left = get_words_left_of_pivot(s1, pivot, &self.options).unwrap_or_default();
+            let i2 = if left.len() + 1 == split_tokens(s1, &self.options).len() {
+                // The pivot ends s1, so the right side is all that the reply
+                // adds to it.
+                let continuations = self.continuations(&sentences_with_word, pivot, s1);
+                self.pick_sentence(&self.complete_endings(&continuations), user, rng)?
+            } else {
+                self.pick_sentence(&self.complete_endings(&sentences_with_word), user, rng)?
+            };
+            let s2 = self.sentences[i2].as_str();
+            let (left, s2, sources) = if self.options.swap_splice_sides && rng.next_u32() % 2 == 1 {
+                // Both sentences contain the pivot, so s2 has a left side too.
+                (
+                    get_words_left_of_pivot(s2, pivot, &self.options).unwrap_or_default(),
+                    s1,
+                    vec![i2, i1],
+                )
+            } else {
+                (left, s2, vec![i1, i2])
+            };
+            let left = self.display_words(&left);
+            let right = self.display_words(
+                &get_words_right_of_pivot_inclusive(s2, pivot, &self.options).unwrap(),
+            );
+            let reply = if left.is_empty() {
+                right
+            } else {
+                format!("{} {}", left, right)
+            };
+            Some(Built::new(self.punctuate(reply, s2), sources))
+        }
+    }
+
+    // pick_sentence picks the index of one of the sentences to build a reply
+    // from, favoring the recently learned ones according to the recency bias,
+    // and those the user taught according to the user bias. Sentences that
+    // were sources of the latest replies are disfavored according to the
+    // source use penalty. The indices must be in range, as on_topic returns
+    // them.
+    fn pick_sentence(
+        &self,
+        ids: &[usize],
+        user: Option<&str>,
+        rng: &mut dyn RngCore,
+    ) -> Option<usize> {
+        let recency_bias = self.options.sentence_recency_bias;
+        let user = user.filter(|_| self.options.user_bias > 0.0);
+        let mut uses: HashMap<&str, i32> = HashMap::new();
+        if self.options.source_use_penalty > 0.0 {
+            for source in self.source_uses.iter().flatten() {
+                *uses.entry(source.as_str()).or_default() += 1;
+            }
+        }
+        if recency_bias <= 0.0 && user.is_none() && uses.is_empty() && self.word_fatigue.is_empty()
+        {
+            return sample_index(ids.len(), rng).map(|i| ids[i]);
+        }
+        let mut by_age: Vec<usize> = ids.to_vec();
+        by_age.sort_by_key(|&i| self.sentence_meta.get(i).map(|m| m.learned_at).unwrap_or(0));
+        let weights: Vec<f64> = by_age
+            .iter()
+            .enumerate()
+            .map(|(rank, &i)| {
+                let s = self.sentences[i].as_str();
+                let mut weight = if recency_bias > 0.0 {
+                    ((rank + 1) as f64).powf(recency_bias)
+                } else {
+                    1.0
+                };
+                if let (Some(user), Some(meta)) = (user, self.sentence_meta.get(i)) {
+                    if meta.users.iter().any(|u| u == user) {
+                        weight *= 1.0 + self.options.user_bias;
+                    }
+                }
+                if let Some(&n) = uses.get(s) {
+                    let penalty = self.options.source_use_penalty.min(1.0);
+                    weight *= (1.0 - penalty).powi(n);
+                }
+                if !self.word_fatigue.is_empty() {
+                    let words: HashSet<Cow<str>> =
+                        index_keys(s, &self.options).into_iter().collect();
+                    weight *= words.iter().map(|w| self.fatigue(w)).product::<f64>();
+                }
+                weight
+            })
+            .collect();
+        match pick_weighted(&weights, rng) {
+            Some(rank) => Some(by_age[rank]),
+            None => sample_index(ids.len(), rng).map(|i| ids[i]),
+        }
+    }
+
+    // continuations returns the indices of the sentences other than s1 in
+    // which the pivot has words after it, or all of them if there are none.
+    fn continuations(&self, ids: &[usize], pivot: &str, s1: &str) -> Vec<usize> {
+        let continuing: Vec<usize> = ids
+            .iter()
+            .copied()
+            .filter(|&i| self.sentences[i] != s1)
+            .filter(|&i| {
+                matches!(get_words_right_of_pivot_inclusive(&self.sentences[i], pivot, &self.options),
+                    Some(right) if right.len() > 1)
+            })
+            .collect();
+        if continuing.is_empty() {
+            ids.to_vec()
+        } else {
+            continuing
+        }
+    }
+
+    // complete_endings returns the indices of the sentences the right side of
+    // a reply may be taken from. When complete endings are preferred, these
+    // are the sentences ending with terminal punctuation, or all of them if
+    // there are none.
+    fn complete_endings(&self, ids: &[usize]) -> Vec<usize> {
+        if self.options.prefer_complete_endings {
+            let complete: Vec<usize> = ids
+                .iter()
+                .copied()
+                .filter(|&i| !terminal_punctuation(&self.sentences[i]).is_empty())
+                .collect();
+            if !complete.is_empty() {
+                return complete;
+            }
+        }
+        ids.to_vec()
+    }
+
+    // content_openings returns the indices of the sentences the left side of
+    // a reply may be taken from. When content openings are preferred, these
+    // are the sentences whose first word isn't a function word, or all of them
+    // if there are none.
+    fn content_openings(&self, ids: &[usize]) -> Vec<usize> {
+        if !self.options.prefer_content_openings {
+            return ids.to_vec();
+        }
+        let is_function_word = |word: &str| {
+            if self.options.function_words.is_empty() {
+                DEFAULT_FUNCTION_WORDS.contains(&word)
+            } else {
+                self.options
+                    .function_words
+                    .iter()
+                    .any(|w| normalize_word(&w.to_lowercase(), &self.options) == word)
+            }
+        };
+        let with_content: Vec<usize> = ids
+            .iter()
+            .copied()
+            .filter(
+                |&i| match split_tokens(&self.sentences[i], &self.options).first() {
+                    Some(first) => !is_function_word(&normalize_word(first, &self.options)),
+                    None => false,
+                },
+            )
+            .collect();
+        if with_content.is_empty() {
+            ids.to_vec()
+        } else {
+            with_content
+        }
+    }
+
+    // lead_ins returns the indices of the sentences the left side of a reply
+    // may be taken from. When a lead-in is preferred, these are the sentences
+    // where the pivot has words before it, or all of them if there are none.
+    fn lead_ins(&self, ids: &[usize], pivot: &str) -> Vec<usize> {
+        if self.options.prefer_lead_in {
+            let with_lead_in: Vec<usize> = ids
+                .iter()
+                .copied()
+                .filter(|&i| {
+                    matches!(get_words_left_of_pivot(&self.sentences[i], pivot, &self.options),
+                        Some(left) if !left.is_empty())
+                })
+                .collect();
+            if !with_lead_in.is_empty() {
+                return with_lead_in;
+            }
+        }
+        ids.to_vec()
+    }
+
+    // random_sentence returns a random sentence from the dictionary, or None
+    // if it does not know any sentences. Sentences are picked uniformly,
+    // unless weighted random sentences are on.
+    pub fn random_sentence(&self, rng: &mut dyn RngCore) -> Option<&str> {
+        self.random_sentence_id(rng)
+            .map(|i| self.sentences[i].as_str())
+    }
+
+    // random_sentence_reply replies with a random sentence, as written.
+    fn random_sentence_reply(&self, rng: &mut dyn RngCore) -> Option<Built> {
+        self.random_sentence_id(rng)
+            .map(|i| Built::new(self.sentences[i].clone(), vec![i]))
+    }
+
+    fn random_sentence_id(&self, rng: &mut dyn RngCore) -> Option<usize> {
+        if self.sentences.is_empty() {
+            return None;
+        }
+        if !self.options.weighted_random_sentences {
+            return sample_index(self.sentences.len(), rng);
+        }
+        let mut sampler = self.sentence_sampler.borrow_mut();
+        // The table is also out of date if sentences were added or removed
+        // other than by learning.
+        if sampler.as_ref().map(|s| s.len()) != Some(self.sentences.len()) {
+            *sampler = self.build_sentence_sampler();
+        }
+        match *sampler {
+            Some(ref sampler) => Some(sampler.sample(rng)),
+            None => sample_index(self.sentences.len(), rng),
+        }
+    }
+
+    // random_word returns a uniformly random known word, as it is used as a
+    // key in the indices, or None if no word is known.
+    pub fn random_word(&self, rng: &mut dyn RngCore) -> Option<&str> {
+        let i = sample_index(self.indices.len(), rng)?;
+        self.indices.iter().nth(i).map(|(word, _)| word)
+    }
+
+    // on_topic returns the indices of the sentences the word appears in that
+    // also contain a word of the topic filter, if one is set. If none do, all
+    // sentences with the word are returned unless the topic is strict.
+    // Indices past the sentences, as stale indices may have, are left out.
+    fn on_topic(&self, word: &str) -> Vec<usize> {
+        let mut ids = self.sentence_ids_with_word(word);
+        ids.retain(|&i| i < self.sentences.len());
+        if self.options.topic_filter.is_empty() {
+            return ids;
+        }
+        let topic_ids = self
+            .options
+            .topic_filter
+            .iter()
+            .map(|w| self.sentence_ids_with_word(&normalize_word(&w.to_lowercase(), &self.options)))
+            .fold(vec![], |all, ids| union_sorted(&all, &ids));
+        let topical = intersect_sorted(&ids, &topic_ids);
+        if topical.is_empty() && !self.options.strict_topic {
+            ids
+        } else {
+            topical
+        }
+    }
+
+    // punctuate ends the reply with the terminal punctuation of the sentence
+    // its right side was taken from, according to the configured mode.
+    fn punctuate(&self, mut reply: String, source: &str) -> String {
+        if self.options.punctuation_tokens {
+            // The reply already ends with the punctuation of its source.
+            return reply;
+        }
+        let ending = terminal_punctuation(source);
+        match self.options.terminal_punctuation {
+            TerminalPunctuation::Drop => {}
+            TerminalPunctuation::Keep => reply.push_str(ending),
+            TerminalPunctuation::Collapse => {
+                let mut last = None;
+                for c in ending.chars() {
+                    if last != Some(c) {
+                        reply.push(c);
+                    }
+                    last = Some(c);
+                }
+            }
+        }
+        reply
+    }
+
+    fn pick_pivot<'a>(&self, known_words: &'a [String], rng: &mut dyn RngCore) -> Option<&'a str> {
+        let mut candidates: Vec<&String> = known_words
+            .iter()
+            .filter(|w| w.chars().count() >= self.options.min_pivot_len)
+            .collect();
+        if candidates.is_empty() {
+            candidates = known_words.iter().collect();
+        }
+        if self.options.recency_bias {
+            candidates.sort_by_key(|w| Reverse(self.last_seen.get(*w).copied().unwrap_or(0)));
+            // Only the most recent half of the known words are candidates.
+            candidates.truncate(candidates.len() - candidates.len() / 2);
+        }
+        if self.options.word_weights.is_empty() && self.word_fatigue.is_empty() {
+            return pick_random(&candidates, rng).map(|w| w.as_str());
+        }
+        let weights: Vec<f64> = candidates
+            .iter()
+            .map(|w| self.word_weight(w) * self.fatigue(w))
+            .collect();
+        match pick_weighted(&weights, rng) {
+            Some(i) => Some(candidates[i].as_str()),
+            None => pick_random(&candidates, rng).map(|w| w.as_str()),
+        }
+    }
+
+    // word_weight returns the pivot weight of a normalized word.
+    fn word_weight(&self, word: &str) -> f64 {
+        self.options
+            .word_weights
+            .iter()
+            .find(|(w, _)| normalize_word(&w.to_lowercase(), &self.options) == word)
+            .map(|(_, weight)| weight.max(0.0))
+            .unwrap_or(1.0)
+    }
+
+    // viable_pivots returns the words of the line that could be used as
+    // pivots to build a reply, i.e. the known words that appear in at least
+    // two sentences.
+    pub fn viable_pivots(&self, line: &str) -> Vec<String> {
+        let mut pivots: Vec<String> = vec![];
+        for word in self.known_words(line) {
+            if !pivots.contains(&word) && self.can_pivot(&word) {
+                pivots.push(word);
+            }
+        }
+        pivots
+    }
+
+    // reply_space returns the number of words that can be used as pivots,
+    // i.e. that appear in at least two sentences.
+    pub fn reply_space(&self) -> usize {
+        match self.warm {
+            Some(ref warm) => warm.pivots.len(),
+            None => self
+                .indices
+                .iter()
+                .filter(|(word, _)| self.can_pivot(word))
+                .count(),
+        }
+    }
+
+    // unreachable_sentences returns the sentences none of whose words appear
+    // in another sentence, so that no pivot can select them and they never
+    // make it into a splice.
+    pub fn unreachable_sentences(&self) -> Vec<&str> {
+        self.sentences
+            .iter()
+            .filter(|sentence| {
+                index_keys(sentence, &self.options)
+                    .iter()
+                    .all(|key| self.indices.get(key).map(|ys| ys.len() < 2).unwrap_or(true))
+            })
+            .map(|sentence| sentence.as_str())
+            .collect()
+    }
+
+    // sentences_only_reachable_via returns the sentences containing the word
+    // in which no other word can be used as a pivot, so that removing the
+    // word would leave them out of every splice.
+    pub fn sentences_only_reachable_via(&self, word: &str) -> Vec<&str> {
+        let word = normalize_word(&word.to_lowercase(), &self.options).into_owned();
+        if !self.can_pivot(&word) {
+            return vec![];
+        }
+        self.sentences_with_word(&word)
+            .into_iter()
+            .filter(|sentence| {
+                split_tokens(sentence, &self.options)
+                    .iter()
+                    .map(|w| normalize_word(w, &self.options))
+                    .all(|w| w == word || !self.can_pivot(&w))
+            })
+            .collect()
+    }
+
+    // word_path returns the shortest chain of words leading from one word to
+    // the other, both included, where each word shares a sentence with the
+    // next. None is returned if either word is unknown or they are further
+    // apart than MAX_WORD_PATH_HOPS words in between.
+    pub fn word_path(&self, from: &str, to: &str) -> Option<Vec<String>> {
+        let from = normalize_word(&from.to_lowercase(), &self.options).into_owned();
+        let to = normalize_word(&to.to_lowercase(), &self.options).into_owned();
+        if !self.knows_word(&from) || !self.knows_word(&to) {
+            return None;
+        }
+        let mut previous: HashMap<String, String> = HashMap::new();
+        let mut visited: HashSet<String> = HashSet::new();
+        visited.insert(from.clone());
+        let mut frontier = vec![from.clone()];
+        for _ in 0..=MAX_WORD_PATH_HOPS {
+            if visited.contains(&to) {
+                break;
+            }
+            let mut next = vec![];
+            for word in &frontier {
+                for sentence in self.sentences_with_word(word) {
+                    for key in index_keys(sentence, &self.options) {
+                        if visited.insert(key.to_string()) {
+                            previous.insert(key.to_string(), word.clone());
+                            next.push(key.into_owned());
+                        }
+                    }
+                }
+            }
+            frontier = next;
+        }
+        if !visited.contains(&to) {
+            return None;
+        }
+        let mut path = vec![to];
+        while let Some(word) = previous.get(path.last().unwrap()) {
+            path.push(word.clone());
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    // similar_words returns, for each word of the line, the known word that
+    // is closest to it in spelling, if any is close enough to be a typo.
+    fn similar_words(&self, line: &str) -> Vec<String> {
+        index_keys(&line.to_lowercase(), &self.options)
+            .into_iter()
+            .filter_map(|word| {
+                let max_distance = if word.chars().count() <= 4 { 1 } else { 2 };
+                self.indices
+                    .iter()
+                    .map(|(known, _)| (edit_distance(&word, known), known))
+                    .filter(|(distance, _)| *distance <= max_distance)
+                    .min()
+                    .map(|(_, known)| known.to_string())
+            })
+            .collect()
+    }
+
+    // normalize_line returns the words of the line as they are used as keys
+    // in the indices, separated by single spaces.
+    pub fn normalize_line(&self, line: &str) -> String {
+        split_tokens(&line.to_lowercase(), &self.options)
+            .iter()
+            .map(|w| normalize_word(w, &self.options))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn known_words(&self, line: &str) -> Vec<String> {
+        let words = split_tokens(&line.to_lowercase(), &self.options)
+            .iter()
+            .map(|s| normalize_word(s, &self.options).into_owned())
+            .collect::<Vec<_>>();
+        if !self.options.prefix_matching {
+            return words.into_iter().filter(|s| self.knows_word(s)).collect();
+        }
+        let mut known = vec![];
+        for word in words {
+            if self.knows_word(&word) {
+                known.push(word);
+            } else if word.chars().count() >= MIN_PREFIX_LEN {
+                known.extend(self.words_with_prefix(&word));
+            }
+        }
+        known
+    }
+
+    // words_with_prefix returns the known words starting with the prefix,
+    // found in the sorted words of the warm cache, or in a sorted copy of the
+    // indices' words if the dictionary isn't warmed up.
+    fn words_with_prefix(&self, prefix: &str) -> Vec<String> {
+        let sorted;
+        let words: &[String] = match self.warm {
+            Some(ref warm) => &warm.sorted_words,
+            None => {
+                let mut words: Vec<String> = self
+                    .indices
+                    .iter()
+                    .map(|(word, _)| word.to_string())
+                    .collect();
+                words.sort();
+                sorted = words;
+                &sorted
+            }
+        };
+        let start = match words.binary_search_by(|w| w.as_str().cmp(prefix)) {
+            Ok(i) | Err(i) => i,
+        };
+        words[start..]
+            .iter()
+            .take_while(|w| w.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    // export_transitions lists, for every run of order words found in the
+    // sentences, the words that follow it and how many times they do. The
+    // edges are sorted by prefix, then by next word, and can be rendered as a
+    // graph, e.g. with Graphviz.
+    pub fn export_transitions(&self, order: usize) -> Vec<(Vec<String>, String, usize)> {
+        let mut counts: HashMap<(Vec<String>, String), usize> = HashMap::new();
+        for sentence in &self.sentences {
+            let words: Vec<String> = split_tokens(sentence, &self.options)
+                .iter()
+                .map(|w| normalize_word(w, &self.options).into_owned())
+                .collect();
+            for i in order..words.len() {
+                let edge = (words[i - order..i].to_vec(), words[i].clone());
+                *counts.entry(edge).or_default() += 1;
+            }
+        }
+        let mut edges: Vec<(Vec<String>, String, usize)> = counts
+            .into_iter()
+            .map(|((prefix, next), count)| (prefix, next, count))
+            .collect();
+        edges.sort();
+        edges
+    }
+
+    // vocab_overlap returns the Jaccard similarity of the indexed words of
+    // both dictionaries, from 0.0 for no shared word to 1.0 for the same
+    // words. Two empty dictionaries are considered unrelated.
+    pub fn vocab_overlap(&self, other: &Dictionary) -> f64 {
+        let words: HashSet<&str> = self.indices.iter().map(|(word, _)| word).collect();
+        let other_words: HashSet<&str> = other.indices.iter().map(|(word, _)| word).collect();
+        let union = words.union(&other_words).count();
+        if union == 0 {
+            return 0.0;
+        }
+        words.intersection(&other_words).count() as f64 / union as f64
+    }
+
+    // reply_diversity replies to the given number of random known words and
+    // returns the ratio of distinct replies to replies, from near 0.0 when
+    // every reply is the same to 1.0 when none repeat. A low diversity is a
+    // sign of a brain too small, or too repetitive, to say much. Returns 0.0
+    // if there were no replies.
+    pub fn reply_diversity(&self, sample: usize, rng: &mut dyn RngCore) -> f64 {
+        let mut replies = 0;
+        let mut distinct: HashSet<String> = HashSet::new();
+        for _ in 0..sample {
+            let word = match self.random_word(rng) {
+                Some(word) => word,
+                None => break,
+            };
+            if let Some(reply) = self.respond_to(word, rng) {
+                replies += 1;
+                distinct.insert(reply);
+            }
+        }
+        if replies == 0 {
+            return 0.0;
+        }
+        distinct.len() as f64 / replies as f64
+    }
+
+    // checksum returns a hash of the sentences and the indices that is the
+    // same for equal dictionaries, across runs and builds, so it can be
+    // stored to tell later whether the dictionary changed.
+    pub fn checksum(&self) -> u64 {
+        let mut hash = Fnv1a::new();
+        hash.write_u64(self.sentences.len() as u64);
+        for sentence in &self.sentences {
+            hash.write_str(sentence);
+        }
+        let mut indices: Vec<(&str, &Postings)> = self.indices.iter().collect();
+        indices.sort_by_key(|(word, _)| *word);
+        hash.write_u64(indices.len() as u64);
+        for (word, ys) in indices {
+            hash.write_str(word);
+            hash.write_u64(ys.len() as u64);
+            for y in ys.iter() {
+                hash.write_u64(u64::from(y));
+            }
+        }
+        hash.finish()
+    }
+
+    // find_dangling_indices returns the word and sentence index pairs of the
+    // indices that point past the end of the sentences, or to a sentence
+    // that does not contain the word. The pairs are sorted by word, then by
+    // index. A non-empty result means the indices should be rebuilt.
+    pub fn find_dangling_indices(&self) -> Vec<(String, usize)> {
+        let mut dangling: Vec<(String, usize)> = self
+            .indices
+            .iter()
+            .flat_map(|(word, ys)| ys.iter().map(move |y| (word, y as usize)))
+            .filter(|(word, y)| match self.get_sentence(*y) {
+                Some(sentence) => !split_tokens(sentence, &self.options)
+                    .iter()
+                    .any(|w| normalize_word(w, &self.options) == *word),
+                None => true,
+            })
+            .map(|(word, y)| (word.to_string(), y))
+            .collect();
+        dangling.sort();
+        dangling
+    }
+
+    // get_sentence returns the sentence at the given index, or None if the
+    // index is out of range, e.g. because it came from stale indices.
+    pub fn get_sentence(&self, i: usize) -> Option<&str> {
+        self.sentences.get(i).map(|s| s.as_str())
+    }
+
+    fn sentences_with_word(&self, word: &str) -> Vec<&str> {
+        self.sentences_at(&self.sentence_ids_with_word(word))
+    }
+
+    // sentence_ids_with_word returns the indices of the sentences the word
+    // appears in. Sentences are indexed in order, so the indices are sorted.
+    fn sentence_ids_with_word(&self, word: &str) -> Vec<usize> {
+        self.indices
+            .get(word)
+            .map(|ys| ys.iter().map(|y| y as usize).collect())
+            .unwrap_or_else(Vec::new)
+    }
+
+    fn sentences_at(&self, ids: &[usize]) -> Vec<&str> {
+        ids.iter().filter_map(|&i| self.get_sentence(i)).collect()
+    }
+}
+
+// Fnv1a is the 64-bit FNV-1a hash. Unlike the standard library's hasher, its
+// output is guaranteed not to change between Rust releases.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Fnv1a {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.write(&n.to_le_bytes());
+    }
+
+    // write_str writes the string's length before it, so that the boundaries
+    // between strings change the hash.
+    fn write_str(&mut self, s: &str) {
+        self.write_u64(s.len() as u64);
+        self.write(s.as_bytes());
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// sentence_count returns the number of sentences the line would be split
+// into when learned.
+pub fn sentence_count(line: &str) -> usize {
+    split_sentences(line).len()
+}
+
+fn split_sentences(s: &str) -> Vec<&str> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"(?<=[.!?]+)\s+").unwrap();
+    }
+    RE.split(s).filter(|s| !s.is_empty()).collect()
+}
+
+// find_code returns the byte range of the first piece of code in the text,
+// including the backticks around it. Code starts with a run of backticks and
+// ends with the next run of as many backticks, which covers both `inline`
+// code and ```fenced``` blocks.
+fn find_code(s: &str) -> Option<(usize, usize)> {
+    let mut search_from = 0;
+    while let Some(i) = s[search_from..].find('`') {
+        let start = search_from + i;
+        let fence = s[start..].len() - s[start..].trim_start_matches('`').len();
+        let body = start + fence;
+        let mut j = body;
+        while let Some(k) = s[j..].find('`') {
+            let close = j + k;
+            let run = s[close..].len() - s[close..].trim_start_matches('`').len();
+            if run == fence {
+                return Some((start, close + run));
+            }
+            j = close + run;
+        }
+        search_from = body;
+    }
+    None
+}
+
+// strip_code removes every piece of code found by find_code from the text.
+// A space is left in place of code that isn't next to whitespace, so that
+// the words around it are not glued together.
+fn strip_code(s: &str) -> Cow<'_, str> {
+    let mut rest = s;
+    let mut stripped = String::new();
+    while let Some((start, end)) = find_code(rest) {
+        stripped.push_str(&rest[..start]);
+        rest = &rest[end..];
+        let glued = !stripped.is_empty()
+            && !stripped.ends_with(char::is_whitespace)
+            && !rest.is_empty()
+            && !rest.starts_with(char::is_whitespace);
+        if glued {
+            stripped.push(' ');
+        }
+    }
+    if rest.len() == s.len() {
+        Cow::Borrowed(s)
+    } else {
+        stripped.push_str(rest);
+        Cow::Owned(stripped)
+    }
+}
+
+// sanitize removes the invisible characters that can spoof how text is
+// displayed, such as zero-width spaces and bidi overrides, and the control
+// characters other than line breaks. Zero-width joiners are kept, as emoji
+// sequences need them. Tabs and carriage returns become spaces.
+fn sanitize(s: &str) -> Cow<'_, str> {
+    let invisible = |c: char| match c {
+        '\n' => false,
+        '\u{061c}' | '\u{200b}' | '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' => true,
+        '\u{2060}'..='\u{2064}' | '\u{2066}'..='\u{2069}' | '\u{feff}' => true,
+        c => c.is_control(),
+    };
+    if !s.contains(invisible) {
+        return Cow::Borrowed(s);
+    }
+    Cow::Owned(
+        s.chars()
+            .filter_map(|c| match c {
+                '\t' | '\r' => Some(' '),
+                c if invisible(c) => None,
+                c => Some(c),
+            })
+            .collect(),
+    )
+}
+
+// split_lines splits the text into the parts that are split into sentences
+// on their own, according to how line breaks are treated.
+fn split_lines(s: &str, line_breaks: LineBreaks) -> Vec<&str> {
+    match line_breaks {
+        LineBreaks::Ignore => vec![s],
+        LineBreaks::Line => s.lines().map(str::trim).filter(|l| !l.is_empty()).collect(),
+        LineBreaks::Paragraph => {
+            let mut paragraphs = vec![];
+            let mut start = None;
+            let mut end = 0;
+            let mut offset = 0;
+            for line in s.split('\n') {
+                if line.trim().is_empty() {
+                    if let Some(start) = start.take() {
+                        paragraphs.push(s[start..end].trim());
+                    }
+                } else {
+                    start.get_or_insert(offset);
+                    end = offset + line.len();
+                }
+                offset += line.len() + 1;
+            }
+            if let Some(start) = start {
+                paragraphs.push(s[start..end].trim());
+            }
+            paragraphs
+        }
+    }
+}
+
+fn split_words(s: &str) -> Vec<&str> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"[,.!?:\s]+").unwrap();
+    }
+    RE.split(s).filter(|s| !s.is_empty()).collect()
+}
+
+// split_words_and_punctuation splits the text into words like split_words,
+// but keeps every run of punctuation as a token of its own.
+fn split_words_and_punctuation(s: &str) -> Vec<&str> {
+    let mut tokens = vec![];
+    // Where the current token starts, and whether it is punctuation.
+    let mut start: Option<(usize, bool)> = None;
+    for (i, c) in s.char_indices() {
+        let kind = if c.is_whitespace() {
+            None
+        } else {
+            Some(is_word_punctuation(c))
+        };
+        match (start, kind) {
+            (Some((_, current)), Some(next)) if current == next => continue,
+            (Some((j, _)), _) => tokens.push(&s[j..i]),
+            (None, _) => {}
+        }
+        start = kind.map(|punctuation| (i, punctuation));
+    }
+    if let Some((j, _)) = start {
+        tokens.push(&s[j..]);
+    }
+    tokens
+}
+
+// is_word_punctuation returns whether the character is one of the punctuation
+// marks split_words splits words on.
+fn is_word_punctuation(c: char) -> bool {
+    matches!(c, ',' | '.' | '!' | '?' | ':')
+}
+
+// split_tokens splits the text into words like split_words, keeping runs of
+// punctuation if they are tokens, then, if emoji are tokens, splits every
+// emoji out of the words it is written next to.
+fn split_tokens<'a>(s: &'a str, options: &Options) -> Vec<&'a str> {
+    let words = if options.punctuation_tokens {
+        split_words_and_punctuation(s)
+    } else {
+        split_words(s)
+    };
+    if options.emoji_tokens {
+        words.into_iter().flat_map(split_emoji).collect()
+    } else {
+        words
+    }
+}
+
+// split_emoji splits the word into runs of text and single emoji. An emoji is
+// kept whole along with the modifiers, variation selectors and tags that
+// follow it, and the emoji it is joined to with zero width joiners, so that
+// e.g. a family emoji is one token.
+fn split_emoji(word: &str) -> Vec<&str> {
+    let mut tokens = vec![];
+    let mut start = 0;
+    let mut chars = word.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if !is_emoji(c) {
+            continue;
+        }
+        if start < i {
+            tokens.push(&word[start..i]);
+        }
+        let mut end = i + c.len_utf8();
+        let mut regional_indicators = if is_regional_indicator(c) { 1 } else { 0 };
+        while let Some(&(j, next)) = chars.peek() {
+            let joined = next == ZERO_WIDTH_JOINER
+                || is_emoji_modifier(next)
+                || (is_regional_indicator(next) && regional_indicators == 1)
+                || word[..j].ends_with(ZERO_WIDTH_JOINER) && is_emoji(next);
+            if !joined {
+                break;
+            }
+            if is_regional_indicator(next) {
+                regional_indicators += 1;
+            }
+            end = j + next.len_utf8();
+            chars.next();
+        }
+        tokens.push(&word[i..end]);
+        start = end;
+    }
+    if start < word.len() {
+        tokens.push(&word[start..]);
+    }
+    tokens
+}
+
+const ZERO_WIDTH_JOINER: char = '\u{200d}';
+
+fn is_emoji(c: char) -> bool {
+    match c {
+        '\u{1f000}'..='\u{1faff}' => !is_emoji_modifier(c),
+        '\u{2600}'..='\u{27bf}' | '\u{2b00}'..='\u{2bff}' => true,
+        _ => false,
+    }
+}
+
+// is_regional_indicator returns whether the character is one of the letters
+// that make up flags, two at a time.
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1f1e6}'..='\u{1f1ff}').contains(&c)
+}
+
+// is_emoji_modifier returns whether the character changes the emoji before
+// it: skin tones, variation selectors, the keycap sign and tags.
+fn is_emoji_modifier(c: char) -> bool {
+    matches!(c,
+        '\u{1f3fb}'..='\u{1f3ff}'
+        | '\u{fe0e}'..='\u{fe0f}'
+        | '\u{20e3}'
+        | '\u{e0020}'..='\u{e007f}')
+}
+
+// terminal_punctuation returns the run of sentence-ending marks at the end
+// of the sentence, e.g. "?!" for "really?!".
+fn terminal_punctuation(sentence: &str) -> &str {
+    let trimmed = sentence.trim_end();
+    let end = trimmed.trim_end_matches(&['.', '!', '?'][..]);
+    &trimmed[end.len()..]
+}
+
+// edit_distance returns the Levenshtein distance between two words.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// sort_sentences sorts the sentences alphabetically, keeping each sentence's
+// metadata at the same index as the sentence.
+fn sort_sentences(sentences: &mut Vec<String>, meta: &mut Vec<SentenceMeta>) {
+    let mut pairs: Vec<(String, SentenceMeta)> = sentences.drain(..).zip(meta.drain(..)).collect();
+    pairs.sort_by_key(|(sentence, _)| sentence.to_lowercase());
+    for (sentence, m) in pairs {
+        sentences.push(sentence);
+        meta.push(m);
+    }
+}
+
+// sorted returns a view of the map sorted by key, so that it is serialized
+// the same way every time.
+fn sorted<K: Ord, V>(map: &HashMap<K, V>) -> BTreeMap<&K, &V> {
+    map.iter().collect()
+}
+
+// serialize_sorted serializes the map with its keys in sorted order.
+fn serialize_sorted<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+    S: Serializer,
+{
+    sorted(map).serialize(serializer)
+}
+
+// write_json streams the value as JSON into a temporary file next to the
+// given path, then moves it over the path. The JSON is never held in memory
+// as a whole, and a crash halfway through leaves the old file untouched.
+pub fn write_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<(), Error> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut writer = io::BufWriter::new(fs::File::create(&tmp_path)?);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.flush()?;
+    drop(writer);
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// serialized_size returns the number of bytes of the value's JSON, without
+// holding the JSON in memory.
+fn serialized_size<T: Serialize + ?Sized>(value: &T) -> Result<u64, Error> {
+    struct ByteCounter(u64);
+
+    impl io::Write for ByteCounter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = ByteCounter(0);
+    serde_json::to_writer(&mut counter, value)?;
+    Ok(counter.0)
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, Error> {
+    let data = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&data)?)
+}
+
+// read_json_or_default reads a JSON file that may not exist, returning the
+// default value if it doesn't.
+fn read_json_or_default<T: DeserializeOwned + Default>(path: &Path) -> Result<T, Error> {
+    if path.is_file() {
+        read_json(path)
+    } else {
+        Ok(T::default())
+    }
+}
+
+// pick_random picks a uniformly random element, or returns None if there are
+// none.
+fn pick_random<'a, T>(v: &'a [T], rng: &mut dyn RngCore) -> Option<&'a T> {
+    sample_index(v.len(), rng).map(|i| &v[i])
+}
+
+// random_unit returns a uniformly random number in [0, 1), made from 53
+// random bits so that every value is exactly representable.
+pub(crate) fn random_unit(rng: &mut dyn RngCore) -> f64 {
+    (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// pick_weighted picks an index with probability proportional to its weight,
+// or returns None if no weight is positive.
+fn pick_weighted(weights: &[f64], rng: &mut dyn RngCore) -> Option<usize> {
+    let total: f64 = weights.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let mut target = random_unit(rng) * total;
+    for (i, weight) in weights.iter().enumerate() {
+        if target < *weight {
+            return Some(i);
+        }
+        target -= weight;
+    }
+    weights.iter().rposition(|w| *w > 0.0)
+}
+
+// sample_index picks a uniformly random index below len, or returns None if
+// len is zero. Draws that fall in the uneven tail of the RNG's range are
+// rejected rather than wrapped with a modulo, which would favor the lower
+// indices.
+pub(crate) fn sample_index(len: usize, rng: &mut dyn RngCore) -> Option<usize> {
+    if len == 0 {
+        return None;
+    }
+    let len = len as u64;
+    let zone = u64::MAX - (u64::MAX % len);
+    loop {
+        let x = rng.next_u64();
+        if x < zone {
+            return Some((x % len) as usize);
+        }
+    }
+}
+
+// normalize_word returns the form of the word that is used as its key in the
+// indices.
+fn normalize_word<'a>(word: &'a str, options: &Options) -> Cow<'a, str> {
+    let mut word = Cow::Borrowed(word);
+    if word.chars().any(char::is_uppercase) {
+        word = Cow::Owned(word.to_lowercase());
+    }
+    if options.fold_confusables {
+        word = Cow::Owned(confusables::fold(&word));
+    }
+    if let Some(limit) = options.elongation_limit {
+        word = Cow::Owned(collapse_elongations(&word, limit));
+    }
+    if options.stem_words {
+        word = Cow::Owned(stemmer::stem(&word).into_owned());
+    }
+    word
+}
+
+// index_keys returns the normalized words of the sentence that can be used as
+// keys in the indices. Tokens without a letter, digit or emoji, such as "-" or
+// ";)", are left out, so that they never become pivots, unless they are runs
+// of punctuation kept as tokens.
+fn index_keys<'a>(sentence: &'a str, options: &Options) -> Vec<Cow<'a, str>> {
+    split_tokens(sentence, options)
+        .into_iter()
+        .map(|word| normalize_word(word, options))
+        .filter(|key| {
+            key.chars().any(|c| c.is_alphanumeric() || is_emoji(c))
+                || (options.punctuation_tokens && key.chars().all(is_word_punctuation))
+        })
+        .collect()
+}
+
+// normalized_length returns the number of characters in the sentence's
+// normalized words, counting a single space between words.
+fn normalized_length(sentence: &str, options: &Options) -> usize {
+    let words = split_tokens(sentence, options);
+    let letters: usize = words
+        .iter()
+        .map(|w| normalize_word(w, options).chars().count())
+        .sum();
+    letters + words.len().saturating_sub(1)
+}
+
+// collapse_elongations shortens every run of three or more repeated letters
+// in the word to at most limit letters.
+fn collapse_elongations(word: &str, limit: usize) -> String {
+    let chars: Vec<char> = word.chars().collect();
+    let mut collapsed = String::with_capacity(word.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let run = chars[i..].iter().take_while(|x| **x == c).count();
+        let keep = if run >= 3 && c.is_alphabetic() {
+            run.min(limit.max(1))
+        } else {
+            run
+        };
+        for _ in 0..keep {
+            collapsed.push(c);
+        }
+        i += run;
+    }
+    collapsed
+}
+
+// alternate takes a word of a and one of b in turn, then the words left in
+// the longer of the two.
+fn alternate<'a>(a: &[&'a str], b: &[&'a str]) -> Vec<&'a str> {
+    let mut words = vec![];
+    for i in 0..a.len().max(b.len()) {
+        words.extend(a.get(i));
+        words.extend(b.get(i));
+    }
+    words
+}
+
+fn get_words_left_of_pivot<'a>(
+    line: &'a str,
+    pivot: &str,
+    options: &Options,
+) -> Option<Vec<&'a str>> {
+    let words = split_tokens(line, options);
+    words
         .iter()
-        .position(|word| word == &pivot)
+        .position(|word| normalize_word(word, options) == pivot)
         .map(|pivot_position| words[0..pivot_position].to_vec())
 }
 
-fn get_words_right_of_pivot_inclusive<'a>(line: &'a str, pivot: &'a str) -> Option<Vec<&'a str>> {
-    let words = split_words(line);
-    words
-        .iter()
-        .position(|word| word == &pivot)
-        .map(|pivot_position| words[pivot_position..words.len()].to_vec())
-}
+fn get_words_left_of_pivot_inclusive<'a>(
+    line: &'a str,
+    pivot: &str,
+    options: &Options,
+) -> Option<Vec<&'a str>> {
+    let words = split_tokens(line, options);
+    words
+        .iter()
+        .position(|word| normalize_word(word, options) == pivot)
+        .map(|pivot_position| words[0..=pivot_position].to_vec())
+}
+
+fn get_words_right_of_pivot_inclusive<'a>(
+    line: &'a str,
+    pivot: &str,
+    options: &Options,
+) -> Option<Vec<&'a str>> {
+    let words = split_tokens(line, options);
+    words
+        .iter()
+        .position(|word| normalize_word(word, options) == pivot)
+        .map(|pivot_position| words[pivot_position..words.len()].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sample_replies replies to the line once for each of the first n seeds,
+    // skipping the seeds that give no reply.
+    fn sample_replies(dict: &Dictionary, line: &str, n: u64) -> Vec<String> {
+        use crate::rng::BorgRng;
+
+        (0..n)
+            .filter_map(|seed| dict.respond_to(line, &mut BorgRng::new(seed)))
+            .collect()
+    }
+
+    #[test]
+    fn test_split_sentences() {
+        assert_eq!(
+            vec![
+                "Hi.",
+                "This sentence is going to be split.",
+                "We.cant.split.things.that.look.like.urls.",
+                "That's a single sentence.",
+                "Lol!",
+                "A single sentence!!!!",
+                "Look at this image: https://imgur.com/gallery/PXSNky0"
+            ],
+            split_sentences(
+                "Hi. This sentence is going to be split. \
+                We.cant.split.things.that.look.like.urls. That's a single sentence. \
+                Lol! A single sentence!!!! Look at this image: https://imgur.com/gallery/PXSNky0"
+            ),
+        );
+    }
+
+    // This tests that the Dictionary::rebuild_indices function is building indices correctly.
+    #[test]
+    fn test_dictionary_rebuild_indices() {
+        let mut d = Dictionary {
+            sentences: vec![
+                "this is a test.".to_string(),
+                "this is is not a trick!".to_string(), // The double "is" is intentional
+                "hello world!".to_string(),
+            ],
+            indices: Indices::new(),
+            ..Default::default()
+        };
+        d.rebuild_indices();
+
+        // Ensure that sentences were sorted after rebuilding incides.
+        assert_eq!(
+            vec![
+                "hello world!".to_string(),
+                "this is a test.".to_string(),
+                "this is is not a trick!".to_string(),
+            ],
+            d.sentences
+        );
+
+        // Ensure that the indices were correctly built
+        assert_eq!(
+            Indices::from(hashmap![
+                "this".to_string() => vec![1, 2],
+                "is".to_string() => vec![1, 2],
+                "a".to_string() => vec![1, 2],
+                "test".to_string() => vec![1],
+                "not".to_string() => vec![2],
+                "trick".to_string() => vec![2],
+                "hello".to_string() => vec![0],
+                "world".to_string() => vec![0]
+            ]),
+            d.indices
+        );
+    }
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(
+            vec!["Hello", "world", "This", "is", "a", "test", "I", "am", "a", "test"],
+            split_words("...Hello world!!!!This is a test? I.am.a.test.")
+        );
+    }
+
+    #[test]
+    fn test_needs_to_build_indices() {
+        // Indices should have to be rebuilt when the bot has sentences,
+        // but no indices. On other conditions, it assumes that the indices
+        // are correct and that they do not need to be rebuilt.
+
+        assert!(Dictionary {
+            sentences: vec!["hello world".to_string()],
+            indices: Indices::new(),
+            ..Default::default()
+        }
+        .needs_to_build_indices());
+
+        assert!(!Dictionary {
+            sentences: vec!["hello world".to_string()],
+            indices: Indices::from(hashmap![
+                "hello".to_string() => vec![0],
+                "world".to_string() => vec![0]
+            ]),
+            ..Default::default()
+        }
+        .needs_to_build_indices());
+
+        assert!(!Dictionary {
+            sentences: vec![],
+            indices: Indices::new(),
+            ..Default::default()
+        }
+        .needs_to_build_indices());
+    }
+
+    #[test]
+    fn test_knows_sentence() {
+        let d = Dictionary {
+            sentences: vec![
+                "hello world".to_string(),
+                "i am a little teapot.".to_string(),
+                "my name is foo...".to_string(),
+                "short and stout".to_string(),
+            ],
+            indices: Indices::from(hashmap![
+                "hello".to_string() => vec![0],
+                "world".to_string() => vec![0],
+                "i".to_string() => vec![1],
+                "am".to_string() => vec![1],
+                "a".to_string() => vec![1],
+                "little".to_string() => vec![1],
+                "teapot".to_string() => vec![1],
+                "my".to_string() => vec![2],
+                "name".to_string() => vec![2],
+                "is".to_string() => vec![2],
+                "foo".to_string() => vec![2],
+                "short".to_string() => vec![3],
+                "and".to_string() => vec![3],
+                "stout".to_string() => vec![3]
+            ]),
+            ..Default::default()
+        };
+        assert!(d.knows_sentence(&"my name is foo...".to_string()));
+        assert!(d.knows_sentence(&"i am a little teapot.".to_string()));
+        assert!(d.knows_sentence(&"short and stout".to_string()));
+        assert!(!d.knows_sentence(&"i shouldn't know this".to_string()));
+        assert!(!d.knows_sentence(&"".to_string()));
+        assert!(!d.knows_sentence(&"0".to_string()));
+        assert!(!d.knows_sentence(&"a".to_string()));
+    }
+
+    #[test]
+    fn test_knows_word() {
+        let d = Dictionary {
+            sentences: vec![
+                "and i am a little teapot".to_string(),
+                "my name is josh and i am a little teapot".to_string(),
+            ],
+            indices: Indices::from(hashmap![
+                "and".to_string() => vec![0, 1],
+                "i".to_string() => vec![0, 1],
+                "am".to_string() => vec![0, 1],
+                "a".to_string() => vec![0, 1],
+                "little".to_string() => vec![0, 1],
+                "teapot".to_string() => vec![0, 1],
+                "my".to_string() => vec![1],
+                "name".to_string() => vec![1],
+                "is".to_string() => vec![1],
+                "josh".to_string() => vec![1]
+            ]),
+            ..Default::default()
+        };
+
+        assert!(d.knows_word("and"));
+        assert!(d.knows_word("teapot"));
+        assert!(d.knows_word("josh"));
+        assert!(!d.knows_word("rat"));
+        assert!(!d.knows_word("dog"));
+        assert!(!d.knows_word(" "));
+        assert!(!d.knows_word(""));
+    }
+
+    #[test]
+    fn test_insert_word_into_indices() {
+        let mut indices = Indices::from(hashmap![
+            "joy".to_string() => vec![1, 2]
+        ]);
+        indices.insert("john", 10);
+        assert_eq!(
+            Indices::from(hashmap![
+                "joy".to_string() => vec![1, 2],
+                "john".to_string() => vec![10]
+            ]),
+            indices
+        );
+        indices.insert("john", 20);
+        assert_eq!(
+            Indices::from(hashmap![
+                "joy".to_string() => vec![1, 2],
+                "john".to_string() => vec![10, 20]
+            ]),
+            indices
+        );
+        indices.insert("joy", 1);
+        assert_eq!(
+            Indices::from(hashmap![
+                "joy".to_string() => vec![1, 2],
+                "john".to_string() => vec![10, 20]
+            ]),
+            indices
+        );
+        indices.insert("joy", 6);
+        assert_eq!(
+            Indices::from(hashmap![
+                "joy".to_string() => vec![1, 2, 6],
+                "john".to_string() => vec![10, 20]
+            ]),
+            indices
+        );
+    }
+
+    #[test]
+    fn test_learn() {
+        let mut dict = Dictionary {
+            sentences: vec![],
+            indices: Indices::new(),
+            ..Default::default()
+        };
+        dict.learn("Hey there, everyone!");
+        assert_eq!(
+            Dictionary {
+                sentences: vec!["hey there, everyone!".to_string()],
+                indices: Indices::from(hashmap![
+                    "hey".to_string() => vec![0],
+                    "there".to_string() => vec![0],
+                    "everyone".to_string() => vec![0]
+                ]),
+                ..Default::default()
+            },
+            dict
+        );
+        dict.learn("How is everyone doing today?!");
+        assert_eq!(
+            Dictionary {
+                sentences: vec![
+                    "hey there, everyone!".to_string(),
+                    "how is everyone doing today?!".to_string(),
+                ],
+                indices: Indices::from(hashmap![
+                    "hey".to_string() => vec![0],
+                    "there".to_string() => vec![0],
+                    "everyone".to_string() => vec![0, 1],
+                    "how".to_string() => vec![1],
+                    "is".to_string() => vec![1],
+                    "doing".to_string() => vec![1],
+                    "today".to_string() => vec![1]
+                ]),
+                ..Default::default()
+            },
+            dict
+        );
+        dict.learn("I've been doing fine today, what about you?");
+        assert_eq!(
+            Dictionary {
+                sentences: vec![
+                    "hey there, everyone!".to_string(),
+                    "how is everyone doing today?!".to_string(),
+                    "i've been doing fine today, what about you?".to_string()
+                ],
+                indices: Indices::from(hashmap![
+                    "hey".to_string() => vec![0],
+                    "there".to_string() => vec![0],
+                    "everyone".to_string() => vec![0, 1],
+                    "how".to_string() => vec![1],
+                    "is".to_string() => vec![1],
+                    "doing".to_string() => vec![1, 2],
+                    "today".to_string() => vec![1, 2],
+                    "i've".to_string() => vec![2],
+                    "been".to_string() => vec![2],
+                    "fine".to_string() => vec![2],
+                    "what".to_string() => vec![2],
+                    "about".to_string() => vec![2],
+                    "you".to_string() => vec![2]
+                ]),
+                ..Default::default()
+            },
+            dict
+        );
+    }
+
+    #[test]
+    fn test_respond() {
+        let dict = Dictionary {
+            sentences: vec![
+                "hey there everyone".to_string(),
+                "everyone is a crab".to_string(),
+                "crabs are great".to_string(),
+                "there are many crabs".to_string(),
+                "crabs".to_string(),
+            ],
+            indices: Indices::from(hashmap![
+                "hey".to_string() => vec![0],
+                "there".to_string() => vec![0, 3],
+                "everyone".to_string() => vec![0, 1],
+                "is".to_string() => vec![1],
+                "a".to_string() => vec![1],
+                "crab".to_string() => vec![1],
+                "crabs".to_string() => vec![2, 3, 4],
+                "are".to_string() => vec![2, 3],
+                "great".to_string() => vec![3],
+                "many".to_string() => vec![3]
+            ]),
+            ..Default::default()
+        };
+        use rand::rngs::mock::StepRng;
+        assert_eq!(
+            Some("everyone".to_string()),
+            dict.respond_to("Hey there everyone!", &mut StepRng::new(2, 1))
+        );
+        assert_eq!(
+            Some("hey there everyone is a crab".to_string()),
+            dict.respond_to("Hey there everyone!", &mut StepRng::new(8, 10))
+        );
+        assert_eq!(
+            None,
+            dict.respond_to("hey there crab people", &mut StepRng::new(2, 7))
+        );
+        assert_eq!(
+            Some("crabs".to_string()),
+            dict.respond_to("hey there crabs people", &mut StepRng::new(2, 7))
+        );
+    }
+
+    #[test]
+    fn test_known_words() {
+        let dict = Dictionary {
+            sentences: vec!["hello world!".to_string(), "i love pizza.".to_string()],
+            indices: Indices::from(hashmap![
+                "hello".to_string() => vec![0],
+                "world".to_string() => vec![0],
+                "i".to_string() => vec![1],
+                "love".to_string() => vec![1],
+                "pizza".to_string() => vec![1]
+            ]),
+            ..Default::default()
+        };
+
+        let empty: Vec<&str> = vec![];
+
+        assert_eq!(vec!["i", "love", "pizza"], dict.known_words("I Love Pizza"));
+        assert_eq!(vec!["i", "pizza"], dict.known_words("I Hate Pizza!"));
+        assert_eq!(vec!["i", "love"], dict.known_words("I Love You"));
+        assert_eq!(empty, dict.known_words("foo likes cake"));
+        assert_eq!(empty, dict.known_words("pizzacake"));
+    }
+
+    #[test]
+    fn test_sentences_with_word() {
+        let dict = Dictionary {
+            sentences: vec![
+                "hello world!".to_string(),
+                "i love pizza.".to_string(),
+                "pizza is like, cool".to_string(),
+            ],
+            indices: Indices::from(hashmap![
+                "hello".to_string() => vec![0],
+                "world".to_string() => vec![0],
+                "i".to_string() => vec![1],
+                "love".to_string() => vec![1],
+                "pizza".to_string() => vec![1, 2],
+                "is".to_string() => vec![2],
+                "like".to_string() => vec![2],
+                "cool".to_string() => vec![2]
+            ]),
+            ..Default::default()
+        };
+
+        let empty: Vec<&str> = vec![];
+
+        assert_eq!(
+            vec!["i love pizza.", "pizza is like, cool"],
+            dict.sentences_with_word("pizza")
+        );
+        assert_eq!(vec!["i love pizza."], dict.sentences_with_word("love"));
+        assert_eq!(empty, dict.sentences_with_word("nonexisting"));
+        assert_eq!(empty, dict.sentences_with_word("luve"));
+        assert_eq!(empty, dict.sentences_with_word(""));
+    }
+
+    #[test]
+    fn test_get_words_left_of_pivot() {
+        assert_eq!(
+            Some(vec!["this", "is", "a"]),
+            get_words_left_of_pivot(
+                "this is a test yeah this is a test",
+                "test",
+                &Options::default()
+            )
+        );
+        assert_eq!(
+            Some(Vec::<&str>::new()),
+            get_words_left_of_pivot("this", "this", &Options::default())
+        );
+        assert_eq!(
+            Some(Vec::<&str>::new()),
+            get_words_left_of_pivot("this this", "this", &Options::default())
+        );
+        assert_eq!(
+            None,
+            get_words_left_of_pivot("i am a little teapot", "fox", &Options::default())
+        );
+        assert_eq!(
+            None,
+            get_words_left_of_pivot(
+                "abc def ghi jkl",
+                "abc def", /* not a word */
+                &Options::default()
+            )
+        );
+    }
+
+    #[test]
+    fn test_get_words_right_of_pivot_inclusive() {
+        assert_eq!(
+            Some(vec!["test", "yeah", "this", "is", "a", "test"]),
+            get_words_right_of_pivot_inclusive(
+                "this is a test yeah this is a test",
+                "test",
+                &Options::default()
+            )
+        );
+        assert_eq!(
+            Some(vec!["this"]),
+            get_words_right_of_pivot_inclusive("this", "this", &Options::default())
+        );
+        assert_eq!(
+            Some(vec!["this", "this"]),
+            get_words_right_of_pivot_inclusive("this this", "this", &Options::default())
+        );
+        assert_eq!(
+            None,
+            get_words_left_of_pivot("i am a little teapot", "fox", &Options::default())
+        );
+        assert_eq!(
+            None,
+            get_words_right_of_pivot_inclusive(
+                "abc def ghi jkl",
+                "abc def", /* not a word */
+                &Options::default()
+            )
+        );
+    }
+
+    #[test]
+    fn test_last_seen() {
+        let mut dict = Dictionary::new_empty();
+        let t0 = UNIX_EPOCH + Duration::from_secs(1000);
+        let t1 = UNIX_EPOCH + Duration::from_secs(2000);
+        dict.learn_at("cats are nice", t0);
+        dict.learn_at("dogs are nice too", t1);
+
+        assert_eq!(Some(t0), dict.last_seen("cats"));
+        assert_eq!(Some(t1), dict.last_seen("dogs"));
+        assert_eq!(Some(t1), dict.last_seen("Are"));
+        assert_eq!(None, dict.last_seen("birds"));
+    }
+
+    #[test]
+    fn test_recency_bias() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        let old = UNIX_EPOCH + Duration::from_secs(1000);
+        let new = UNIX_EPOCH + Duration::from_secs(2000);
+        dict.learn_at("cats purr. cats sleep.", old);
+        dict.learn_at("dogs bark. dogs run.", new);
+
+        // Without the bias, a zero RNG picks the first known word.
+        assert_eq!(
+            Some("cats purr".to_string()),
+            dict.respond_to("cats and dogs", &mut StepRng::new(0, 0))
+        );
+
+        dict.set_options(Options {
+            recency_bias: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            Some("dogs bark".to_string()),
+            dict.respond_to("cats and dogs", &mut StepRng::new(0, 0))
+        );
+    }
+
+    #[test]
+    fn test_terminal_punctuation() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza!!! I love pasta.");
+
+        assert_eq!(
+            Some("i love pizza".to_string()),
+            dict.respond_to("love", &mut StepRng::new(0, 0))
+        );
+
+        dict.set_options(Options {
+            terminal_punctuation: TerminalPunctuation::Keep,
+            ..Default::default()
+        });
+        assert_eq!(
+            Some("i love pizza!!!".to_string()),
+            dict.respond_to("love", &mut StepRng::new(0, 0))
+        );
+
+        dict.set_options(Options {
+            terminal_punctuation: TerminalPunctuation::Collapse,
+            ..Default::default()
+        });
+        assert_eq!(
+            Some("i love pizza!".to_string()),
+            dict.respond_to("love", &mut StepRng::new(0, 0))
+        );
+        assert_eq!(
+            Some("i love pasta.".to_string()),
+            dict.respond_to("love", &mut StepRng::new(1, 0))
+        );
+    }
+
+    #[test]
+    fn test_random_sentence() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        assert_eq!(None, dict.random_sentence(&mut StepRng::new(0, 1)));
+
+        dict.learn("first one. second one. third one.");
+        assert_eq!(
+            Some("first one."),
+            dict.random_sentence(&mut StepRng::new(0, 1))
+        );
+        assert_eq!(
+            Some("third one."),
+            dict.random_sentence(&mut StepRng::new(5, 1))
+        );
+        // The top of the RNG's range is biased and must be redrawn.
+        assert_eq!(
+            Some("second one."),
+            dict.random_sentence(&mut StepRng::new(u64::MAX, 2))
+        );
+        assert_eq!(None, sample_index(0, &mut StepRng::new(0, 1)));
+        assert_eq!(None, pick_random::<&str>(&[], &mut StepRng::new(0, 1)));
+    }
+
+    #[test]
+    fn test_elongation_limit() {
+        use rand::rngs::mock::StepRng;
+
+        assert_eq!("soo good", collapse_elongations("soooo gooood", 2));
+        assert_eq!("so good", collapse_elongations("soooo good", 1));
+        assert_eq!("so good!!!", collapse_elongations("sooo good!!!", 1));
+
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            elongation_limit: Some(1),
+            ..Default::default()
+        });
+        dict.learn("Soooo cool. So what.");
+
+        assert!(dict.knows_word("so"));
+        assert!(!dict.knows_word("soooo"));
+        assert_eq!(vec!["so"], dict.known_words("sooooooo"));
+        // Replies keep the spelling of the sentences they come from.
+        assert_eq!(
+            Some("soooo cool".to_string()),
+            dict.respond_to("so", &mut StepRng::new(0, 0))
+        );
+    }
+
+    #[test]
+    fn test_stale_indices_are_skipped() {
+        let dict = Dictionary {
+            sentences: vec!["pizza is good".to_string()],
+            indices: Indices::from(hashmap![
+                "pizza".to_string() => vec![0, 7],
+                "is".to_string() => vec![0],
+                "good".to_string() => vec![0]
+            ]),
+            ..Default::default()
+        };
+
+        assert_eq!(Some("pizza is good"), dict.get_sentence(0));
+        assert_eq!(None, dict.get_sentence(7));
+        assert_eq!(vec!["pizza is good"], dict.sentences_with_word("pizza"));
+    }
+
+    #[test]
+    fn test_split_round_trip() {
+        let dir = std::env::temp_dir().join(format!("borg-split-{}", std::process::id()));
+        let mut dict = Dictionary::new_empty();
+        dict.learn("Hey there, everyone! How is everyone doing today?");
+
+        dict.write_split(&dir).unwrap();
+        let loaded = Dictionary::load_split(&dir).unwrap();
+        let indices = Dictionary::load_split_indices(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(dict, loaded);
+        assert_eq!(dict.indices, indices);
+        assert_eq!(dict.last_seen("everyone"), loaded.last_seen("everyone"));
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(0, edit_distance("pizza", "pizza"));
+        assert_eq!(1, edit_distance("piza", "pizza"));
+        assert_eq!(2, edit_distance("pizaz", "pizza"));
+        assert_eq!(3, edit_distance("", "abc"));
+    }
+
+    #[test]
+    fn test_unknown_input_fallbacks() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza. Pizza is great.");
+        assert_eq!(None, dict.respond_to("piza", &mut StepRng::new(0, 0)));
+
+        dict.set_options(Options {
+            unknown_input_fallbacks: vec![
+                UnknownInputFallback::Fuzzy,
+                UnknownInputFallback::RandomSentence,
+            ],
+            ..Default::default()
+        });
+        assert_eq!(
+            Some("i love pizza is great".to_string()),
+            dict.respond_to("piza", &mut StepRng::new(0, 0))
+        );
+        // Nothing is close to this, so the random sentence is used.
+        assert_eq!(
+            Some("pizza is great.".to_string()),
+            dict.respond_to("xyzzy", &mut StepRng::new(1, 0))
+        );
+    }
+
+    #[test]
+    fn test_min_pivot_len() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            min_pivot_len: 3,
+            ..Default::default()
+        });
+        let known_words = vec!["i".to_string(), "like".to_string(), "a".to_string()];
+
+        // "i" and "a" are never picked while a longer word is available.
+        for i in 0..3 {
+            assert_eq!(
+                Some("like"),
+                dict.pick_pivot(&known_words, &mut StepRng::new(i, 0))
+            );
+        }
+        // They are still used when nothing else is known.
+        assert_eq!(
+            Some("a"),
+            dict.pick_pivot(&["i".to_string(), "a".to_string()], &mut StepRng::new(1, 0))
+        );
+    }
+
+    #[test]
+    fn test_sentence_meta() {
+        let mut dict = Dictionary {
+            sentences: vec!["an old sentence".to_string()],
+            indices: Indices::from(hashmap![
+                "an".to_string() => vec![0],
+                "old".to_string() => vec![0],
+                "sentence".to_string() => vec![0]
+            ]),
+            ..Default::default()
+        };
+        let t = UNIX_EPOCH + Duration::from_secs(1000);
+        dict.learn_at("A new sentence. A new sentence.", t);
+        dict.learn_at("An old sentence", t);
+
+        assert_eq!(
+            Some(&SentenceMeta {
+                learned_at: 0,
+                count: 2,
+                counted_at: 1000,
+                ..Default::default()
+            }),
+            dict.sentence_meta(0)
+        );
+        assert_eq!(
+            Some(&SentenceMeta {
+                learned_at: 1000,
+                count: 2,
+                counted_at: 1000,
+                ..Default::default()
+            }),
+            dict.sentence_meta(1)
+        );
+        assert_eq!(None, dict.sentence_meta(3));
+
+        // Sorting the sentences keeps their metadata with them.
+        dict.rebuild_indices();
+        assert_eq!(Some("a new sentence."), dict.get_sentence(0));
+        assert_eq!(Some(1000), dict.sentence_meta(0).map(|m| m.learned_at));
+    }
+
+    #[test]
+    fn test_json_old_format_upgrade() {
+        let old = r#"{"sentences":["hi there"],"indices":{"hi":[0],"there":[0]}}"#;
+        let mut dict: Dictionary = serde_json::from_str(old).unwrap();
+        dict.fill_sentence_meta();
+
+        assert_eq!(Some(&SentenceMeta::default()), dict.sentence_meta(0));
+        assert_eq!(None, dict.last_seen("hi"));
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let path = std::env::temp_dir().join(format!("borg-dict-{}.json", std::process::id()));
+        let mut dict = Dictionary::new_empty();
+        dict.learn_at(
+            "Hello there. Hello there.",
+            UNIX_EPOCH + Duration::from_secs(5),
+        );
+
+        dict.write_to_disk(&path).unwrap();
+        let loaded = Dictionary::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(dict, loaded);
+        assert_eq!(dict.sentence_meta, loaded.sentence_meta);
+        assert_eq!(dict.last_seen, loaded.last_seen);
+    }
+
+    #[test]
+    fn test_load_or_seed() {
+        use rand::rngs::mock::StepRng;
+
+        let path = std::env::temp_dir().join(format!("borg-seed-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let dict = Dictionary::load_or_seed(&path, true).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(!dict.sentences.is_empty());
+        assert!(dict.respond_to("hello", &mut StepRng::new(0, 1)).is_some());
+    }
+
+    #[test]
+    fn test_storage() {
+        use crate::storage::Storage;
+
+        #[derive(Default)]
+        struct MemoryStorage {
+            stored: Option<Dictionary>,
+            saves: usize,
+        }
+
+        impl Storage for MemoryStorage {
+            fn load(&mut self) -> Result<Option<Dictionary>, Error> {
+                Ok(self.stored.clone())
+            }
+
+            fn save(&mut self, dictionary: &Dictionary) -> Result<(), Error> {
+                self.stored = Some(dictionary.clone());
+                self.saves += 1;
+                Ok(())
+            }
+        }
+
+        let mut storage = MemoryStorage::default();
+        let mut dict = Dictionary::load_from(&mut storage, false).unwrap();
+        assert_eq!(1, storage.saves);
+        assert!(dict.sentences.is_empty());
+
+        dict.learn_at(
+            "Hello there. Hello there. General Kenobi.",
+            UNIX_EPOCH + Duration::from_secs(5),
+        );
+        dict.save_to(&mut storage).unwrap();
+        let loaded = Dictionary::load_from(&mut storage, false).unwrap();
+        assert_eq!(2, storage.saves);
+        assert_eq!(dict, loaded);
+        assert_eq!(dict.sentence_meta, loaded.sentence_meta);
+        assert_eq!(dict.last_seen, loaded.last_seen);
+        assert!(loaded.contains_sentence("general kenobi."));
+    }
+
+    #[test]
+    fn test_length_target() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("Cats sleep. Big fluffy cats sleep all day long.");
+        assert_eq!(
+            Some("big fluffy cats sleep".to_string()),
+            dict.respond_to("cats", &mut StepRng::new(0, 1))
+        );
+
+        dict.set_options(Options {
+            length_target: Some(LengthTarget {
+                min_words: 5,
+                max_words: 6,
+                candidates: 4,
+            }),
+            ..Default::default()
+        });
+        assert_eq!(
+            Some("cats sleep all day long".to_string()),
+            dict.respond_to("cats", &mut StepRng::new(0, 1))
+        );
+    }
+
+    #[test]
+    fn test_find_dangling_indices() {
+        let dict = Dictionary {
+            sentences: vec!["hello world".to_string(), "pizza is good".to_string()],
+            indices: Indices::from(hashmap![
+                "hello".to_string() => vec![0],
+                "world".to_string() => vec![0, 1],
+                "pizza".to_string() => vec![1, 5],
+                "is".to_string() => vec![1],
+                "good".to_string() => vec![1],
+                "gone".to_string() => vec![0]
+            ]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vec![
+                ("gone".to_string(), 0),
+                ("pizza".to_string(), 5),
+                ("world".to_string(), 1),
+            ],
+            dict.find_dangling_indices()
+        );
+
+        let mut dict = dict;
+        dict.rebuild_indices();
+        assert!(dict.find_dangling_indices().is_empty());
+    }
+
+    #[test]
+    fn test_fold_confusables() {
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            fold_confusables: true,
+            ..Default::default()
+        });
+        dict.learn("I want pizza");
+
+        // "pizzа" below ends with a Cyrillic "а".
+        assert_eq!(vec!["pizza"], dict.known_words("pizzа"));
+        dict.set_options(Options::default());
+        assert!(dict.known_words("pizzа").is_empty());
+    }
+
+    #[test]
+    fn test_json_streaming_write() {
+        // LargestWrite records the size of the largest chunk written to it.
+        #[derive(Default)]
+        struct LargestWrite {
+            total: usize,
+            largest: usize,
+        }
+
+        impl Write for LargestWrite {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.total += buf.len();
+                self.largest = self.largest.max(buf.len());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut dict = Dictionary::new_empty();
+        for i in 0..1000 {
+            dict.learn(&format!("sentence number {} is here.", i));
+        }
+
+        let path = std::env::temp_dir().join(format!("borg-stream-{}.json", std::process::id()));
+        dict.write_to_disk(&path).unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(serde_json::to_string(&dict).unwrap(), written);
+
+        let mut sink = LargestWrite::default();
+        serde_json::to_writer(&mut sink, &dict).unwrap();
+        assert_eq!(written.len(), sink.total);
+        assert!(sink.largest < written.len() / 100);
+    }
+
+    #[test]
+    fn test_topic_filter() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("Cats love fish. Cats hate water. Dogs and cats play.");
+        dict.set_options(Options {
+            topic_filter: vec!["fish".to_string(), "Water".to_string()],
+            ..Default::default()
+        });
+        for i in 0..9 {
+            let reply = dict.respond_to("cats", &mut StepRng::new(i, 1)).unwrap();
+            assert!(!reply.contains("dogs") && !reply.contains("play"));
+        }
+
+        // Nothing is about space, so the filter is ignored unless strict.
+        dict.set_options(Options {
+            topic_filter: vec!["space".to_string()],
+            ..Default::default()
+        });
+        assert!(dict.respond_to("cats", &mut StepRng::new(0, 1)).is_some());
+        dict.set_options(Options {
+            topic_filter: vec!["space".to_string()],
+            strict_topic: true,
+            ..Default::default()
+        });
+        assert_eq!(None, dict.respond_to("cats", &mut StepRng::new(0, 1)));
+    }
+
+    #[test]
+    fn test_viable_pivots() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza. Pizza is great. I love you.");
+
+        assert_eq!(
+            vec!["pizza", "i", "love"],
+            dict.viable_pivots("Pizza? I love, love pizza and great friends")
+        );
+        assert!(dict.viable_pivots("great friends").is_empty());
+    }
+
+    #[test]
+    fn test_stem_words() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            stem_words: true,
+            ..Default::default()
+        });
+        dict.learn("Crabs are great. There are many crabs.");
+
+        assert_eq!(vec!["crab"], dict.known_words("crab"));
+        assert_eq!(
+            Some("crabs are great".to_string()),
+            dict.respond_to("a crab", &mut StepRng::new(0, 0))
+        );
+    }
+
+    #[test]
+    fn test_respond_to_safe() {
+        use rand::rngs::mock::StepRng;
+        use rand_core::Error;
+
+        // An RNG that fails when drawn from, to make building a reply panic.
+        struct FailingRng;
+
+        impl RngCore for FailingRng {
+            fn next_u32(&mut self) -> u32 {
+                panic!("injected failure")
+            }
+
+            fn next_u64(&mut self) -> u64 {
+                panic!("injected failure")
+            }
+
+            fn fill_bytes(&mut self, _: &mut [u8]) {
+                panic!("injected failure")
+            }
+
+            fn try_fill_bytes(&mut self, _: &mut [u8]) -> Result<(), Error> {
+                panic!("injected failure")
+            }
+        }
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza. Pizza is great.");
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            dict.respond_to("pizza", &mut FailingRng)
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(
+            None,
+            dict.respond_to_safe("pizza", &[], None, &mut FailingRng)
+        );
+        assert_eq!(
+            Some(Provenance {
+                reply: "i love pizza is great".to_string(),
+                sources: vec![
+                    (0, "i love pizza.".to_string()),
+                    (1, "pizza is great.".to_string())
+                ],
+            }),
+            dict.respond_to_safe("pizza", &[], None, &mut StepRng::new(0, 0))
+        );
+    }
+
+    #[test]
+    fn test_weighted_random_sentences() {
+        use crate::rng::BorgRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I hate pizza.");
+        for _ in 0..9 {
+            dict.learn("Pizza is great.");
+        }
+        let great = |dict: &Dictionary| {
+            let mut rng = BorgRng::new(3);
+            (0..10_000)
+                .filter(|_| dict.random_sentence(&mut rng) == Some("pizza is great."))
+                .count()
+        };
+        assert!((4_700..5_300).contains(&great(&dict)));
+
+        dict.set_options(Options {
+            weighted_random_sentences: true,
+            ..Default::default()
+        });
+        assert!((8_700..9_300).contains(&great(&dict)));
+
+        // Learning keeps the weights up to date, rebuilding them only when the
+        // next sentence is picked.
+        for _ in 0..10 {
+            dict.learn("I hate pizza.");
+        }
+        assert!(dict.sentence_sampler.borrow().is_none());
+        assert!((4_200..4_800).contains(&great(&dict)));
+        assert!(dict.sentence_sampler.borrow().is_some());
+    }
+
+    #[test]
+    fn test_warm_up() {
+        use rand::rngs::mock::StepRng;
+
+        let text = "I love pizza. Pizza is great. I love you. Hello world.";
+        let mut lazy = Dictionary::new_empty();
+        lazy.learn(text);
+        let mut warm = Dictionary::new_empty();
+        warm.learn(text);
+        warm.warm_up();
+
+        let cache = warm.warm.as_ref().unwrap();
+        let mut pivots: Vec<&str> = cache.pivots.iter().map(|w| w.as_str()).collect();
+        pivots.sort();
+        assert_eq!(vec!["i", "love", "pizza"], pivots);
+        assert_eq!(
+            VocabularyStats {
+                words: 8,
+                sentences: 4,
+                postings: 11,
+            },
+            cache.stats
+        );
+        assert_eq!(lazy.vocabulary_stats(), warm.vocabulary_stats());
+
+        for input in &["pizza", "hello world", "i love great pizza", "nothing"] {
+            for i in 0..8 {
+                assert_eq!(
+                    lazy.respond_to(input, &mut StepRng::new(i, 3)),
+                    warm.respond_to(input, &mut StepRng::new(i, 3))
+                );
+            }
+            assert_eq!(lazy.viable_pivots(input), warm.viable_pivots(input));
+        }
+
+        // Learning drops the precomputed structures.
+        warm.learn("Hello again.");
+        assert!(warm.warm.is_none());
+    }
+
+    #[test]
+    fn test_doc_frequency() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn("The cat sat. The dog ran to the cat. A bird sang. The end.");
+        assert_eq!(3, dict.doc_frequency("the"));
+        assert_eq!(2, dict.doc_frequency("Cat"));
+        assert_eq!(1, dict.doc_frequency("bird"));
+        assert_eq!(0, dict.doc_frequency("fish"));
+
+        let stopwords =
+            |words: &[&str]| -> HashSet<String> { words.iter().map(|w| w.to_string()).collect() };
+        assert_eq!(stopwords(&["the", "cat"]), dict.auto_stopwords(20.0));
+        assert_eq!(HashSet::new(), dict.auto_stopwords(0.0));
+        assert_eq!(10, dict.auto_stopwords(100.0).len());
+
+        dict.warm_up();
+        assert_eq!(3, dict.doc_frequency("the"));
+        assert_eq!(0, dict.doc_frequency("fish"));
+        assert_eq!(stopwords(&["the", "cat"]), dict.auto_stopwords(20.0));
+
+        // Learning drops the cached frequencies.
+        dict.learn("The fish swam. A fish. The fish again.");
+        assert_eq!(3, dict.doc_frequency("fish"));
+        assert_eq!(stopwords(&["the", "fish"]), dict.auto_stopwords(15.0));
+    }
+
+    #[test]
+    fn test_unreachable_sentences() {
+        let mut dict = Dictionary::new_empty();
+        assert!(dict.unreachable_sentences().is_empty());
+        dict.learn("I love pizza. Pizza is great. Hello world! Nice weather today. You love it.");
+        assert_eq!(
+            vec!["hello world!", "nice weather today."],
+            dict.unreachable_sentences()
+        );
+
+        dict.learn("What a world.");
+        assert_eq!(vec!["nice weather today."], dict.unreachable_sentences());
+    }
+
+    #[test]
+    fn test_learn_from_file() {
+        let text = "I love pizza.\nPizza is great.\nYou love pasta. I love pizza.\n";
+        let gzipped = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xf3, 0x54, 0xc8, 0xc9,
+            0x2f, 0x4b, 0x55, 0x28, 0xc8, 0xac, 0xaa, 0x4a, 0xd4, 0xe3, 0x0a, 0x00, 0x51, 0x0a,
+            0x99, 0xc5, 0x0a, 0xe9, 0x45, 0xa9, 0x89, 0x25, 0x7a, 0x5c, 0x91, 0xf9, 0xa5, 0x50,
+            0xf9, 0xc4, 0xe2, 0x92, 0x44, 0x3d, 0x05, 0x4f, 0x14, 0xd5, 0x00, 0x23, 0xdc, 0x87,
+            0xf9, 0x3c, 0x00, 0x00, 0x00,
+        ];
+        let dir = std::env::temp_dir();
+        let plain = dir.join(format!("borg-corpus-{}.txt", std::process::id()));
+        let compressed = dir.join(format!("borg-corpus-{}.txt.gz", std::process::id()));
+        let unmarked = dir.join(format!("borg-corpus-{}.log", std::process::id()));
+        fs::write(&plain, text).unwrap();
+        fs::write(&compressed, &gzipped[..]).unwrap();
+        fs::write(&unmarked, &gzipped[..]).unwrap();
+
+        let mut from_plain = Dictionary::new_empty();
+        assert_eq!(3, from_plain.learn_from_file(&plain).unwrap());
+        let mut from_compressed = Dictionary::new_empty();
+        assert_eq!(3, from_compressed.learn_from_file(&compressed).unwrap());
+        assert_eq!(from_plain, from_compressed);
+        let mut from_unmarked = Dictionary::new_empty();
+        assert_eq!(3, from_unmarked.learn_from_file(&unmarked).unwrap());
+        assert_eq!(from_plain, from_unmarked);
+        assert!(from_compressed.contains_sentence("you love pasta."));
+
+        fs::write(&compressed, &gzipped[..20]).unwrap();
+        assert!(Dictionary::new_empty()
+            .learn_from_file(&compressed)
+            .is_err());
+        for path in &[plain, compressed, unmarked] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_prefix_matching() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza. Pizza is great. Pizzas are round. Zebras run.");
+        assert_eq!(None, dict.respond_to("pizz", &mut StepRng::new(0, 0)));
+
+        dict.set_options(Options {
+            prefix_matching: true,
+            ..Default::default()
+        });
+        assert_eq!(vec!["pizza", "pizzas"], dict.known_words("pizz"));
+        assert_eq!(
+            Some("i love pizza is great".to_string()),
+            dict.respond_to("pizz", &mut StepRng::new(0, 0))
+        );
+        // Known words are matched as they are, and short words not at all.
+        assert_eq!(vec!["pizza"], dict.known_words("pizza"));
+        assert!(dict.known_words("pi").is_empty());
+        assert!(dict.known_words("pizzeria").is_empty());
+
+        dict.warm_up();
+        assert_eq!(vec!["pizza", "pizzas"], dict.known_words("PIZZ"));
+        assert_eq!(vec!["zebras"], dict.known_words("zeb"));
+    }
+
+    #[test]
+    fn test_stable_json_serialization() {
+        let text = "I love pizza. Pizza is great. You love pasta. Zebras eat grass.";
+        let now = UNIX_EPOCH + Duration::from_secs(10);
+        let mut a = Dictionary::new_empty();
+        a.learn_at(text, now);
+        let mut b = Dictionary::new_empty();
+        for sentence in split_sentences(text) {
+            b.learn_at(sentence, now);
+        }
+        let written = serde_json::to_string(&a).unwrap();
+        assert_eq!(written, serde_json::to_string(&b).unwrap());
+
+        let loaded: Dictionary = serde_json::from_str(&written).unwrap();
+        assert_eq!(written, serde_json::to_string(&loaded).unwrap());
+        assert!(written.find("\"grass\"").unwrap() < written.find("\"zebras\"").unwrap());
+    }
+
+    #[test]
+    fn test_word_path() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn("Cats chase mice. Mice eat cheese. Cheese comes from milk. Dogs bark.");
+        assert_eq!(
+            Some(vec![
+                "cats".to_string(),
+                "mice".to_string(),
+                "cheese".to_string(),
+                "milk".to_string()
+            ]),
+            dict.word_path("Cats", "milk")
+        );
+        assert_eq!(
+            Some(vec!["eat".to_string(), "cheese".to_string()]),
+            dict.word_path("eat", "cheese")
+        );
+        assert_eq!(
+            Some(vec!["mice".to_string()]),
+            dict.word_path("mice", "mice")
+        );
+        assert_eq!(None, dict.word_path("cats", "dogs"));
+        assert_eq!(None, dict.word_path("cats", "unicorns"));
+
+        // Stale indices pointing past the sentences are skipped.
+        let stale = Dictionary {
+            sentences: vec!["cats chase mice".to_string(), "dogs bark".to_string()],
+            indices: Indices::from(hashmap![
+                "cats".to_string() => vec![0, 9],
+                "chase".to_string() => vec![0],
+                "mice".to_string() => vec![0],
+                "dogs".to_string() => vec![1],
+                "bark".to_string() => vec![1]
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(
+            Some(vec!["cats".to_string(), "mice".to_string()]),
+            stale.word_path("cats", "mice")
+        );
+        assert_eq!(None, stale.word_path("cats", "dogs"));
+
+        // Words too far apart aren't found.
+        let mut dict = Dictionary::new_empty();
+        for i in 0..=MAX_WORD_PATH_HOPS {
+            dict.learn(&format!("w{} w{}.", i, i + 1));
+        }
+        let far = format!("w{}", MAX_WORD_PATH_HOPS + 1);
+        assert_eq!(
+            MAX_WORD_PATH_HOPS + 2,
+            dict.word_path("w0", &far).unwrap().len()
+        );
+        dict.learn(&format!("{} w{}.", far, MAX_WORD_PATH_HOPS + 2));
+        let too_far = format!("w{}", MAX_WORD_PATH_HOPS + 2);
+        assert_eq!(None, dict.word_path("w0", &too_far));
+    }
+
+    #[test]
+    fn test_skip_questions() {
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            skip_questions: true,
+            ..Default::default()
+        });
+        assert!(!dict.learn("What is your name?"));
+        assert!(!dict.learn("Seriously?!"));
+        assert!(dict.learn("Is it you? My name is borg."));
+
+        assert_eq!(vec!["my name is borg."], dict.sentences);
+        assert!(dict.last_seen("you").is_none());
+
+        dict.set_options(Options::default());
+        assert!(dict.learn("What is your name?"));
+    }
+
+    #[test]
+    fn test_bridge_pivots() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza. Pizza is hot. Cats are great. Dogs chase cats.");
+        dict.set_options(Options {
+            bridge_pivots: true,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            Some("i love pizza cats are great".to_string()),
+            dict.respond_to("pizza and cats", &mut StepRng::new(0, 0))
+        );
+        // The pivots keep the order of the input.
+        assert_eq!(
+            Some("dogs chase cats pizza is hot".to_string()),
+            dict.respond_to("cats and pizza", &mut StepRng::new(1, 0))
+        );
+        // With a single known word, the usual splice is used.
+        assert_eq!(
+            Some("i love pizza is hot".to_string()),
+            dict.respond_to("pizza", &mut StepRng::new(0, 0))
+        );
+
+        // Under a strict topic, a pivot may have no sentences to bridge from.
+        dict.set_options(Options {
+            bridge_pivots: true,
+            topic_filter: vec!["hot".to_string()],
+            strict_topic: true,
+            ..Default::default()
+        });
+        let known_words = vec!["pizza".to_string(), "cats".to_string()];
+        for i in 0..4 {
+            assert!(dict
+                .bridge(&known_words, None, &mut StepRng::new(i, 1))
+                .is_none());
+            dict.respond_to("pizza and cats", &mut StepRng::new(i, 1));
+        }
+    }
+
+    #[test]
+    fn test_respond_with_confidence() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn(
+            "The cat sat on the mat. The cat likes fish. The dog sat too. \
+            The dog barks. Cats and dogs. The end.",
+        );
+
+        let (reply, confidence) = dict
+            .respond_with_confidence("the cat sat", &mut StepRng::new(0, 1))
+            .unwrap();
+        assert_eq!(dict.confidence("the cat sat", &reply), confidence);
+
+        // Sharing rarer words, and more of them, with the input scores higher.
+        let rich = dict.confidence("the cat sat", "the cat sat on the mat");
+        let sparse = dict.confidence("the cat sat", "the end");
+        assert!(rich > sparse);
+        assert!(sparse > 0.0 && rich <= 1.0);
+        assert_eq!(0.0, dict.confidence("the cat sat", "dogs bark"));
+    }
+
+    #[test]
+    fn test_expire_older_than() {
+        let day = Duration::from_secs(86400);
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut dict = Dictionary {
+            sentences: vec!["an ancient cat".to_string()],
+            indices: Indices::from(hashmap![
+                "an".to_string() => vec![0],
+                "ancient".to_string() => vec![0],
+                "cat".to_string() => vec![0]
+            ]),
+            ..Default::default()
+        };
+        dict.learn_at("The old cat.", t0);
+        dict.learn_at("The new cat.", t0 + day * 10);
+
+        assert_eq!(0, dict.expire_older_than(day * 30, t0 + day * 20));
+        assert_eq!(1, dict.expire_older_than(day * 5, t0 + day * 12));
+
+        assert_eq!(vec!["an ancient cat", "the new cat."], dict.sentences);
+        assert!(!dict.knows_word("old"));
+        assert_eq!(
+            vec!["an ancient cat", "the new cat."],
+            dict.sentences_with_word("cat")
+        );
+        assert!(dict.find_dangling_indices().is_empty());
+    }
+
+    #[test]
+    fn test_prefer_complete_endings() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza. Pizza is great.");
+        dict.learn("I want pizza with");
+        let fragments = |dict: &Dictionary| {
+            sample_replies(dict, "pizza", 20)
+                .iter()
+                .filter(|reply| reply.ends_with("pizza with"))
+                .count()
+        };
+        assert!(fragments(&dict) > 0);
+
+        dict.set_options(Options {
+            prefer_complete_endings: true,
+            ..Default::default()
+        });
+        assert_eq!(0, fragments(&dict));
+    }
+
+    #[test]
+    fn test_prefer_content_openings() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("And pizza is great. Of pizza we dream. Cold pizza rules.");
+        let openings = |dict: &Dictionary| {
+            (0..6)
+                .filter_map(|i| dict.respond_to("pizza", &mut StepRng::new(i, 1)))
+                .map(|reply| reply.split(' ').next().unwrap().to_string())
+                .collect::<HashSet<String>>()
+        };
+        assert!(openings(&dict).len() > 1);
+
+        dict.set_options(Options {
+            prefer_content_openings: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            vec!["cold".to_string()],
+            openings(&dict).into_iter().collect::<Vec<_>>()
+        );
+
+        dict.set_options(Options {
+            prefer_content_openings: true,
+            function_words: vec!["Cold".to_string(), "of".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(
+            vec!["and".to_string()],
+            openings(&dict).into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_prefer_lead_in() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("Pizza is great. Pizza is hot. I love pizza.");
+        assert_eq!(
+            Some("pizza is great".to_string()),
+            dict.respond_to("pizza", &mut StepRng::new(0, 0))
+        );
+
+        dict.set_options(Options {
+            prefer_lead_in: true,
+            ..Default::default()
+        });
+        for i in 0..6 {
+            let reply = dict.respond_to("pizza", &mut StepRng::new(i, 1)).unwrap();
+            assert!(reply.starts_with("i love pizza"), "{:?}", reply);
+        }
+
+        // Without any lead-in to use, the pivot opens the reply.
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            prefer_lead_in: true,
+            ..Default::default()
+        });
+        dict.learn("Pizza is great. Pizza is hot.");
+        assert_eq!(
+            Some("pizza is great".to_string()),
+            dict.respond_to("pizza", &mut StepRng::new(0, 0))
+        );
+    }
+
+    #[test]
+    fn test_reply_space() {
+        let mut dict = Dictionary::new_empty();
+        assert_eq!(0, dict.reply_space());
+
+        dict.learn("I love pizza. Pizza is great. Hello world.");
+        assert_eq!(1, dict.reply_space());
+        dict.warm_up();
+        assert_eq!(1, dict.reply_space());
+
+        dict.learn("I love you. Hello there.");
+        assert_eq!(4, dict.reply_space());
+    }
+
+    #[test]
+    fn test_min_learn_chars() {
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            min_learn_chars: 5,
+            elongation_limit: Some(1),
+            ..Default::default()
+        });
+        assert!(!dict.learn("ok!!!!!!!!!!"));
+        assert!(!dict.learn("Okkkkkk."));
+        assert!(!dict.learn("a b"));
+        assert!(dict.learn("ok. ok cool. a b c"));
+
+        assert_eq!(vec!["ok cool.", "a b c"], dict.sentences);
+    }
+
+    #[test]
+    fn test_max_word_repeats() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("Go go go team. Go home.");
+        assert_eq!(
+            Some("go go go team".to_string()),
+            dict.respond_to("go", &mut StepRng::new(0, 0))
+        );
+
+        dict.set_options(Options {
+            max_word_repeats: Some(2),
+            ..Default::default()
+        });
+        for i in 0..8 {
+            if let Some(reply) = dict.respond_to("go", &mut StepRng::new(i, 1)) {
+                assert!(dict.most_repeats(&reply) <= 2, "{:?}", reply);
+            }
+        }
+        assert_eq!(
+            Some("go home".to_string()),
+            dict.respond_to("go", &mut StepRng::new(0, 1))
+        );
+        // A reply that can only ever repeat too much is not said.
+        assert_eq!(None, dict.respond_to("go", &mut StepRng::new(0, 0)));
+    }
+
+    #[test]
+    fn test_line_breaks() {
+        let message = "first line\nsecond line\n\nnew paragraph.  \r\nlast line";
+        assert_eq!(vec![message], split_lines(message, LineBreaks::Ignore));
+        assert_eq!(
+            vec!["first line", "second line", "new paragraph.", "last line"],
+            split_lines(message, LineBreaks::Line)
+        );
+        assert_eq!(
+            vec!["first line\nsecond line", "new paragraph.  \r\nlast line"],
+            split_lines(message, LineBreaks::Paragraph)
+        );
+
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            line_breaks: LineBreaks::Line,
+            ..Default::default()
+        });
+        dict.learn("Hello there\nGeneral Kenobi");
+        assert_eq!(vec!["hello there", "general kenobi"], dict.sentences);
+    }
+
+    #[test]
+    fn test_checksum() {
+        let mut fnv = Fnv1a::new();
+        fnv.write(b"a");
+        assert_eq!(0xaf63_dc4c_8601_ec8c, fnv.finish());
+
+        let mut a = Dictionary::new_empty();
+        let mut b = Dictionary::new_empty();
+        assert_eq!(a.checksum(), b.checksum());
+
+        a.learn("I love pizza. Pizza is great.");
+        b.learn("I love pizza. Pizza is great.");
+        assert_eq!(a.checksum(), b.checksum());
+
+        let before = a.checksum();
+        a.learn("Hello world.");
+        assert_ne!(before, a.checksum());
+        // Learning a known sentence changes nothing that is checked.
+        let before = a.checksum();
+        a.learn("Hello world.");
+        assert_eq!(before, a.checksum());
+    }
+
+    #[test]
+    fn test_min_overlap_ratio() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza. Pizza is great.");
+        dict.set_options(Options {
+            min_overlap_ratio: 0.5,
+            ..Default::default()
+        });
+
+        // One known word out of three is below the ratio, two of four isn't.
+        assert_eq!(
+            None,
+            dict.respond_to("pizza for dinner", &mut StepRng::new(0, 0))
+        );
+        assert!(dict
+            .respond_to("pizza is for dinner", &mut StepRng::new(0, 0))
+            .is_some());
+        assert!(dict.respond_to("pizza", &mut StepRng::new(0, 0)).is_some());
+    }
+
+    #[test]
+    fn test_export_transitions() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza. I love you. I hate pizza.");
+
+        let edge = |prefix: &[&str], next: &str, count: usize| {
+            (
+                prefix.iter().map(|w| w.to_string()).collect::<Vec<_>>(),
+                next.to_string(),
+                count,
+            )
+        };
+        assert_eq!(
+            vec![
+                edge(&["hate"], "pizza", 1),
+                edge(&["i"], "hate", 1),
+                edge(&["i"], "love", 2),
+                edge(&["love"], "pizza", 1),
+                edge(&["love"], "you", 1),
+            ],
+            dict.export_transitions(1)
+        );
+        assert_eq!(
+            vec![
+                edge(&["i", "hate"], "pizza", 1),
+                edge(&["i", "love"], "pizza", 1),
+                edge(&["i", "love"], "you", 1),
+            ],
+            dict.export_transitions(2)
+        );
+        assert!(dict.export_transitions(3).is_empty());
+    }
+
+    #[test]
+    fn test_code_handling() {
+        assert_eq!(None, find_code("no code here, just a ` tick"));
+        assert_eq!(Some((4, 11)), find_code("try `x + 1` ok"));
+        assert_eq!(
+            "run  then \n and",
+            strip_code("run `cargo test` then ```\nfn main() {}\n```\n and")
+        );
+        assert_eq!("a `` b", strip_code("a `` b"));
+        assert_eq!("x y", strip_code("x`code`y"));
+        assert_eq!("", strip_code("`code`"));
+
+        let message = "Use `git push --force`. It works.";
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            code: CodeHandling::Skip,
+            ..Default::default()
+        });
+        assert!(!dict.learn(message));
+        assert!(dict.learn("No code. It works."));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            code: CodeHandling::Strip,
+            ..Default::default()
+        });
+        assert!(dict.learn(message));
+        assert_eq!(vec!["use .", "it works."], dict.sentences);
+        assert!(!dict.knows_word("push"));
+    }
 
     #[test]
-    fn test_split_sentences() {
+    fn test_respond_with_pivot() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza. Pizza is great. Hello world.");
+
         assert_eq!(
-            vec![
-                "Hi.",
-                "This sentence is going to be split.",
-                "We.cant.split.things.that.look.like.urls.",
-                "That's a single sentence.",
-                "Lol!",
-                "A single sentence!!!!",
-                "Look at this image: https://imgur.com/gallery/PXSNky0"
-            ],
-            split_sentences(
-                "Hi. This sentence is going to be split. \
-                We.cant.split.things.that.look.like.urls. That's a single sentence. \
-                Lol! A single sentence!!!! Look at this image: https://imgur.com/gallery/PXSNky0"
-            ),
+            Some("i love pizza is great".to_string()),
+            dict.respond_with_pivot("Pizza", &mut StepRng::new(1, 1))
+        );
+        assert_eq!(
+            dict.respond_with_pivot("pizza", &mut StepRng::new(3, 5)),
+            dict.respond_with_pivot("pizza", &mut StepRng::new(3, 5))
+        );
+        assert_eq!(
+            None,
+            dict.respond_with_pivot("hello", &mut StepRng::new(0, 1))
+        );
+        assert_eq!(
+            None,
+            dict.respond_with_pivot("nope", &mut StepRng::new(0, 1))
         );
     }
 
-    // This tests that the Dictionary::rebuild_indices function is building indices correctly.
     #[test]
-    fn test_dictionary_rebuild_indices() {
-        let mut d = Dictionary {
-            sentences: vec![
-                "this is a test.".to_string(),
-                "this is is not a trick!".to_string(), // The double "is" is intentional
-                "hello world!".to_string(),
-            ],
-            indices: hashmap![],
+    fn test_auto_save_guard() {
+        let path = std::env::temp_dir().join(format!("borg-guard-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("Hello world.");
+        assert!(dict.save_if_dirty(&path).unwrap());
+        assert!(!dict.save_if_dirty(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        // Nothing changed, so nothing is written.
+        drop(AutoSaveGuard::new(dict, &path));
+        assert!(!path.is_file());
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("Hello world.");
+        dict.save_if_dirty(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut guard = AutoSaveGuard::new(dict, &path);
+        guard.learn("Goodbye world.");
+        drop(guard);
+        assert!(path.is_file());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_word_weights() {
+        use crate::rng::BorgRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            word_weights: hashmap!["Pizza".to_string() => 0.25],
+            ..Default::default()
+        });
+        let known_words = vec!["pizza".to_string(), "pasta".to_string()];
+
+        let mut rng = BorgRng::new(7);
+        let pizzas = (0..1000)
+            .filter(|_| dict.pick_pivot(&known_words, &mut rng) == Some("pizza"))
+            .count();
+        // Pizza is expected to be picked a fifth of the time.
+        assert!(pizzas > 150 && pizzas < 250, "{}", pizzas);
+
+        dict.set_options(Options {
+            word_weights: hashmap!["pizza".to_string() => 0.0],
+            ..Default::default()
+        });
+        assert!((0..100).all(|_| dict.pick_pivot(&known_words, &mut rng) == Some("pasta")));
+        assert_eq!(None, pick_weighted(&[0.0, 0.0], &mut rng));
+    }
+
+    #[test]
+    fn test_preview_learn() {
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            skip_questions: true,
+            ..Default::default()
+        });
+        dict.learn("I love pizza.");
+
+        let line = "I love pizza. Do you? Pasta is fine. Pasta is fine.";
+        let preview = dict.preview_learn(line);
+        assert_eq!(vec!["pasta is fine."], preview);
+        assert_eq!(1, dict.sentences.len());
+
+        dict.learn(line);
+        assert_eq!(preview, dict.sentences[1..].to_vec());
+    }
+
+    #[test]
+    fn test_follow_up() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza. I love pasta. Pasta is great.");
+        dict.set_options(Options {
+            follow_up: Some(FollowUp {
+                chance: 1.0,
+                max_words: 10,
+            }),
+            ..Default::default()
+        });
+        assert_eq!(
+            Some("i love pasta. pasta is great".to_string()),
+            dict.respond_to("pizza love", &mut StepRng::new(1, 0))
+        );
+
+        // There is no room for a follow-up.
+        dict.set_options(Options {
+            follow_up: Some(FollowUp {
+                chance: 1.0,
+                max_words: 4,
+            }),
+            ..Default::default()
+        });
+        assert_eq!(
+            Some("i love pasta".to_string()),
+            dict.respond_to("pizza love", &mut StepRng::new(1, 0))
+        );
+        dict.set_options(Options {
+            follow_up: Some(FollowUp {
+                chance: 0.0,
+                max_words: 10,
+            }),
+            ..Default::default()
+        });
+        assert_eq!(
+            Some("i love pasta".to_string()),
+            dict.respond_to("pizza love", &mut StepRng::new(1, 0))
+        );
+    }
+
+    #[test]
+    fn test_emoji_tokens() {
+        let family = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}";
+        let thumbs_up = "\u{1f44d}\u{1f3fd}";
+        let flag = "\u{1f1e7}\u{1f1f7}";
+        assert_eq!(vec![family], split_emoji(family));
+        assert_eq!(
+            vec!["hi", family, thumbs_up, "there"],
+            split_emoji(&format!("hi{}{}there", family, thumbs_up))
+        );
+        assert_eq!(vec![flag, flag], split_emoji(&format!("{}{}", flag, flag)));
+        assert_eq!(vec!["plain"], split_emoji("plain"));
+
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            emoji_tokens: true,
+            ..Default::default()
+        });
+        dict.learn(&format!("My family{} is here.", family));
+        assert!(dict.knows_word(family));
+        assert!(dict.knows_word("family"));
+        assert!(!dict.knows_word("\u{1f468}"));
+        assert_eq!(vec![family.to_string()], dict.known_words(family));
+    }
+
+    #[test]
+    fn test_punctuation_tokens() {
+        use rand::rngs::mock::StepRng;
+
+        let options = Options {
+            punctuation_tokens: true,
+            ..Default::default()
         };
-        d.rebuild_indices();
+        assert_eq!(
+            vec!["wow", "!!!", "so", "cool", ",", "right", "?!"],
+            split_tokens("wow!!! so cool, right?!", &options)
+        );
+        assert_eq!(
+            vec!["wow", "so", "cool", "right"],
+            split_tokens("wow!!! so cool, right?!", &Options::default())
+        );
 
-        // Ensure that sentences were sorted after rebuilding incides.
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(options);
+        dict.learn("I love pizza!!! Pizza is great.");
+        assert!(dict.knows_word("!!!"));
         assert_eq!(
-            vec![
-                "hello world!".to_string(),
-                "this is a test.".to_string(),
-                "this is is not a trick!".to_string(),
+            Some("i love pizza!!!".to_string()),
+            dict.respond_to("pizza", &mut StepRng::new(0, 0))
+        );
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza!!! Pizza is great.");
+        assert!(!dict.knows_word("!!!"));
+    }
+
+    #[test]
+    fn test_fallback_chain() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            fallback_chain: vec![
+                ReplyStrategy::Splice,
+                ReplyStrategy::Fuzzy,
+                ReplyStrategy::RandomSentence,
+                ReplyStrategy::Static("I have nothing to say.".to_string()),
             ],
-            d.sentences
+            ..Default::default()
+        });
+        assert_eq!(
+            Some("I have nothing to say.".to_string()),
+            dict.respond_to("pizza", &mut StepRng::new(0, 0))
         );
 
-        // Ensure that the indices were correctly built
+        dict.learn("I love pizza.");
+        // There is only one sentence to splice from, and nothing is close to
+        // "xyzzy".
         assert_eq!(
-            hashmap![
-                "this".to_string() => vec![1, 2],
-                "is".to_string() => vec![1, 2],
-                "a".to_string() => vec![1, 2],
-                "test".to_string() => vec![1],
-                "not".to_string() => vec![2],
-                "trick".to_string() => vec![2],
-                "hello".to_string() => vec![0],
-                "world".to_string() => vec![0]
-            ],
-            d.indices
+            Some("i love pizza.".to_string()),
+            dict.respond_to("xyzzy", &mut StepRng::new(0, 0))
+        );
+
+        dict.learn("Pizza is great.");
+        assert_eq!(
+            Some("i love pizza is great".to_string()),
+            dict.respond_to("piza", &mut StepRng::new(0, 0))
         );
     }
 
     #[test]
-    fn test_split_words() {
+    fn test_interleave() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            fallback_chain: vec![ReplyStrategy::Interleave],
+            ..Default::default()
+        });
+        dict.learn("One two pizza three four five. Six seven pizza eight.");
         assert_eq!(
-            vec!["Hello", "world", "This", "is", "a", "test", "I", "am", "a", "test"],
-            split_words("...Hello world!!!!This is a test? I.am.a.test.")
+            Some("six one seven two pizza three eight four five".to_string()),
+            dict.respond_to("pizza", &mut StepRng::new(0, 0))
+        );
+        // There is no second sentence with the word.
+        assert_eq!(None, dict.respond_to("three", &mut StepRng::new(0, 0)));
+
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            fallback_chain: vec![ReplyStrategy::Interleave],
+            ..Default::default()
+        });
+        let long_left = (0..20).map(|i| format!("a{}", i)).collect::<Vec<_>>();
+        let long_right = (0..20).map(|i| format!("b{}", i)).collect::<Vec<_>>();
+        dict.learn(&format!(
+            "{} pizza {}. Pizza rocks.",
+            long_left.join(" "),
+            long_right.join(" ")
+        ));
+        let reply = dict.respond_to("pizza", &mut StepRng::new(0, 0)).unwrap();
+        assert_eq!(MAX_INTERLEAVE_WORDS, reply.split(' ').count());
+        assert!(reply.starts_with("a13 a14 a15 a16 a17 a18 a19 pizza b0 rocks b1"));
+
+        // Every sentence with the word is the same.
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            fallback_chain: vec![ReplyStrategy::Interleave],
+            ..Default::default()
+        });
+        dict.learn("I love pizza.");
+        dict.sentences.push("i love pizza.".to_string());
+        dict.rebuild_indices();
+        assert_eq!(
+            Some("i i love love pizza".to_string()),
+            dict.respond_to("pizza", &mut StepRng::new(0, 0))
         );
     }
 
     #[test]
-    fn test_needs_to_build_indices() {
-        // Indices should have to be rebuilt when the bot has sentences,
-        // but no indices. On other conditions, it assumes that the indices
-        // are correct and that they do not need to be rebuilt.
+    fn test_source_use_penalty() {
+        use rand::rngs::mock::StepRng;
 
-        assert!(Dictionary {
-            sentences: vec!["hello world".to_string()],
-            indices: hashmap![],
-        }
-        .needs_to_build_indices());
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza a lot. You hate pizza today. We eat pizza daily.");
+        let reply = |dict: &Dictionary| {
+            dict.respond_to_safe("pizza", &[], None, &mut StepRng::new(0, 0))
+                .unwrap()
+        };
+        let first = reply(&dict);
+        dict.note_reply(&first);
+        // Without a penalty, nothing is remembered and the same reply comes
+        // back.
+        assert_eq!(first, reply(&dict));
 
-        assert!(!Dictionary {
-            sentences: vec!["hello world".to_string()],
-            indices: hashmap![
-                "hello".to_string() => vec![0],
-                "world".to_string() => vec![0]
-            ],
-        }
-        .needs_to_build_indices());
+        dict.set_options(Options {
+            source_use_window: 2,
+            source_use_penalty: 1.0,
+            ..Default::default()
+        });
+        dict.note_reply(&first);
+        assert!(!first.sources.is_empty());
+        let second = reply(&dict);
+        assert!(!second.sources.is_empty());
+        assert!(second
+            .sources
+            .iter()
+            .all(|source| !first.sources.contains(source)));
 
-        assert!(!Dictionary {
-            sentences: vec![],
-            indices: hashmap![],
-        }
-        .needs_to_build_indices());
+        // Replies out of the window are forgotten.
+        dict.note_reply(&second);
+        dict.note_reply(&Provenance {
+            reply: "we eat pizza daily".to_string(),
+            sources: vec![(2, "we eat pizza daily.".to_string())],
+        });
+        let second_sources: Vec<String> = second.sources.into_iter().map(|(_, s)| s).collect();
+        assert_eq!(
+            vec![second_sources, vec!["we eat pizza daily.".to_string()]],
+            Vec::from(dict.source_uses)
+        );
     }
 
     #[test]
-    fn test_knows_sentence() {
-        let d = Dictionary {
+    fn test_vocab_overlap() {
+        let mut a = Dictionary::new_empty();
+        let mut b = Dictionary::new_empty();
+        assert_eq!(0.0, a.vocab_overlap(&b));
+
+        a.learn("I love pizza.");
+        b.learn("I love pasta. Pasta is great.");
+        // Shared: i, love. All: i, love, pizza, pasta, is, great.
+        assert!((a.vocab_overlap(&b) - 2.0 / 6.0).abs() < 1e-9);
+        assert_eq!(a.vocab_overlap(&b), b.vocab_overlap(&a));
+        assert_eq!(1.0, a.vocab_overlap(&a));
+    }
+
+    #[test]
+    fn test_reply_diversity() {
+        use crate::rng::BorgRng;
+
+        let mut trivial = Dictionary::new_empty();
+        assert_eq!(0.0, trivial.reply_diversity(10, &mut BorgRng::new(0)));
+        trivial.learn("Hello world. Hello world!");
+
+        let mut rich = Dictionary::new_empty();
+        rich.learn(
+            "I love pizza with cheese. Pizza is great for dinner. Cats love fish. \
+             I love my cats. Dinner is at eight. Cheese is great with wine. \
+             Fish swim in the sea. The sea is great.",
+        );
+
+        let trivial_diversity = trivial.reply_diversity(50, &mut BorgRng::new(1));
+        let rich_diversity = rich.reply_diversity(50, &mut BorgRng::new(1));
+        assert!(trivial_diversity > 0.0);
+        assert!(
+            rich_diversity > trivial_diversity,
+            "{} <= {}",
+            rich_diversity,
+            trivial_diversity
+        );
+    }
+
+    #[test]
+    fn test_dedup() {
+        let mut dict = Dictionary {
             sentences: vec![
-                "hello world".to_string(),
-                "i am a little teapot.".to_string(),
-                "my name is foo...".to_string(),
-                "short and stout".to_string(),
+                "i love pizza.".to_string(),
+                "pizza is great.".to_string(),
+                "i love pizza.".to_string(),
+                "i love pizza.".to_string(),
             ],
-            indices: hashmap![
-                "hello".to_string() => vec![0],
-                "world".to_string() => vec![0],
-                "i".to_string() => vec![1],
-                "am".to_string() => vec![1],
-                "a".to_string() => vec![1],
-                "little".to_string() => vec![1],
-                "teapot".to_string() => vec![1],
-                "my".to_string() => vec![2],
-                "name".to_string() => vec![2],
-                "is".to_string() => vec![2],
-                "foo".to_string() => vec![2],
-                "short".to_string() => vec![3],
-                "and".to_string() => vec![3],
-                "stout".to_string() => vec![3]
+            sentence_meta: vec![
+                SentenceMeta {
+                    learned_at: 20,
+                    count: 1,
+                    ..Default::default()
+                },
+                SentenceMeta::default(),
+                SentenceMeta {
+                    learned_at: 10,
+                    count: 2,
+                    ..Default::default()
+                },
             ],
+            ..Default::default()
         };
-        assert!(d.knows_sentence(&"my name is foo...".to_string()));
-        assert!(d.knows_sentence(&"i am a little teapot.".to_string()));
-        assert!(d.knows_sentence(&"short and stout".to_string()));
-        assert!(!d.knows_sentence(&"i shouldn't know this".to_string()));
-        assert!(!d.knows_sentence(&"".to_string()));
-        assert!(!d.knows_sentence(&"0".to_string()));
-        assert!(!d.knows_sentence(&"a".to_string()));
+        dict.rebuild_indices();
+
+        assert_eq!(2, dict.dedup());
+        assert_eq!(2, dict.sentences.len());
+        let i = dict.sentence_position("i love pizza.").unwrap();
+        assert_eq!(
+            Some(&SentenceMeta {
+                learned_at: 10,
+                count: 4,
+                ..Default::default()
+            }),
+            dict.sentence_meta(i)
+        );
+        assert_eq!(2, dict.sentences_with_word("pizza").len());
+        assert!(dict.find_dangling_indices().is_empty());
+
+        assert_eq!(0, dict.dedup());
     }
 
     #[test]
-    fn test_knows_word() {
-        let d = Dictionary {
+    fn test_sentence_recency_bias() {
+        use crate::rng::BorgRng;
+
+        let t0 = UNIX_EPOCH + Duration::from_secs(1000);
+        let mut dict = Dictionary::new_empty();
+        dict.learn_at("Old pizza is stale.", t0);
+        dict.learn_at("Some pizza is fine.", t0 + Duration::from_secs(10));
+        dict.learn_at("New pizza is fresh.", t0 + Duration::from_secs(20));
+
+        let mut fresh_replies = |bias: f64| {
+            dict.set_options(Options {
+                sentence_recency_bias: bias,
+                ..Default::default()
+            });
+            let mut rng = BorgRng::new(42);
+            (0..300)
+                .filter_map(|_| dict.respond_to("pizza", &mut rng))
+                .filter(|reply| reply.ends_with("fresh"))
+                .count()
+        };
+        let uniform = fresh_replies(0.0);
+        let biased = fresh_replies(4.0);
+        assert!(uniform > 50 && uniform < 150, "uniform: {}", uniform);
+        assert!(
+            biased > 2 * uniform,
+            "uniform: {}, biased: {}",
+            uniform,
+            biased
+        );
+    }
+
+    #[test]
+    fn test_shard() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza. Pizza is great. I love you. You are great. Cats sleep.");
+
+        assert!(dict.shard(0).is_empty());
+
+        let shards = dict.shard(2);
+        assert_eq!(2, shards.len());
+        let mut sentences: Vec<String> = shards
+            .iter()
+            .flat_map(|shard| shard.sentences.clone())
+            .collect();
+        sentences.sort();
+        let mut expected = dict.sentences.clone();
+        expected.sort();
+        assert_eq!(expected, sentences);
+
+        for shard in &shards {
+            assert!(!shard.sentences.is_empty());
+            assert!(shard.find_dangling_indices().is_empty());
+            for sentence in &shard.sentences {
+                for word in split_tokens(sentence, &shard.options) {
+                    assert!(shard.knows_word(&normalize_word(word, &shard.options)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_min_reply_space() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            min_reply_space: 3,
+            ..Default::default()
+        });
+        dict.learn("I love pizza. Pizza is great.");
+        assert_eq!(1, dict.reply_space());
+        assert_eq!(None, dict.respond_to("pizza", &mut StepRng::new(0, 1)));
+
+        dict.learn("I love you. You are great.");
+        assert!(dict.reply_space() >= 3);
+        assert!(dict.respond_to("pizza", &mut StepRng::new(0, 1)).is_some());
+    }
+
+    #[test]
+    fn test_contains_sentence_and_word() {
+        let mut d = Dictionary {
             sentences: vec![
-                "and i am a little teapot".to_string(),
-                "my name is josh and i am a little teapot".to_string(),
-            ],
-            indices: hashmap![
-                "and".to_string() => vec![0, 1],
-                "i".to_string() => vec![0, 1],
-                "am".to_string() => vec![0, 1],
-                "a".to_string() => vec![0, 1],
-                "little".to_string() => vec![0, 1],
-                "teapot".to_string() => vec![0, 1],
-                "my".to_string() => vec![1],
-                "name".to_string() => vec![1],
-                "is".to_string() => vec![1],
-                "josh".to_string() => vec![1]
+                "hello world".to_string(),
+                "my name is josh.".to_string(),
+                "hello world".to_string(),
             ],
+            ..Default::default()
         };
+        d.rebuild_indices();
+        assert!(d.contains_sentence("my name is josh."));
+        assert!(d.contains_sentence("My name is Josh."));
+        assert!(d.contains_sentence("hello world"));
+        assert!(!d.contains_sentence("my name is josh"));
+        assert!(!d.contains_sentence(""));
 
-        assert!(d.knows_word("and"));
-        assert!(d.knows_word("teapot"));
-        assert!(d.knows_word("josh"));
-        assert!(!d.knows_word("rat"));
-        assert!(!d.knows_word("dog"));
-        assert!(!d.knows_word(" "));
-        assert!(!d.knows_word(""));
+        assert!(d.contains_word("josh"));
+        assert!(d.contains_word("Hello"));
+        assert!(!d.contains_word("josh."));
+        assert!(!d.contains_word("teapot"));
+
+        // The duplicate makes lookups scan until it is removed.
+        d.dedup();
+        assert_eq!(d.sentences.len(), d.sentence_positions.len());
+        d.learn("I am a little teapot.");
+        assert_eq!(d.sentences.len(), d.sentence_positions.len());
+        assert!(d.contains_sentence("i am a little teapot."));
+        assert!(d.contains_sentence("hello world"));
+        assert!(d.contains_word("teapot"));
+    }
+
+    #[test]
+    fn test_preserve_case() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            preserve_case: true,
+            ..Default::default()
+        });
+        dict.learn("My friend Josh likes pizza. Pizza is what Josh eats.");
+        assert!(dict.contains_sentence("My friend Josh likes pizza."));
+        assert!(dict.contains_word("josh"));
+        assert!(dict.contains_word("JOSH"));
+
+        for i in 0..4 {
+            let reply = dict.respond_to("josh?", &mut StepRng::new(i, 1)).unwrap();
+            assert!(reply.contains("Josh"), "{:?}", reply);
+            assert!(!reply.contains("josh"), "{:?}", reply);
+        }
+    }
+
+    #[test]
+    fn test_splice_pivot_ending_left_sentence() {
+        use crate::rng::BorgRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza. We ate pizza. Pizza is great.");
+        let mut rng = BorgRng::new(7);
+        for _ in 0..50 {
+            let reply = dict.respond_to("pizza", &mut rng).unwrap();
+            if !reply.starts_with("pizza") {
+                assert!(reply.ends_with("pizza is great"), "{:?}", reply);
+            }
+        }
     }
 
     #[test]
-    fn test_insert_word_into_indices() {
-        let mut indices = hashmap![
-            "joy".to_string() => vec![1, 2]
-        ];
-        insert_word_into_indices(&mut indices, "john", 10);
-        assert_eq!(
-            hashmap![
-                "joy".to_string() => vec![1, 2],
-                "john".to_string() => vec![10]
-            ],
-            indices
-        );
-        insert_word_into_indices(&mut indices, "john", 20);
-        assert_eq!(
-            hashmap![
-                "joy".to_string() => vec![1, 2],
-                "john".to_string() => vec![10, 20]
-            ],
-            indices
-        );
-        insert_word_into_indices(&mut indices, "joy", 1);
-        assert_eq!(
-            hashmap![
-                "joy".to_string() => vec![1, 2],
-                "john".to_string() => vec![10, 20]
-            ],
-            indices
-        );
-        insert_word_into_indices(&mut indices, "joy", 6);
+    fn test_mask_pii() {
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            mask_pii: true,
+            ..Default::default()
+        });
+        dict.learn("Mail me at josh@example.com. My number is 555-123-4567. I love pizza.");
+        assert!(dict.contains_sentence("mail me at [email]."));
+        assert!(dict.contains_sentence("my number is [phone]."));
+        assert!(dict.contains_sentence("i love pizza."));
+        assert!(!dict.contains_word("josh"));
+        assert!(!dict.contains_word("555-123-4567"));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!("plain text", sanitize("plain text"));
         assert_eq!(
-            hashmap![
-                "joy".to_string() => vec![1, 2, 6],
-                "john".to_string() => vec![10, 20]
-            ],
-            indices
+            "pay alice now.\nok then",
+            sanitize("pay \u{202e}alice\u{202c}\u{200b} now.\u{0007}\nok\tthen")
         );
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love\u{200b} piz\u{2066}za\u{2069}.\u{001b} You\rlove\u{feff} it.");
+        assert!(dict.contains_sentence("i love pizza."));
+        assert!(dict.contains_sentence("you love it."));
+        assert!(dict
+            .sentences
+            .iter()
+            .all(|s| !s.contains(|c: char| c.is_control()
+                || c == '\u{200b}'
+                || c == '\u{2066}'
+                || c == '\u{feff}')));
     }
 
     #[test]
-    fn test_learn() {
-        let mut dict = Dictionary {
-            sentences: vec![],
-            indices: hashmap![],
+    fn test_swap_splice_sides() {
+        use crate::rng::BorgRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza a lot. We eat pizza daily.");
+        let mirror = |reply: &str| {
+            match reply {
+                "i love pizza daily" => "we eat pizza a lot",
+                "we eat pizza a lot" => "i love pizza daily",
+                other => other,
+            }
+            .to_string()
         };
-        dict.learn("Hey there, everyone!");
-        assert_eq!(
-            Dictionary {
-                sentences: vec!["hey there, everyone!".to_string()],
-                indices: hashmap![
-                    "hey".to_string() => vec![0],
-                    "there".to_string() => vec![0],
-                    "everyone".to_string() => vec![0]
-                ]
-            },
-            dict
+
+        let (mut kept, mut swapped) = (0, 0);
+        for seed in 0..40 {
+            let unswapped = dict.respond_to("pizza", &mut BorgRng::new(seed)).unwrap();
+            dict.set_options(Options {
+                swap_splice_sides: true,
+                ..Default::default()
+            });
+            let reply = dict.respond_to("pizza", &mut BorgRng::new(seed)).unwrap();
+            dict.set_options(Options::default());
+
+            if reply == unswapped {
+                kept += 1;
+            } else {
+                assert_eq!(mirror(&unswapped), reply);
+                swapped += 1;
+            }
+        }
+        assert!(
+            kept > 0 && swapped > 0,
+            "kept {}, swapped {}",
+            kept,
+            swapped
         );
-        dict.learn("How is everyone doing today?!");
+    }
+
+    #[test]
+    fn test_max_file_bytes_json() {
+        let path = std::env::temp_dir().join(format!("borg-max-{}.json", std::process::id()));
+        let t0 = UNIX_EPOCH + Duration::from_secs(1000);
+        let mut dict = Dictionary::new_empty();
+        for i in 0..100 {
+            dict.learn_at(
+                &format!("sentence number {} is here.", i),
+                t0 + Duration::from_secs(i),
+            );
+        }
+        let max_bytes = serialized_size(&dict).unwrap() / 2;
+        dict.set_options(Options {
+            max_file_bytes: Some(max_bytes),
+            ..Default::default()
+        });
+
+        dict.write_to_disk(&path).unwrap();
+        let written = fs::metadata(&path).unwrap().len();
+        fs::remove_file(&path).unwrap();
+
+        assert!(written <= max_bytes);
+        assert!(dict.sentences.len() < 100);
+        assert!(dict.contains_sentence("sentence number 99 is here."));
+        assert!(!dict.contains_sentence("sentence number 0 is here."));
+        assert!(dict.find_dangling_indices().is_empty());
+    }
+
+    #[test]
+    fn test_sentences_only_reachable_via() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn("Cats sleep. Cats eat fish. Dogs eat meat. Birds sing.");
+
+        // "cats eat fish." can be reached through "cats" or "eat", the other
+        // sentences with those words through only one of them.
         assert_eq!(
-            Dictionary {
-                sentences: vec![
-                    "hey there, everyone!".to_string(),
-                    "how is everyone doing today?!".to_string(),
-                ],
-                indices: hashmap![
-                    "hey".to_string() => vec![0],
-                    "there".to_string() => vec![0],
-                    "everyone".to_string() => vec![0, 1],
-                    "how".to_string() => vec![1],
-                    "is".to_string() => vec![1],
-                    "doing".to_string() => vec![1],
-                    "today".to_string() => vec![1]
-                ]
-            },
-            dict
+            vec!["cats sleep."],
+            dict.sentences_only_reachable_via("Cats")
         );
-        dict.learn("I've been doing fine today, what about you?");
         assert_eq!(
-            Dictionary {
-                sentences: vec![
-                    "hey there, everyone!".to_string(),
-                    "how is everyone doing today?!".to_string(),
-                    "i've been doing fine today, what about you?".to_string()
-                ],
-                indices: hashmap![
-                    "hey".to_string() => vec![0],
-                    "there".to_string() => vec![0],
-                    "everyone".to_string() => vec![0, 1],
-                    "how".to_string() => vec![1],
-                    "is".to_string() => vec![1],
-                    "doing".to_string() => vec![1, 2],
-                    "today".to_string() => vec![1, 2],
-                    "i've".to_string() => vec![2],
-                    "been".to_string() => vec![2],
-                    "fine".to_string() => vec![2],
-                    "what".to_string() => vec![2],
-                    "about".to_string() => vec![2],
-                    "you".to_string() => vec![2]
-                ]
-            },
-            dict
+            vec!["dogs eat meat."],
+            dict.sentences_only_reachable_via("eat")
         );
+        // Words found in a single sentence are never pivots.
+        assert!(dict.sentences_only_reachable_via("birds").is_empty());
+        assert!(dict.sentences_only_reachable_via("unknown").is_empty());
     }
 
     #[test]
-    fn test_respond() {
-        let dict = Dictionary {
-            sentences: vec![
-                "hey there everyone".to_string(),
-                "everyone is a crab".to_string(),
-                "crabs are great".to_string(),
-                "there are many crabs".to_string(),
-                "crabs".to_string(),
-            ],
-            indices: hashmap![
-                "hey".to_string() => vec![0],
-                "there".to_string() => vec![0, 3],
-                "everyone".to_string() => vec![0, 1],
-                "is".to_string() => vec![1],
-                "a".to_string() => vec![1],
-                "crab".to_string() => vec![1],
-                "crabs".to_string() => vec![2, 3, 4],
-                "are".to_string() => vec![2, 3],
-                "great".to_string() => vec![3],
-                "many".to_string() => vec![3]
-            ],
-        };
-        use rand::rngs::mock::StepRng;
-        assert_eq!(
-            Some("everyone".to_string()),
-            dict.respond_to("Hey there everyone!", &mut StepRng::new(2, 1))
+    fn test_random_walk() {
+        use crate::rng::BorgRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn(
+            "The cat sat. The cat sat on the mat. The dog sat on the cat. \
+             A dog ran to the big red barn and the cat ran after it.",
         );
+        let mut rng = BorgRng::new(3);
+        let mut replies = 0;
+        for _ in 0..50 {
+            if let Some(reply) = dict.random_walk(4, 6, &mut rng) {
+                let words = split_tokens(&reply, &dict.options).len();
+                assert!((4..=6).contains(&words), "{:?}", reply);
+                replies += 1;
+            }
+        }
+        assert!(replies > 0);
+        assert_eq!(None, dict.random_walk(100, 200, &mut rng));
+
+        // The transitions are kept between walks, and dropped on learning.
+        assert!(dict.transitions.borrow().is_some());
+        dict.learn("The bird flew.");
+        assert!(dict.transitions.borrow().is_none());
+
+        // Walks can be replies.
+        dict.set_options(Options {
+            fallback_chain: vec![ReplyStrategy::RandomWalk {
+                min_words: 4,
+                max_words: 6,
+            }],
+            ..Default::default()
+        });
+        let reply = dict
+            .respond_to_safe("anything", &[], None, &mut rng)
+            .unwrap();
+        let words = split_tokens(&reply.reply, &dict.options).len();
+        assert!((4..=6).contains(&words), "{:?}", reply);
+        assert!(!reply.sources.is_empty());
+
+        // A sentence without words is skipped over rather than ending the
+        // search.
+        let mut dict = Dictionary::new_empty();
+        dict.sentences = vec!["...".to_string(), "the cat sat.".to_string()];
+        dict.rebuild_indices();
+        let walks = (0..20)
+            .filter_map(|_| dict.random_walk(1, 5, &mut rng))
+            .count();
+        assert_eq!(20, walks);
+    }
+
+    #[test]
+    fn test_delete_session() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn_in_session("I love pizza. Pizza is great.", "food");
+        dict.learn_in_session("Cats sleep. Cats eat fish. I love pizza.", "pets");
         assert_eq!(
-            Some("hey there everyone".to_string()),
-            dict.respond_to("Hey there everyone!", &mut StepRng::new(8, 10))
+            Some("pets"),
+            dict.sentence_meta(dict.sentence_position("cats sleep.").unwrap())
+                .and_then(|meta| meta.session.as_deref())
         );
+
+        assert_eq!(0, dict.delete_session("nothing"));
+        assert_eq!(2, dict.delete_session("food"));
+        assert!(!dict.contains_word("pizza"));
+        assert!(dict.find_dangling_indices().is_empty());
+        assert!(dict.respond_to("cats", &mut StepRng::new(0, 1)).is_some());
+    }
+
+    #[test]
+    fn test_respond_in_context() {
+        use crate::rng::BorgRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza. Pizza is great. Cats sleep all day. Cats are cute.");
+        let context = vec!["we were talking about cats".to_string()];
         assert_eq!(
             None,
-            dict.respond_to("hey there crab people", &mut StepRng::new(2, 7))
+            dict.respond_to("what do you think", &mut BorgRng::new(0))
         );
+        for seed in 0..10 {
+            let reply = dict
+                .respond_in_context("what do you think", &context, None, &mut BorgRng::new(seed))
+                .unwrap();
+            assert!(reply.contains("cats"), "{:?}", reply);
+        }
+
+        // The line's own words are still the most likely pivots.
+        let pizza = (0..100)
+            .filter_map(|seed| {
+                dict.respond_in_context("pizza?", &context, None, &mut BorgRng::new(seed))
+            })
+            .filter(|reply| reply.contains("pizza"))
+            .count();
+        assert!(pizza > 50 && pizza < 100, "{}", pizza);
+    }
+
+    #[test]
+    fn test_respond_to_user() {
+        use crate::rng::BorgRng;
+
+        let t = UNIX_EPOCH + Duration::from_secs(100);
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            user_bias: 20.0,
+            ..Default::default()
+        });
+        dict.learn_from_user("alice", "I love pizza with cheese.", t);
+        dict.learn_from_user("bob", "Pizza is bad for you.", t);
+        dict.learn_from_user("bob", "I love pizza with cheese.", t);
         assert_eq!(
-            Some("crabs".to_string()),
-            dict.respond_to("hey there crabs people", &mut StepRng::new(2, 7))
+            vec!["alice".to_string(), "bob".to_string()],
+            dict.sentence_meta(0).unwrap().users
         );
+
+        let mut alice = BorgRng::new(7);
+        let mut bob = BorgRng::new(7);
+        let count = |user: &str, rng: &mut BorgRng, phrase: &str| {
+            (0..50)
+                .filter_map(|_| dict.respond_to_user(user, "pizza", rng))
+                .filter(|reply| reply.contains(phrase))
+                .count()
+        };
+        assert!(count("alice", &mut alice, "is bad") < count("bob", &mut bob, "is bad"));
+        assert!(dict.respond_to_user("carol", "pizza", &mut alice).is_some());
     }
 
     #[test]
-    fn test_known_words() {
-        let dict = Dictionary {
-            sentences: vec!["hello world!".to_string(), "i love pizza.".to_string()],
-            indices: hashmap![
-                "hello".to_string() => vec![0],
-                "world".to_string() => vec![0],
-                "i".to_string() => vec![1],
-                "love".to_string() => vec![1],
-                "pizza".to_string() => vec![1]
-            ],
-        };
+    fn test_reindex_incremental() {
+        // Sentences indexed by word, whatever their order.
+        fn by_word(dict: &Dictionary) -> HashMap<String, Vec<String>> {
+            dict.indices
+                .iter()
+                .map(|(word, _)| {
+                    let mut sentences: Vec<String> = dict
+                        .sentences_with_word(word)
+                        .into_iter()
+                        .map(|s| s.to_owned())
+                        .collect();
+                    sentences.sort();
+                    (word.to_owned(), sentences)
+                })
+                .collect()
+        }
 
-        let empty: Vec<&str> = vec![];
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza. Pizza is great. Cats eat fish. I love cats. Fish swim.");
+        dict.rebuild_indices();
 
-        assert_eq!(vec!["i", "love", "pizza"], dict.known_words("I Love Pizza"));
-        assert_eq!(vec!["i", "pizza"], dict.known_words("I Hate Pizza!"));
-        assert_eq!(vec!["i", "love"], dict.known_words("I Love You"));
-        assert_eq!(empty, dict.known_words("foo likes cake"));
-        assert_eq!(empty, dict.known_words("pizzacake"));
+        assert!(!dict.forget_sentence("Dogs bark."));
+        assert!(dict.forget_sentence("Cats eat fish."));
+        dict.sentences.insert(1, "dogs love pizza.".to_string());
+        dict.sentence_meta.insert(1, SentenceMeta::default());
+        dict.reindex_incremental(SentenceChange::Added(1));
+        assert!(dict.forget_sentence("fish swim."));
+
+        let mut rebuilt = dict.clone();
+        rebuilt.rebuild_indices();
+        assert_eq!(by_word(&rebuilt), by_word(&dict));
+        assert!(!dict.contains_word("fish"));
+        assert!(dict.last_seen("fish").is_none());
+        assert!(dict.find_dangling_indices().is_empty());
+        for (i, sentence) in dict.sentences.iter().enumerate() {
+            assert_eq!(Some(i), dict.sentence_position(sentence));
+        }
     }
 
     #[test]
-    fn test_sentences_with_word() {
-        let dict = Dictionary {
-            sentences: vec![
-                "hello world!".to_string(),
-                "i love pizza.".to_string(),
-                "pizza is like, cool".to_string(),
-            ],
-            indices: hashmap![
-                "hello".to_string() => vec![0],
-                "world".to_string() => vec![0],
-                "i".to_string() => vec![1],
-                "love".to_string() => vec![1],
-                "pizza".to_string() => vec![1, 2],
-                "is".to_string() => vec![2],
-                "like".to_string() => vec![2],
-                "cool".to_string() => vec![2]
-            ],
-        };
+    fn test_reindex_incremental_insert_in_middle() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn("A cat eats fish. I love pizza. Pizza is great. You love cats.");
+        dict.rebuild_indices();
 
-        let empty: Vec<&str> = vec![];
+        let i = dict
+            .sentences
+            .iter()
+            .position(|s| s.as_str() > "dogs love pizza.")
+            .unwrap();
+        dict.sentences.insert(i, "dogs love pizza.".to_string());
+        dict.sentence_meta.insert(i, SentenceMeta::default());
+        dict.reindex_incremental(SentenceChange::Added(i));
 
-        assert_eq!(
-            vec!["i love pizza.", "pizza is like, cool"],
-            dict.sentences_with_word("pizza")
-        );
-        assert_eq!(vec!["i love pizza."], dict.sentences_with_word("love"));
-        assert_eq!(empty, dict.sentences_with_word("nonexisting"));
-        assert_eq!(empty, dict.sentences_with_word("luve"));
-        assert_eq!(empty, dict.sentences_with_word(""));
+        let mut rebuilt = dict.clone();
+        rebuilt.rebuild_indices();
+        assert_eq!(rebuilt.sentences, dict.sentences);
+        for (word, _) in rebuilt.indices.iter() {
+            let ids = dict.sentence_ids_with_word(word);
+            assert_eq!(rebuilt.sentence_ids_with_word(word), ids);
+            assert!(ids.windows(2).all(|w| w[0] < w[1]), "{}: {:?}", word, ids);
+        }
+        assert_eq!(rebuilt.indices.len(), dict.indices.len());
     }
 
     #[test]
-    fn test_get_words_left_of_pivot() {
+    fn test_count_debounce() {
+        let t0 = UNIX_EPOCH + Duration::from_secs(1000);
+        let at = |secs| t0 + Duration::from_secs(secs);
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            count_debounce_secs: 60,
+            ..Default::default()
+        });
+        dict.learn_at("Buy my stuff.", t0);
+        dict.learn_at("Buy my stuff.", at(1));
+        dict.learn_at("Buy my stuff.", at(30));
+        assert_eq!(1, dict.sentence_meta(0).unwrap().count);
+        dict.learn_at("Buy my stuff.", at(60));
+        dict.learn_at("Buy my stuff.", at(90));
+        assert_eq!(2, dict.sentence_meta(0).unwrap().count);
+        dict.learn_at("Buy my stuff.", at(200));
+        assert_eq!(3, dict.sentence_meta(0).unwrap().count);
+
+        dict.set_options(Options::default());
+        dict.learn_at("Buy my stuff.", at(201));
+        assert_eq!(4, dict.sentence_meta(0).unwrap().count);
+    }
+
+    #[test]
+    fn test_forget_word() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I love pizza. Pizza is great. Cats eat fish. I love cats. Fish swim.");
+        assert_eq!(0, dict.forget_word("dogs"));
+        assert_eq!(2, dict.forget_word("Pizza"));
+        assert!(!dict.contains_word("pizza"));
+        assert!(!dict.contains_sentence("i love pizza."));
+        assert!(dict.last_seen("pizza").is_none());
+        assert!(dict.last_seen("love").is_some());
+        assert_eq!(3, dict.sentences.len());
+        assert_eq!(3, dict.sentence_meta.len());
+        assert!(dict.find_dangling_indices().is_empty());
+        for (i, sentence) in dict.sentences.iter().enumerate() {
+            assert_eq!(Some(i), dict.sentence_position(sentence));
+        }
+        assert!(dict.respond_to("cats", &mut StepRng::new(0, 1)).is_some());
+    }
+
+    #[test]
+    fn test_word_display_forms() {
+        use crate::rng::BorgRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.set_options(Options {
+            preserve_case: true,
+            ..Default::default()
+        });
+        dict.learn("I ate an Apple today. Apple pie is great. An apple a day is great.");
+        assert!(dict.contains_word("apple"));
+        assert!(dict.contains_word("APPLE"));
+
+        let mut rng = BorgRng::new(5);
+        for _ in 0..20 {
+            let reply = dict.respond_to("apple", &mut rng).unwrap();
+            assert!(reply.contains("Apple"), "{:?}", reply);
+            assert!(!reply.contains("apple"), "{:?}", reply);
+        }
+
+        // The counts are rebuilt with the indices.
+        let sorted_forms = |dict: &Dictionary| {
+            let mut forms: Vec<(String, Vec<(String, u32)>)> = dict
+                .word_forms
+                .iter()
+                .map(|(word, forms)| {
+                    let mut forms = forms.clone();
+                    forms.sort();
+                    (word.clone(), forms)
+                })
+                .collect();
+            forms.sort();
+            forms
+        };
+        let forms = sorted_forms(&dict);
+        dict.rebuild_indices();
+        assert_eq!(forms, sorted_forms(&dict));
+
+        // They are taken back with forgotten sentences.
+        assert!(dict.forget_sentence("I ate an Apple today."));
+        assert!(dict.forget_sentence("Apple pie is great."));
+        let forms = sorted_forms(&dict);
+        dict.rebuild_indices();
+        assert_eq!(forms, sorted_forms(&dict));
         assert_eq!(
-            Some(vec!["this", "is", "a"]),
-            get_words_left_of_pivot("this is a test yeah this is a test", "test")
+            Some(&vec![("apple".to_string(), 1)]),
+            dict.word_forms.get("apple")
         );
+        assert!(!dict.word_forms.contains_key("pie"));
+    }
+
+    #[test]
+    fn test_no_junk_tokens() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn("!!! ... ??? -- ; :) \u{200b} ()");
+        dict.learn("Hello - world ;) Hello -- world :-)");
+        let words: HashSet<&str> = dict.indices.iter().map(|(word, _)| word).collect();
         assert_eq!(
-            Some(Vec::<&str>::new()),
-            get_words_left_of_pivot("this", "this")
+            vec!["hello", "world"].into_iter().collect::<HashSet<_>>(),
+            words
         );
-        assert_eq!(
-            Some(Vec::<&str>::new()),
-            get_words_left_of_pivot("this this", "this")
+        assert!(dict
+            .last_seen
+            .keys()
+            .all(|word| word == "hello" || word == "world"));
+
+        dict.rebuild_indices();
+        assert_eq!(2, dict.indices.len());
+        assert!(dict.similar_words("- ; ...").is_empty());
+    }
+
+    #[test]
+    fn test_relevance_candidates() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn(
+            "Cats sleep all day. Cats eat fish daily. Fish swim in the sea. \
+             Dogs eat meat. My cats play.",
         );
-        assert_eq!(None, get_words_left_of_pivot("i am a little teapot", "fox"));
-        assert_eq!(
-            None,
-            get_words_left_of_pivot("abc def ghi jkl", "abc def" /* not a word */)
+        let covers_both = |reply: &str| reply.contains("cats") && reply.contains("fish");
+
+        let replies = sample_replies(&dict, "cats and fish", 20);
+        assert!(!replies.iter().all(|reply| covers_both(reply)));
+        dict.set_options(Options {
+            relevance_candidates: 10,
+            ..Default::default()
+        });
+        let relevant = sample_replies(&dict, "cats and fish", 20);
+        assert_eq!(20, relevant.len());
+        assert!(
+            relevant.iter().all(|reply| covers_both(reply)),
+            "{:?}",
+            relevant
         );
     }
 
     #[test]
-    fn test_get_words_right_of_pivot_inclusive() {
+    fn test_provenance() {
+        use rand::rngs::mock::StepRng;
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("Pizza is great. Cats are hot. I love pizza.");
+        let provenance = dict
+            .respond_with_provenance("pizza", &mut StepRng::new(0, 0))
+            .unwrap();
         assert_eq!(
-            Some(vec!["test", "yeah", "this", "is", "a", "test"]),
-            get_words_right_of_pivot_inclusive("this is a test yeah this is a test", "test")
+            Provenance {
+                reply: "pizza is great".to_string(),
+                sources: vec![(0, "pizza is great.".to_string())],
+            },
+            provenance
         );
+
         assert_eq!(
-            Some(vec!["this"]),
-            get_words_right_of_pivot_inclusive("this", "this")
+            vec![
+                (2, "i love pizza.".to_string()),
+                (0, "pizza is great.".to_string())
+            ],
+            dict.provenance("I love pizza is great.").sources
         );
+        // Bridged replies don't repeat a pivot, and follow-ups are traced
+        // too.
         assert_eq!(
-            Some(vec!["this", "this"]),
-            get_words_right_of_pivot_inclusive("this this", "this")
+            vec![
+                (2, "i love pizza.".to_string()),
+                (1, "cats are hot.".to_string()),
+                (1, "cats are hot.".to_string())
+            ],
+            dict.provenance("I love pizza are hot. Cats are hot.")
+                .sources
         );
-        assert_eq!(None, get_words_left_of_pivot("i am a little teapot", "fox"));
+        assert!(dict.provenance("dogs bark").sources.is_empty());
+
+        // Stale indices pointing past the sentences are skipped.
+        let dict = Dictionary {
+            sentences: vec!["pizza is good".to_string()],
+            indices: Indices::from(hashmap![
+                "pizza".to_string() => vec![0, 7],
+                "is".to_string() => vec![0],
+                "good".to_string() => vec![0]
+            ]),
+            ..Default::default()
+        };
+        assert!(dict.provenance("pizza rocks").sources.is_empty());
         assert_eq!(
-            None,
-            get_words_right_of_pivot_inclusive("abc def ghi jkl", "abc def" /* not a word */)
+            vec![(0, "pizza is good".to_string())],
+            dict.provenance("pizza is good").sources
+        );
+    }
+
+    #[test]
+    fn test_blocked_phrases() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn("I hate the rain. The people are nice.");
+        assert!(sample_replies(&dict, "the", 20)
+            .iter()
+            .any(|reply| reply.contains("hate the people")));
+
+        dict.set_options(Options {
+            blocked_phrases: vec!["Hate the PEOPLE!".to_string()],
+            ..Default::default()
+        });
+        let replies = sample_replies(&dict, "the", 20);
+        assert!(!replies.is_empty());
+        assert!(
+            replies
+                .iter()
+                .all(|reply| !reply.contains("hate the people")),
+            "{:?}",
+            replies
+        );
+    }
+
+    #[test]
+    fn test_reply_language() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn(
+            "I love pizza with the cheese. La pizza es muy buena y barata. \
+             This pizza is the best.",
         );
+        let spanish = |replies: &[String]| {
+            replies
+                .iter()
+                .filter(|reply| language::detect(reply) == Some(Language::Spanish))
+                .count()
+        };
+
+        assert!(spanish(&sample_replies(&dict, "pizza", 20)) > 0);
+        dict.set_options(Options {
+            reply_language: Some(Language::English),
+            ..Default::default()
+        });
+        let english = sample_replies(&dict, "pizza", 20);
+        assert!(!english.is_empty());
+        assert_eq!(0, spanish(&english), "{:?}", english);
     }
 }
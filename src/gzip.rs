@@ -0,0 +1,382 @@
+use std::io;
+
+const MAGIC: [u8; 2] = [0x1f, 0x8b];
+const DEFLATE: u8 = 8;
+
+const FLAG_HEADER_CRC: u8 = 0x02;
+const FLAG_EXTRA: u8 = 0x04;
+const FLAG_NAME: u8 = 0x08;
+const FLAG_COMMENT: u8 = 0x10;
+
+// Base lengths and distances of the DEFLATE length and distance codes, and
+// how many extra bits follow each.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// Order in which the code lengths of the code length alphabet are stored.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+// is_gzip returns whether the data starts like gzip data does.
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+// decompress returns the data held by the gzip file, made of one or more
+// members one after the other, checking each against its checksum.
+pub fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    let mut rest = data;
+    loop {
+        let used = decompress_member(rest, &mut out)?;
+        rest = &rest[used..];
+        if !is_gzip(rest) {
+            return Ok(out);
+        }
+    }
+}
+
+// decompress_member appends the data of the gzip member at the start of the
+// data to out, and returns the number of bytes the member takes.
+fn decompress_member(data: &[u8], out: &mut Vec<u8>) -> io::Result<usize> {
+    if !is_gzip(data) || data.len() < 18 {
+        return Err(invalid("not gzip data"));
+    }
+    if data[2] != DEFLATE {
+        return Err(invalid("unknown gzip compression method"));
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & FLAG_EXTRA != 0 {
+        let extra = u16::from_le_bytes([byte_at(data, pos)?, byte_at(data, pos + 1)?]);
+        pos += 2 + extra as usize;
+    }
+    for flag in &[FLAG_NAME, FLAG_COMMENT] {
+        if flags & flag != 0 {
+            while byte_at(data, pos)? != 0 {
+                pos += 1;
+            }
+            pos += 1;
+        }
+    }
+    if flags & FLAG_HEADER_CRC != 0 {
+        pos += 2;
+    }
+    if pos > data.len() {
+        return Err(invalid("truncated gzip header"));
+    }
+
+    let start = out.len();
+    let mut reader = BitReader::new(&data[pos..]);
+    inflate(&mut reader, out)?;
+    pos += reader.bytes_used();
+
+    let trailer = data
+        .get(pos..pos + 8)
+        .ok_or_else(|| invalid("truncated gzip trailer"))?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    let member = &out[start..];
+    if crc32(member) != crc || member.len() as u32 != size {
+        return Err(invalid("gzip checksum mismatch"));
+    }
+    Ok(pos + 8)
+}
+
+// inflate appends the data of a DEFLATE stream to out.
+fn inflate(reader: &mut BitReader, out: &mut Vec<u8>) -> io::Result<()> {
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let len = reader.bits(16)?;
+                let nlen = reader.bits(16)?;
+                if len != !nlen & 0xffff {
+                    return Err(invalid("corrupt stored block"));
+                }
+                for _ in 0..len {
+                    out.push(reader.bits(8)? as u8);
+                }
+            }
+            1 => {
+                let (literals, distances) = fixed_codes();
+                inflate_block(reader, out, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(reader)?;
+                inflate_block(reader, out, &literals, &distances)?;
+            }
+            _ => return Err(invalid("unknown block type")),
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> io::Result<()> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+        let code = symbol - 257;
+        if code >= LENGTH_BASE.len() {
+            return Err(invalid("bad length code"));
+        }
+        let len = LENGTH_BASE[code] as usize + reader.bits(LENGTH_EXTRA[code] as u32)? as usize;
+        let code = distances.decode(reader)? as usize;
+        if code >= DISTANCE_BASE.len() {
+            return Err(invalid("bad distance code"));
+        }
+        let distance =
+            DISTANCE_BASE[code] as usize + reader.bits(DISTANCE_EXTRA[code] as u32)? as usize;
+        if distance > out.len() {
+            return Err(invalid("distance too far back"));
+        }
+        for _ in 0..len {
+            out.push(out[out.len() - distance]);
+        }
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    for (symbol, length) in lengths.iter_mut().enumerate() {
+        *length = match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> io::Result<(Huffman, Huffman)> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    let mut code_lengths = [0u8; 19];
+    for &i in CODE_LENGTH_ORDER.iter().take(code_length_count) {
+        code_lengths[i] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+
+    let total = literal_count + distance_count;
+    let mut lengths: Vec<u8> = Vec::with_capacity(total);
+    while lengths.len() < total {
+        let symbol = code_lengths.decode(reader)?;
+        let (length, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => match lengths.last() {
+                Some(&previous) => (previous, 3 + reader.bits(2)?),
+                None => return Err(invalid("repeated code length with none before")),
+            },
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        if lengths.len() + repeat as usize > total {
+            return Err(invalid("too many code lengths"));
+        }
+        lengths.extend((0..repeat).map(|_| length));
+    }
+    Ok((
+        Huffman::new(&lengths[..literal_count]),
+        Huffman::new(&lengths[literal_count..]),
+    ))
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn byte_at(data: &[u8], pos: usize) -> io::Result<u8> {
+    data.get(pos)
+        .copied()
+        .ok_or_else(|| invalid("truncated gzip header"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// BitReader Struct
+/////////////////////////////////////////////////////////////////////////////
+
+// BitReader reads the data as bits, least significant first, as DEFLATE
+// packs them.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u64,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            pos: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        while self.count < n {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| invalid("truncated compressed data"))?;
+            self.buffer |= (byte as u64) << self.count;
+            self.count += 8;
+            self.pos += 1;
+        }
+        let value = (self.buffer & ((1u64 << n) - 1)) as u32;
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    // align skips to the start of the next byte.
+    fn align(&mut self) {
+        let skip = self.count % 8;
+        self.buffer >>= skip;
+        self.count -= skip;
+    }
+
+    // bytes_used returns the number of bytes read up to the current bit,
+    // counting the one it is in.
+    fn bytes_used(&self) -> usize {
+        self.pos - (self.count / 8) as usize
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Huffman Struct
+/////////////////////////////////////////////////////////////////////////////
+
+// Huffman decodes a canonical Huffman code given by the code length of each
+// symbol.
+struct Huffman {
+    // Number of codes of each length.
+    counts: [u16; 16],
+    // The symbols, ordered by their codes.
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16)
+            .filter(|&s| lengths[s as usize] != 0)
+            .collect();
+        symbols.sort_by_key(|&s| lengths[s as usize]);
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return self
+                    .symbols
+                    .get((index + code - first) as usize)
+                    .copied()
+                    .ok_or_else(|| invalid("bad Huffman code"));
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("bad Huffman code"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decompress() {
+        // "hello hello hello\n", compressed by gzip with a fixed Huffman code.
+        let fixed = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48, 0xcd, 0xc9,
+            0xc9, 0x57, 0xc8, 0x40, 0x90, 0x5c, 0x00, 0x3b, 0x7c, 0x8a, 0xdf, 0x12, 0x00, 0x00,
+            0x00,
+        ];
+        assert_eq!(b"hello hello hello\n".to_vec(), decompress(&fixed).unwrap());
+
+        // Random letters, most of them "a", with a dynamic Huffman code.
+        let dynamic = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x25, 0xca, 0xb1, 0x0d,
+            0x00, 0x30, 0x0c, 0x02, 0xc1, 0xde, 0x53, 0xb0, 0xda, 0x83, 0xf7, 0x9f, 0x21, 0x58,
+            0x81, 0x8a, 0x13, 0x60, 0xb4, 0x0a, 0x88, 0x84, 0x35, 0x85, 0x26, 0xed, 0xb0, 0xba,
+            0x55, 0xcc, 0xb1, 0x27, 0x83, 0xad, 0xc9, 0x3f, 0x3f, 0xd7, 0x50, 0x91, 0xcc, 0x3c,
+            0x00, 0x00, 0x00,
+        ];
+        let expected = b"aaba d caa accadbaabaaaacaca\nad abaadbacaabab\nc\nabb \nca acca";
+        assert_eq!(expected.to_vec(), decompress(&dynamic).unwrap());
+
+        // Two members, the second holding a stored block.
+        let mut members = fixed.to_vec();
+        members.extend_from_slice(&[
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x03, 0x01, 0x03, 0x00, 0xfc,
+            0xff, 0x62, 0x79, 0x65, 0x34, 0x91, 0x37, 0x77, 0x03, 0x00, 0x00, 0x00,
+        ]);
+        assert_eq!(
+            b"hello hello hello\nbye".to_vec(),
+            decompress(&members).unwrap()
+        );
+
+        let mut corrupt = fixed.to_vec();
+        corrupt[fixed.len() - 8] ^= 1;
+        assert!(decompress(&corrupt).is_err());
+        assert!(decompress(&fixed[..20]).is_err());
+        assert!(decompress(b"plain text").is_err());
+        assert!(is_gzip(&fixed));
+        assert!(!is_gzip(b"plain text"));
+    }
+}
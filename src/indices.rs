@@ -0,0 +1,549 @@
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::mem::size_of;
+
+/////////////////////////////////////////////////////////////////////////////
+// Types
+/////////////////////////////////////////////////////////////////////////////
+
+pub type WordId = u32;
+pub type SentenceId = u32;
+
+/// The postings list of a word, i.e. the sentences it appears in. With the
+/// bitset_indices feature, the lists are stored as bitsets, which take much
+/// less memory for words that appear in many sentences.
+#[cfg(not(feature = "bitset_indices"))]
+pub type Postings = VecPostings;
+#[cfg(feature = "bitset_indices")]
+pub type Postings = BitsetPostings;
+
+/////////////////////////////////////////////////////////////////////////////
+// VecPostings Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// VecPostings stores sentence ids as a plain list, sorted in increasing
+/// order.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VecPostings {
+    ids: Vec<SentenceId>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// VecPostings Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl VecPostings {
+    // insert adds the id to the list where it belongs in order, unless it is
+    // already in it.
+    pub fn insert(&mut self, id: SentenceId) {
+        if let Err(i) = self.ids.binary_search(&id) {
+            self.ids.insert(i, id);
+        }
+    }
+
+    pub fn contains(&self, id: SentenceId) -> bool {
+        self.ids.binary_search(&id).is_ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = SentenceId> + '_ {
+        self.ids.iter().copied()
+    }
+
+    pub fn remove(&mut self, id: SentenceId) {
+        if let Ok(i) = self.ids.binary_search(&id) {
+            self.ids.remove(i);
+        }
+    }
+
+    // shift moves every id from the given one on up by one, or down by one,
+    // as when a sentence is inserted or removed before them.
+    pub fn shift(&mut self, from: SentenceId, up: bool) {
+        for id in self.ids.iter_mut().filter(|id| **id >= from) {
+            if up {
+                *id += 1;
+            } else {
+                *id -= 1;
+            }
+        }
+    }
+
+    fn estimated_size(&self) -> usize {
+        size_of::<Self>() + self.ids.len() * size_of::<SentenceId>()
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// BitsetPostings Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// BitsetPostings stores sentence ids as a sparse bitset: a sorted list of
+/// 64-bit blocks, only keeping the blocks that have a bit set. Ids are
+/// iterated in increasing order.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BitsetPostings {
+    // Pairs of block number and bits, where bit i of block b is id b * 64 + i.
+    blocks: Vec<(u32, u64)>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// BitsetPostings Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl BitsetPostings {
+    pub fn insert(&mut self, id: SentenceId) {
+        let (block, bit) = (id / 64, 1u64 << (id % 64));
+        match self.blocks.binary_search_by_key(&block, |(b, _)| *b) {
+            Ok(i) => self.blocks[i].1 |= bit,
+            Err(i) => self.blocks.insert(i, (block, bit)),
+        }
+    }
+
+    pub fn contains(&self, id: SentenceId) -> bool {
+        let (block, bit) = (id / 64, 1u64 << (id % 64));
+        match self.blocks.binary_search_by_key(&block, |(b, _)| *b) {
+            Ok(i) => self.blocks[i].1 & bit != 0,
+            Err(_) => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks
+            .iter()
+            .map(|(_, bits)| bits.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = SentenceId> + '_ {
+        self.blocks.iter().flat_map(|(block, bits)| {
+            let bits = *bits;
+            (0..64)
+                .filter(move |i| bits & (1u64 << i) != 0)
+                .map(move |i| block * 64 + i)
+        })
+    }
+
+    pub fn remove(&mut self, id: SentenceId) {
+        let (block, bit) = (id / 64, 1u64 << (id % 64));
+        if let Ok(i) = self.blocks.binary_search_by_key(&block, |(b, _)| *b) {
+            self.blocks[i].1 &= !bit;
+            if self.blocks[i].1 == 0 {
+                self.blocks.remove(i);
+            }
+        }
+    }
+
+    // shift moves every id from the given one on up by one, or down by one,
+    // as when a sentence is inserted or removed before them. The bits don't
+    // line up across blocks, so the bitset is built again.
+    pub fn shift(&mut self, from: SentenceId, up: bool) {
+        let ids: Vec<SentenceId> = self.iter().collect();
+        self.blocks.clear();
+        for id in ids {
+            match id {
+                id if id < from => self.insert(id),
+                id if up => self.insert(id + 1),
+                id => self.insert(id - 1),
+            }
+        }
+    }
+
+    fn estimated_size(&self) -> usize {
+        size_of::<Self>() + self.blocks.len() * size_of::<(u32, u64)>()
+    }
+}
+
+macro_rules! serialize_postings {
+    ($t:ty) => {
+        impl Serialize for $t {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let mut seq = serializer.serialize_seq(Some(self.len()))?;
+                for id in self.iter() {
+                    seq.serialize_element(&id)?;
+                }
+                seq.end()
+            }
+        }
+    };
+}
+
+serialize_postings!(VecPostings);
+serialize_postings!(BitsetPostings);
+
+/////////////////////////////////////////////////////////////////////////////
+// Vocabulary Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// Vocabulary is the string table of the dictionary. Every distinct word is
+/// stored exactly once and is referred to everywhere else by its WordId.
+#[derive(Debug, Default, Clone)]
+pub struct Vocabulary {
+    ids: HashMap<Box<str>, WordId>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Vocabulary Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl Vocabulary {
+    pub fn id(&self, word: &str) -> Option<WordId> {
+        self.ids.get(word).copied()
+    }
+
+    // intern returns the id of the word, assigning it the next free id if
+    // the word has not been seen before.
+    pub fn intern(&mut self, word: &str, next_id: WordId) -> WordId {
+        *self.ids.entry(word.into()).or_insert(next_id)
+    }
+
+    pub fn forget(&mut self, word: &str) -> Option<WordId> {
+        self.ids.remove(word)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, WordId)> {
+        self.ids.iter().map(|(word, id)| (word.as_ref(), *id))
+    }
+
+    fn estimated_size(&self) -> usize {
+        self.ids.len() * size_of::<(Box<str>, WordId)>()
+            + self.ids.keys().map(|word| word.len()).sum::<usize>()
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Indices Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// Indices maps every known word to the sentences it appears in. Words are
+/// interned in a Vocabulary and the sentence lists are keyed by WordId, so
+/// the word strings are not repeated anywhere else.
+///
+/// On disk, indices are stored as a plain JSON object of word to sentence
+/// indices, which keeps old dictionary files loadable.
+#[derive(Debug, Default, Clone)]
+pub struct Indices {
+    vocabulary: Vocabulary,
+    postings: Vec<Postings>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Indices Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl Indices {
+    pub fn new() -> Indices {
+        Indices::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vocabulary.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.vocabulary.len()
+    }
+
+    pub fn contains_key(&self, word: &str) -> bool {
+        self.vocabulary.id(word).is_some()
+    }
+
+    pub fn get(&self, word: &str) -> Option<&Postings> {
+        self.vocabulary
+            .id(word)
+            .map(|id| &self.postings[id as usize])
+    }
+
+    // insert records that the word appears in the sentence at the given
+    // index. Inserting the same pair twice has no effect.
+    pub fn insert(&mut self, word: &str, sentence_index: usize) {
+        let next_id = self.postings.len() as WordId;
+        let id = self.vocabulary.intern(word, next_id);
+        if id == next_id {
+            self.postings.push(Postings::default());
+        }
+        self.postings[id as usize].insert(sentence_index as SentenceId);
+    }
+
+    // remove_entry forgets that the word appears in the sentence at the given
+    // index, and forgets the word altogether if it appears nowhere else.
+    // Returns whether the word was forgotten.
+    pub fn remove_entry(&mut self, word: &str, sentence_index: usize) -> bool {
+        let id = match self.vocabulary.id(word) {
+            Some(id) => id,
+            None => return false,
+        };
+        let postings = &mut self.postings[id as usize];
+        postings.remove(sentence_index as SentenceId);
+        if postings.is_empty() {
+            self.vocabulary.forget(word);
+            return true;
+        }
+        false
+    }
+
+    // shift_sentences moves every sentence index from the given one on up by
+    // one, or down by one, as when a sentence is inserted or removed before
+    // them.
+    pub fn shift_sentences(&mut self, from: usize, up: bool) {
+        for postings in &mut self.postings {
+            postings.shift(from as SentenceId, up);
+        }
+    }
+
+    pub fn remove(&mut self, word: &str) -> Option<Vec<SentenceId>> {
+        self.vocabulary.forget(word).map(|id| {
+            let postings = std::mem::take(&mut self.postings[id as usize]);
+            postings.iter().collect()
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Postings)> {
+        self.vocabulary
+            .iter()
+            .map(move |(word, id)| (word, &self.postings[id as usize]))
+    }
+
+    // estimated_size returns an approximation, in bytes, of the memory used
+    // by the indices, not counting the hash map's spare capacity.
+    pub fn estimated_size(&self) -> usize {
+        self.vocabulary.estimated_size()
+            + self
+                .postings
+                .iter()
+                .map(|p| p.estimated_size())
+                .sum::<usize>()
+    }
+}
+
+impl PartialEq for Indices {
+    fn eq(&self, other: &Indices) -> bool {
+        self.len() == other.len() && self.iter().all(|(word, ys)| other.get(word) == Some(ys))
+    }
+}
+
+impl Eq for Indices {}
+
+impl From<HashMap<String, Vec<usize>>> for Indices {
+    fn from(map: HashMap<String, Vec<usize>>) -> Indices {
+        let mut indices = Indices::new();
+        for (word, ys) in map {
+            for y in ys {
+                indices.insert(&word, y);
+            }
+        }
+        indices
+    }
+}
+
+// Indices are serialized sorted by word, so that equal indices are always
+// written the same way.
+impl Serialize for Indices {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut entries: Vec<(&str, &Postings)> = self.iter().collect();
+        entries.sort_by_key(|(word, _)| *word);
+        let mut map = serializer.serialize_map(Some(entries.len()))?;
+        for (word, ys) in entries {
+            map.serialize_entry(word, ys)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Indices {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Indices, D::Error> {
+        HashMap::<String, Vec<usize>>::deserialize(deserializer).map(Indices::from)
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Sorted Id Functions
+/////////////////////////////////////////////////////////////////////////////
+
+// intersect_sorted returns the ids found in both lists. Both lists must be
+// sorted in increasing order and free of duplicates, as the ids of postings
+// are, and so is the result.
+pub fn intersect_sorted(a: &[usize], b: &[usize]) -> Vec<usize> {
+    let mut result = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            Ordering::Less => i += 1,
+            Ordering::Greater => j += 1,
+            Ordering::Equal => {
+                result.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    result
+}
+
+// union_sorted returns the ids found in either list. Both lists must be
+// sorted in increasing order and free of duplicates, and so is the result.
+pub fn union_sorted(a: &[usize], b: &[usize]) -> Vec<usize> {
+    let mut result = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            Ordering::Less => {
+                result.push(a[i]);
+                i += 1;
+            }
+            Ordering::Greater => {
+                result.push(b[j]);
+                j += 1;
+            }
+            Ordering::Equal => {
+                result.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    result.extend_from_slice(&a[i..]);
+    result.extend_from_slice(&b[j..]);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intersect_and_union_sorted() {
+        let empty: Vec<usize> = vec![];
+        assert_eq!(vec![2, 5], intersect_sorted(&[1, 2, 4, 5], &[2, 3, 5, 8]));
+        assert_eq!(
+            vec![1, 2, 3, 4, 5, 8],
+            union_sorted(&[1, 2, 4, 5], &[2, 3, 5, 8])
+        );
+
+        assert_eq!(empty, intersect_sorted(&[1, 3], &[2, 4]));
+        assert_eq!(vec![1, 2, 3, 4], union_sorted(&[1, 3], &[2, 4]));
+
+        assert_eq!(empty, intersect_sorted(&[], &[1, 2]));
+        assert_eq!(vec![1, 2], union_sorted(&[], &[1, 2]));
+        assert_eq!(vec![1, 2], union_sorted(&[1, 2], &[]));
+        assert_eq!(empty, union_sorted(&[], &[]));
+    }
+
+    #[test]
+    fn test_insert_and_get() {
+        let mut indices = Indices::new();
+        indices.insert("hello", 0);
+        indices.insert("world", 0);
+        indices.insert("hello", 2);
+        indices.insert("hello", 2);
+
+        let ids = |word| indices.get(word).map(|p| p.iter().collect::<Vec<_>>());
+        assert_eq!(Some(vec![0, 2]), ids("hello"));
+        assert_eq!(Some(vec![0]), ids("world"));
+        assert_eq!(None, indices.get("nope"));
+        assert!(indices.contains_key("world"));
+        assert!(!indices.contains_key(""));
+        assert_eq!(2, indices.len());
+
+        assert_eq!(Some(vec![0]), indices.remove("world"));
+        assert!(!indices.contains_key("world"));
+        assert_eq!(1, indices.len());
+    }
+
+    // Bitsets only pay off for words in many sentences, so they are larger
+    // than lists on a dictionary this small.
+    #[test]
+    #[cfg(not(feature = "bitset_indices"))]
+    fn test_estimated_size_is_smaller_than_string_keys() {
+        let map: HashMap<String, Vec<usize>> = hashmap![
+            "this".to_string() => vec![0, 1, 2],
+            "is".to_string() => vec![0, 1, 2],
+            "a".to_string() => vec![0, 1],
+            "test".to_string() => vec![0],
+            "hello".to_string() => vec![2]
+        ];
+        let string_keyed_size: usize = map
+            .iter()
+            .map(|(word, ys)| {
+                size_of::<(String, Vec<usize>)>() + word.len() + ys.len() * size_of::<usize>()
+            })
+            .sum();
+
+        let indices = Indices::from(map.clone());
+        for (word, ys) in &map {
+            let ys: Vec<SentenceId> = ys.iter().map(|y| *y as SentenceId).collect();
+            assert_eq!(Some(ys), indices.get(word).map(|p| p.iter().collect()));
+        }
+        assert!(indices.estimated_size() < string_keyed_size);
+    }
+
+    #[test]
+    fn test_bitset_postings_match_vec_postings() {
+        let ids: Vec<SentenceId> = vec![0, 1, 63, 64, 65, 200, 1000, 1, 64];
+        let mut vec = VecPostings::default();
+        let mut bitset = BitsetPostings::default();
+        for id in &ids {
+            vec.insert(*id);
+            bitset.insert(*id);
+        }
+
+        assert_eq!(vec.len(), bitset.len());
+        assert_eq!(
+            vec.iter().collect::<Vec<_>>(),
+            bitset.iter().collect::<Vec<_>>()
+        );
+        for id in 0..1100 {
+            assert_eq!(vec.contains(id), bitset.contains(id));
+        }
+        assert!(BitsetPostings::default().is_empty());
+
+        vec.remove(64);
+        bitset.remove(64);
+        vec.shift(64, false);
+        bitset.shift(64, false);
+        assert_eq!(vec![0, 1, 63, 64, 199, 999], vec.iter().collect::<Vec<_>>());
+        assert_eq!(
+            vec.iter().collect::<Vec<_>>(),
+            bitset.iter().collect::<Vec<_>>()
+        );
+        vec.shift(1, true);
+        bitset.shift(1, true);
+        assert_eq!(
+            vec![0, 2, 64, 65, 200, 1000],
+            vec.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec.iter().collect::<Vec<_>>(),
+            bitset.iter().collect::<Vec<_>>()
+        );
+
+        // Common words take much less room as bitsets.
+        let mut vec = VecPostings::default();
+        let mut bitset = BitsetPostings::default();
+        for id in 0..5000 {
+            vec.insert(id);
+            bitset.insert(id);
+        }
+        assert!(bitset.estimated_size() * 10 < vec.estimated_size());
+    }
+}
@@ -0,0 +1,158 @@
+use serde::{Deserialize, Serialize};
+
+/////////////////////////////////////////////////////////////////////////////
+// Language Enum
+/////////////////////////////////////////////////////////////////////////////
+
+/// A language that detect can tell apart. Languages written in the Latin
+/// script are told apart by their most common words, the others by their
+/// script alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Language {
+    English,
+    Spanish,
+    Portuguese,
+    French,
+    German,
+    Italian,
+    Russian,
+    Greek,
+    Arabic,
+    Chinese,
+    Japanese,
+    Korean,
+}
+
+const STOPWORDS: &[(Language, &[&str])] = &[
+    (
+        Language::English,
+        &[
+            "the", "and", "is", "are", "you", "that", "of", "to", "it", "with", "this", "what",
+            "have", "not", "i", "my",
+        ],
+    ),
+    (
+        Language::Spanish,
+        &[
+            "el", "la", "los", "las", "que", "es", "y", "de", "en", "un", "una", "por", "con",
+            "no", "muy", "pero", "está",
+        ],
+    ),
+    (
+        Language::Portuguese,
+        &[
+            "o", "os", "as", "que", "é", "e", "de", "em", "um", "uma", "não", "com", "por",
+            "muito", "você", "está",
+        ],
+    ),
+    (
+        Language::French,
+        &[
+            "le", "la", "les", "et", "est", "que", "de", "un", "une", "je", "tu", "vous", "pas",
+            "avec", "des", "c'est",
+        ],
+    ),
+    (
+        Language::German,
+        &[
+            "der", "die", "das", "und", "ist", "nicht", "ich", "du", "ein", "eine", "mit", "auch",
+            "zu", "sie", "es",
+        ],
+    ),
+    (
+        Language::Italian,
+        &[
+            "il", "lo", "la", "che", "e", "è", "di", "un", "una", "non", "per", "con", "sono", "mi",
+        ],
+    ),
+];
+
+// detect guesses the language of the text. Text mostly in a script other than
+// Latin is taken to be in the main language of that script. Latin text is in
+// the language with the most common words in it, and None is returned when no
+// language has more than the others, e.g. for text that is too short to tell.
+pub fn detect(text: &str) -> Option<Language> {
+    let mut latin = 0;
+    let mut other: Vec<(Language, usize)> = vec![];
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        let language = match c {
+            '\u{0370}'..='\u{03ff}' => Language::Greek,
+            '\u{0400}'..='\u{04ff}' => Language::Russian,
+            '\u{0600}'..='\u{06ff}' => Language::Arabic,
+            '\u{3040}'..='\u{30ff}' => Language::Japanese,
+            '\u{4e00}'..='\u{9fff}' => Language::Chinese,
+            '\u{1100}'..='\u{11ff}' | '\u{ac00}'..='\u{d7af}' => Language::Korean,
+            _ => {
+                latin += 1;
+                continue;
+            }
+        };
+        match other.iter_mut().find(|(l, _)| *l == language) {
+            Some((_, count)) => *count += 1,
+            None => other.push((language, 1)),
+        }
+    }
+    // Japanese mixes kana with Chinese characters.
+    if other.iter().any(|(l, _)| *l == Language::Japanese) {
+        if let Some(han) = other.iter().position(|(l, _)| *l == Language::Chinese) {
+            let (_, count) = other.remove(han);
+            for (l, c) in other.iter_mut() {
+                if *l == Language::Japanese {
+                    *c += count;
+                }
+            }
+        }
+    }
+    if let Some(&(language, count)) = other.iter().max_by_key(|(_, count)| *count) {
+        if count > latin {
+            return Some(language);
+        }
+    }
+
+    let words: Vec<String> = text
+        .split(|c: char| !(c.is_alphabetic() || c == '\''))
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect();
+    let mut scores: Vec<(Language, usize)> = STOPWORDS
+        .iter()
+        .map(|(language, stopwords)| {
+            let hits = words
+                .iter()
+                .filter(|w| stopwords.contains(&w.as_str()))
+                .count();
+            (*language, hits)
+        })
+        .collect();
+    scores.sort_by_key(|(_, hits)| std::cmp::Reverse(*hits));
+    match scores.as_slice() {
+        [(language, best), (_, second), ..] if *best > *second => Some(*language),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            Some(Language::English),
+            detect("I think that this is the best pizza")
+        );
+        assert_eq!(
+            Some(Language::Spanish),
+            detect("La pizza es muy buena y barata")
+        );
+        assert_eq!(
+            Some(Language::German),
+            detect("Ich weiß nicht, was das ist")
+        );
+        assert_eq!(Some(Language::Russian), detect("Привет, как дела?"));
+        assert_eq!(Some(Language::Japanese), detect("今日はいい天気ですね"));
+        assert_eq!(None, detect("pizza"));
+        assert_eq!(None, detect(""));
+    }
+}
@@ -0,0 +1,58 @@
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use crate::borg::Borg;
+
+/////////////////////////////////////////////////////////////////////////////
+// LearnQueue Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// LearnQueue collects lines to learn, along with their source, without
+/// taking the lock on the Borg, so that busy platforms don't wait on each
+/// other to learn. The lines are learned together when the queue is flushed,
+/// under a single lock. Clones share the same queue.
+#[derive(Debug, Clone, Default)]
+pub struct LearnQueue {
+    lines: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// LearnQueue Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl LearnQueue {
+    pub fn new() -> LearnQueue {
+        LearnQueue::default()
+    }
+
+    // push queues the line sent by the source to be learned on the next
+    // flush.
+    pub fn push(&self, source: &str, line: &str) {
+        self.lock().push((source.to_owned(), line.to_owned()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    // flush learns every queued line, in the order they were pushed, and
+    // empties the queue. Lines pushed while flushing are left for the next
+    // flush. Returns the number of lines learned.
+    pub fn flush(&self, borg: &mut Borg) -> usize {
+        let lines = mem::take(&mut *self.lock());
+        if lines.is_empty() {
+            return 0;
+        }
+        borg.learn_batch(lines)
+    }
+
+    // lock locks the queue. A producer that panicked while pushing leaves the
+    // queue as it was, so a poisoned lock is safe to use.
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<(String, String)>> {
+        self.lines.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
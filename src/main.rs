@@ -17,22 +17,41 @@ extern crate env_logger;
 
 #[macro_use]
 mod util;
+mod alias;
 mod borg;
+mod cache;
 mod config;
+mod confusables;
 mod dictionary;
 mod discord;
+mod gzip;
+mod indices;
+mod language;
+mod learn_queue;
+mod metrics;
+mod pii;
+mod preprocess;
+mod rng;
+mod schedule;
+mod stemmer;
+mod storage;
+#[cfg(feature = "tcp_server")]
+mod tcp;
 mod telegram;
+mod throttle;
 
 use borg::Borg;
-use config::{Config, ConfigError};
+use config::{Config, ConfigError, PlatformConfig};
 use dictionary::Dictionary;
 use futures::lock::Mutex;
 use futures::Future;
+use learn_queue::LearnQueue;
 use std::error;
 use std::fmt;
 use std::path::Path;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 
 /////////////////////////////////////////////////////////////////////////////
 // Platform Error
@@ -41,12 +60,16 @@ use std::sync::Arc;
 #[derive(Debug)]
 pub enum PlatformError {
     TelegramError(telegram::RunError),
+    #[cfg(feature = "tcp_server")]
+    TcpError(std::io::Error),
 }
 
 impl fmt::Display for PlatformError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             PlatformError::TelegramError(ref e) => e.fmt(f),
+            #[cfg(feature = "tcp_server")]
+            PlatformError::TcpError(ref e) => e.fmt(f),
         }
     }
 }
@@ -55,6 +78,8 @@ impl error::Error for PlatformError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
             PlatformError::TelegramError(ref e) => Some(e),
+            #[cfg(feature = "tcp_server")]
+            PlatformError::TcpError(ref e) => Some(e),
         }
     }
 }
@@ -115,7 +140,10 @@ async fn main() {
 
     debug!("Config {:?} loaded.", CONFIG_PATH);
 
-    let mut dict = match Dictionary::load(Path::new(&config.dictionary_path)) {
+    let mut dict = match Dictionary::load_or_seed(
+        Path::new(&config.dictionary_path),
+        config.seed_dictionary,
+    ) {
         Ok(d) => d,
         Err(e) => match e {
             dictionary::Error::IOError(e) => {
@@ -142,49 +170,107 @@ async fn main() {
 
     debug!("Dictionary {:?} loaded.", &config.dictionary_path);
 
+    dict.set_options(config.dictionary_options.clone());
+
+    if config.dictionary_options.dedup_on_load {
+        let removed = dict.dedup();
+        if removed > 0 {
+            warn!("Removed {} duplicate sentences.", removed);
+            if let Err(e) = save_dictionary(&config, &mut dict) {
+                error!("Couldn't save dictionary, error: {:?}", e)
+            }
+        }
+    }
+
     if dict.needs_to_build_indices() {
         warn!("Indices need to be built. Building indices.");
         dict.rebuild_indices();
         warn!("Indices built.");
 
-        if let Err(e) = save_dictionary(&config, &dict) {
+        if let Err(e) = save_dictionary(&config, &mut dict) {
             error!("Couldn't save dictionary, error: {:?}", e)
         }
     }
 
+    dict.warm_up();
+
     let borg = Arc::new(Mutex::new(Borg::new(dict, config.behavior)));
     let mut tasks: PlatformTasks = vec![];
 
-    let telegram_context = match config.telegram {
-        Some(telegram_config) => Some(Arc::new(Mutex::new(
-            match telegram::Context::new(telegram_config, borg.clone()) {
-                Ok(o) => o,
-                Err(e) => {
-                    error!("Could not start Telegram. Error: {}", e);
-                    return;
+    let learn_queue = config.learn_queue_interval_ms.map(|interval| {
+        let queue = LearnQueue::new();
+        let (borg, flushed) = (borg.clone(), queue.clone());
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(Duration::from_millis(interval));
+            loop {
+                ticks.tick().await;
+                let learned = flushed.flush(&mut *borg.lock().await);
+                if learned > 0 {
+                    debug!("Learned {} queued lines.", learned);
                 }
-            },
-        ))),
-        None => None,
-    };
+            }
+        });
+        queue
+    });
 
-    if let Some(telegram_context) = telegram_context {
-        tasks.push(Box::pin(async move {
-            match telegram::run(telegram_context.clone()).await {
-                Err(e) => Err(PlatformError::TelegramError(e)),
-                Ok(_) => Ok(()),
+    for platform in config.platforms.into_iter().filter(|p| p.is_enabled()) {
+        match platform {
+            PlatformConfig::Telegram(telegram_config) => {
+                let telegram_context = Arc::new(Mutex::new(
+                    match telegram::Context::new(telegram_config, borg.clone(), learn_queue.clone())
+                    {
+                        Ok(o) => o,
+                        Err(e) => {
+                            error!("Could not start Telegram. Error: {}", e);
+                            return;
+                        }
+                    },
+                ));
+                tasks.push(Box::pin(async move {
+                    match telegram::run(telegram_context.clone()).await {
+                        Err(e) => Err(PlatformError::TelegramError(e)),
+                        Ok(_) => Ok(()),
+                    }
+                }));
             }
-        }));
+            #[cfg(feature = "tcp_server")]
+            PlatformConfig::Tcp(tcp_config) => {
+                let borg = borg.clone();
+                tasks.push(Box::pin(async move {
+                    tcp::serve_tcp(&tcp_config.address, borg, tcp_config.learn)
+                        .await
+                        .map_err(PlatformError::TcpError)
+                }));
+            }
+            other => warn!("{} is not supported yet, not starting it.", other.name()),
+        }
     }
 
-    for result in futures::future::join_all(tasks).await {
-        if let Err(e) = result {
-            error!("Task exited with an error: {}", e);
+    tokio::select! {
+        results = futures::future::join_all(tasks) => {
+            for result in results {
+                if let Err(e) = result {
+                    error!("Task exited with an error: {}", e);
+                }
+            }
         }
+        _ = tokio::signal::ctrl_c() => {
+            warn!("Interrupted, shutting down.");
+        }
+    }
+
+    let mut borg = borg.lock().await;
+    if let Some(queue) = learn_queue {
+        queue.flush(&mut borg);
+    }
+    match borg.save_if_dirty(Path::new(&config.dictionary_path)) {
+        Ok(true) => debug!("Dictionary saved."),
+        Ok(false) => debug!("Dictionary unchanged, not saving it."),
+        Err(e) => error!("Couldn't save dictionary, error: {:?}", e),
     }
 }
 
-fn save_dictionary(config: &Config, dict: &Dictionary) -> Result<(), dictionary::Error> {
+fn save_dictionary(config: &Config, dict: &mut Dictionary) -> Result<(), dictionary::Error> {
     match dict.write_to_disk(Path::new(&config.dictionary_path)) {
         Ok(_) => Ok(()),
         Err(e) => {
@@ -0,0 +1,78 @@
+/////////////////////////////////////////////////////////////////////////////
+// Metrics Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// Counters of what a Borg has done since it was started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Messages that were considered for a reply.
+    pub messages_seen: u64,
+    /// Messages that were passed on to the dictionary to be learned.
+    pub messages_learned: u64,
+    /// Replies that were said.
+    pub replies: u64,
+    /// Messages that were not replied to because of the reply rates, the
+    /// speaking setting or quiet hours.
+    pub replies_suppressed: u64,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Metrics Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl Metrics {
+    // render_prometheus renders the counters in the Prometheus text exposition
+    // format, ready to be served to a scraper.
+    #[cfg(feature = "prometheus")]
+    pub fn render_prometheus(&self) -> String {
+        let counters = [
+            (
+                "borg_messages_seen_total",
+                "Messages that were considered for a reply.",
+                self.messages_seen,
+            ),
+            (
+                "borg_messages_learned_total",
+                "Messages that were learned.",
+                self.messages_learned,
+            ),
+            (
+                "borg_replies_total",
+                "Replies that were said.",
+                self.replies,
+            ),
+            (
+                "borg_replies_suppressed_total",
+                "Messages that were not replied to.",
+                self.replies_suppressed,
+            ),
+        ];
+        let mut text = String::new();
+        for (name, help, value) in counters.iter() {
+            text.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} counter\n{} {}\n",
+                name, help, name, name, value
+            ));
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_render_prometheus() {
+        use super::*;
+
+        let metrics = Metrics {
+            messages_seen: 3,
+            ..Default::default()
+        };
+        let text = metrics.render_prometheus();
+        assert!(
+            text.contains("# TYPE borg_messages_seen_total counter\nborg_messages_seen_total 3\n")
+        );
+        assert!(text.contains("borg_replies_total 0\n"));
+    }
+}
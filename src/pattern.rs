@@ -49,6 +49,19 @@ pub struct Pattern {
     pub original: String,
 }
 
+// Compiled regexes can't be cloned, so a clone compiles its own copy.
+impl Clone for Pattern {
+    fn clone(&self) -> Pattern {
+        Pattern {
+            compiled: self
+                .compiled
+                .as_ref()
+                .and_then(|_| Regex::new(&self.original).ok()),
+            original: self.original.clone(),
+        }
+    }
+}
+
 impl Pattern {
     pub fn compile(&mut self) -> Result<&Regex, CompilationError> {
         match self.compiled {
@@ -0,0 +1,41 @@
+use onig::Regex;
+
+const EMAIL_PLACEHOLDER: &str = "[email]";
+const CARD_PLACEHOLDER: &str = "[card]";
+const PHONE_PLACEHOLDER: &str = "[phone]";
+
+// mask replaces email addresses, credit card numbers and phone numbers in the
+// text with placeholders, so that they are never learned and repeated. Card
+// numbers are runs of 13 to 19 digits, phone numbers runs of 7 to 15 digits,
+// either possibly broken up by spaces, dashes and the like. Any such run is
+// masked, whether or not it is really a card or phone number.
+pub fn mask(text: &str) -> String {
+    lazy_static! {
+        static ref EMAIL: Regex = Regex::new(r"[\w.+-]+@[\w-]+(?:\.[\w-]+)+").unwrap();
+        static ref CARD: Regex = Regex::new(r"(?<!\d)\d(?:[ -]?\d){12,18}(?!\d)").unwrap();
+        static ref PHONE: Regex =
+            Regex::new(r"(?<![\d+])\+?\(?\d(?:[ ().-]{0,2}\d){6,14}(?!\d)").unwrap();
+    }
+    let text = EMAIL.replace_all(text, EMAIL_PLACEHOLDER);
+    let text = CARD.replace_all(&text, CARD_PLACEHOLDER);
+    PHONE.replace_all(&text, PHONE_PLACEHOLDER)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask() {
+        assert_eq!(
+            "write to [email] or call [phone].",
+            mask("write to josh.smith+bot@example.co.uk or call +1 (555) 123-4567.")
+        );
+        assert_eq!("my card is [card]", mask("my card is 4111 1111 1111 1111"));
+        assert_eq!("call [phone] now", mask("call 555-1234 now"));
+        assert_eq!(
+            "I ate 12 pizzas in 2020, at 10:30. Email me!",
+            mask("I ate 12 pizzas in 2020, at 10:30. Email me!")
+        );
+    }
+}
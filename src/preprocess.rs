@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+
+/////////////////////////////////////////////////////////////////////////////
+// InputTransform Enum
+/////////////////////////////////////////////////////////////////////////////
+
+/// A rewrite applied to incoming lines before they are learned or replied
+/// to, e.g. to expand abbreviations.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InputTransform {
+    Lowercase,
+    /// Removes the words starting with "@", such as "@borg".
+    StripMentions,
+    /// Replaces every word equal to `from`, ignoring case, with `to`.
+    ReplaceWord {
+        from: String,
+        to: String,
+    },
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// InputTransform Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl InputTransform {
+    pub fn apply(&self, line: &str) -> String {
+        match self {
+            InputTransform::Lowercase => line.to_lowercase(),
+            InputTransform::StripMentions => line
+                .split_whitespace()
+                .filter(|w| !w.starts_with('@'))
+                .collect::<Vec<_>>()
+                .join(" "),
+            InputTransform::ReplaceWord { from, to } => line
+                .split(' ')
+                .map(|w| {
+                    let (word, punctuation) = split_trailing_punctuation(w);
+                    if word.to_lowercase() == from.to_lowercase() {
+                        format!("{}{}", to, punctuation)
+                    } else {
+                        w.to_owned()
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+}
+
+// apply_all runs the line through every transform, in order.
+pub fn apply_all(line: &str, transforms: &[InputTransform]) -> String {
+    transforms
+        .iter()
+        .fold(line.to_owned(), |line, transform| transform.apply(&line))
+}
+
+// split_trailing_punctuation splits a word from the punctuation that ends it,
+// so that "u?" can be matched as "u".
+fn split_trailing_punctuation(word: &str) -> (&str, &str) {
+    let end = word
+        .trim_end_matches(|c: char| c.is_ascii_punctuation())
+        .len();
+    word.split_at(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_all() {
+        let transforms = vec![
+            InputTransform::StripMentions,
+            InputTransform::ReplaceWord {
+                from: "u".to_string(),
+                to: "you".to_string(),
+            },
+            InputTransform::Lowercase,
+        ];
+        assert_eq!(
+            "how are you? fun, but you are not",
+            apply_all("@borg How are U? fun, but u are not", &transforms)
+        );
+        assert_eq!("unchanged", apply_all("unchanged", &[]));
+    }
+}
@@ -0,0 +1,74 @@
+use rand::rngs::SmallRng;
+use rand_core::{Error, RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+/////////////////////////////////////////////////////////////////////////////
+// BorgRng Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// BorgRng is a small, fast random number generator (SplitMix64) whose whole
+/// state is a single number that can be saved and restored, so that a bot
+/// can be moved around without changing what it would say next.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BorgRng {
+    state: u64,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// BorgRng Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl BorgRng {
+    pub fn new(seed: u64) -> BorgRng {
+        BorgRng { state: seed }
+    }
+
+    pub fn from_entropy() -> BorgRng {
+        BorgRng::new(SmallRng::from_entropy().next_u64())
+    }
+}
+
+impl RngCore for BorgRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clone_continues_the_same_sequence() {
+        let mut rng = BorgRng::new(42);
+        rng.next_u64();
+        let mut copy = rng.clone();
+        for _ in 0..10 {
+            assert_eq!(rng.next_u64(), copy.next_u64());
+        }
+
+        let mut bytes = [0u8; 11];
+        rng.fill_bytes(&mut bytes);
+        assert_ne!([0u8; 11], bytes);
+    }
+}
@@ -0,0 +1,202 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/////////////////////////////////////////////////////////////////////////////
+// Weekday Enum
+/////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+const WEEKDAYS: [Weekday; 7] = [
+    Weekday::Monday,
+    Weekday::Tuesday,
+    Weekday::Wednesday,
+    Weekday::Thursday,
+    Weekday::Friday,
+    Weekday::Saturday,
+    Weekday::Sunday,
+];
+
+/////////////////////////////////////////////////////////////////////////////
+// TimeOfDay Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// A time of day, written as "HH:MM" in the configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    minutes: u32,
+}
+
+impl TimeOfDay {
+    pub fn new(hour: u32, minute: u32) -> Option<TimeOfDay> {
+        if hour < 24 && minute < 60 {
+            Some(TimeOfDay {
+                minutes: hour * 60 + minute,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let mut parts = s.splitn(2, ':');
+        let hour = parts.next().and_then(|h| h.trim().parse().ok());
+        let minute = parts.next().and_then(|m| m.trim().parse().ok());
+        match (hour, minute) {
+            (Some(hour), Some(minute)) => TimeOfDay::new(hour, minute),
+            _ => None,
+        }
+        .ok_or_else(|| format!("invalid time of day {:?}, expected HH:MM", s))
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(t: TimeOfDay) -> String {
+        t.to_string()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.minutes / 60, self.minutes % 60)
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// QuietHours Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// A weekly schedule of periods during which the bot does not reply.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuietHours {
+    /// Offset of the schedule's timezone from UTC, in minutes.
+    #[serde(default)]
+    pub utc_offset_minutes: i32,
+    pub periods: Vec<QuietPeriod>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuietPeriod {
+    /// Days on which the period starts. When empty, it starts every day.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: TimeOfDay,
+    /// When the end is before the start, the period runs past midnight into
+    /// the next day.
+    pub end: TimeOfDay,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// QuietHours Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl QuietHours {
+    pub fn is_quiet(&self, now: SystemTime) -> bool {
+        let secs = match now.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        };
+        let local = secs + i64::from(self.utc_offset_minutes) * 60;
+        let days = local.div_euclid(86400);
+        // The Unix epoch fell on a Thursday.
+        let today = WEEKDAYS[(days + 3).rem_euclid(7) as usize];
+        let yesterday = WEEKDAYS[(days + 2).rem_euclid(7) as usize];
+        let time = TimeOfDay {
+            minutes: (local.rem_euclid(86400) / 60) as u32,
+        };
+        self.periods
+            .iter()
+            .any(|p| p.contains(today, yesterday, time))
+    }
+}
+
+impl QuietPeriod {
+    fn starts_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    fn contains(&self, today: Weekday, yesterday: Weekday, time: TimeOfDay) -> bool {
+        if self.start <= self.end {
+            self.starts_on(today) && self.start <= time && time < self.end
+        } else {
+            (self.starts_on(today) && self.start <= time)
+                || (self.starts_on(yesterday) && time < self.end)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // at returns the time at the given day and time, counting days from
+    // Monday, January 5th 1970 (UTC).
+    fn at(day: u64, hour: u64, minute: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(((4 + day) * 24 + hour) * 3600 + minute * 60)
+    }
+
+    fn time(hour: u32, minute: u32) -> TimeOfDay {
+        TimeOfDay::new(hour, minute).unwrap()
+    }
+
+    #[test]
+    fn test_time_of_day_from_string() {
+        assert_eq!(Ok(time(22, 30)), TimeOfDay::try_from("22:30".to_string()));
+        assert!(TimeOfDay::try_from("24:00".to_string()).is_err());
+        assert!(TimeOfDay::try_from("noon".to_string()).is_err());
+        assert_eq!("07:05", time(7, 5).to_string());
+    }
+
+    #[test]
+    fn test_is_quiet() {
+        let quiet_hours = QuietHours {
+            utc_offset_minutes: 0,
+            periods: vec![
+                QuietPeriod {
+                    days: vec![Weekday::Friday],
+                    start: time(23, 0),
+                    end: time(7, 0),
+                },
+                QuietPeriod {
+                    days: vec![],
+                    start: time(12, 0),
+                    end: time(13, 0),
+                },
+            ],
+        };
+
+        assert!(quiet_hours.is_quiet(at(4, 23, 30))); // Friday night
+        assert!(quiet_hours.is_quiet(at(5, 6, 59))); // Saturday morning
+        assert!(!quiet_hours.is_quiet(at(5, 7, 0)));
+        assert!(!quiet_hours.is_quiet(at(3, 23, 30))); // Thursday night
+        assert!(quiet_hours.is_quiet(at(2, 12, 15))); // Every day at noon
+        assert!(!quiet_hours.is_quiet(at(2, 13, 0)));
+
+        // 23:30 on Friday in UTC-3 is 02:30 on Saturday in UTC.
+        let quiet_hours = QuietHours {
+            utc_offset_minutes: -180,
+            ..quiet_hours
+        };
+        assert!(quiet_hours.is_quiet(at(5, 2, 30)));
+        assert!(!quiet_hours.is_quiet(at(4, 23, 30)));
+    }
+}
@@ -0,0 +1,35 @@
+use std::borrow::Cow;
+
+// stem strips the plural ending of an English word, so that singular and
+// plural forms of a word share a stem. This is the "S" stemmer described by
+// Harman (1991), which is much lighter than a full Porter stemmer. Words
+// shorter than four letters are left alone to keep "is" and "us" apart from
+// "i" and "u".
+pub fn stem(word: &str) -> Cow<'_, str> {
+    if word.chars().count() < 4 {
+        Cow::Borrowed(word)
+    } else if word.ends_with("ies") && !word.ends_with("eies") && !word.ends_with("aies") {
+        Cow::Owned(format!("{}y", &word[..word.len() - 3]))
+    } else if word.ends_with('s') && !word.ends_with("us") && !word.ends_with("ss") {
+        // This covers the "es" rule too: "horses" becomes "horse".
+        Cow::Borrowed(&word[..word.len() - 1])
+    } else {
+        Cow::Borrowed(word)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stem() {
+        assert_eq!("crab", stem("crabs"));
+        assert_eq!("pony", stem("ponies"));
+        assert_eq!("horse", stem("horses"));
+        assert_eq!("is", stem("is"));
+        assert_eq!("glass", stem("glass"));
+        assert_eq!("virus", stem("virus"));
+        assert_eq!("toe", stem("toes"));
+    }
+}
@@ -0,0 +1,57 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::dictionary::{self, Dictionary, Error};
+
+/////////////////////////////////////////////////////////////////////////////
+// Storage Trait
+/////////////////////////////////////////////////////////////////////////////
+
+/// Storage is where a dictionary is kept between runs, e.g. a JSON file or a
+/// database. Backends whose errors are neither I/O nor JSON errors can wrap
+/// them in an io::Error.
+pub trait Storage {
+    // load returns the stored dictionary as it was saved, or None if none
+    // was saved yet.
+    fn load(&mut self) -> Result<Option<Dictionary>, Error>;
+
+    // save stores the dictionary, replacing the one stored before.
+    fn save(&mut self, dictionary: &Dictionary) -> Result<(), Error>;
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// FileStorage Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// FileStorage keeps the dictionary as a single JSON file, which is the
+/// default storage.
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    path: PathBuf,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// FileStorage Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl FileStorage {
+    pub fn new(path: &Path) -> FileStorage {
+        FileStorage {
+            path: path.to_owned(),
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&mut self) -> Result<Option<Dictionary>, Error> {
+        if !self.path.is_file() {
+            return Ok(None);
+        }
+        let data = fs::read_to_string(&self.path)?;
+        Ok(Some(serde_json::from_str(&data)?))
+    }
+
+    fn save(&mut self, dictionary: &Dictionary) -> Result<(), Error> {
+        dictionary::write_json(&self.path, dictionary)
+    }
+}
@@ -0,0 +1,99 @@
+use std::io;
+use std::sync::Arc;
+
+use futures::lock::Mutex;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::borg::Borg;
+
+/////////////////////////////////////////////////////////////////////////////
+// Run Method
+/////////////////////////////////////////////////////////////////////////////
+
+// serve_tcp listens on the address and serves every connection, so that bots
+// written in any language can use Borg as a backend by exchanging lines of
+// text with it. It only returns if the listener fails.
+pub async fn serve_tcp(addr: &str, borg: Arc<Mutex<Borg>>, learn: bool) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    serve(listener, borg, learn).await
+}
+
+async fn serve(mut listener: TcpListener, borg: Arc<Mutex<Borg>>, learn: bool) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        debug!("[serve_tcp] Accepted connection from {}", peer);
+        let borg = borg.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, borg, learn).await {
+                warn!("[serve_tcp] Connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+// serve_connection reads lines from the stream until it is closed, learning
+// each one if asked to, and writes back one line for every line read: the
+// reply, or an empty line if there is none.
+async fn serve_connection(
+    mut stream: TcpStream,
+    borg: Arc<Mutex<Borg>>,
+    learn: bool,
+) -> io::Result<()> {
+    let (reader, mut writer) = stream.split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let reply = {
+            let mut borg = borg.lock().await;
+            if learn {
+                borg.learn(&line);
+            }
+            borg.respond_to(&line)
+        };
+        // A reply spanning several lines would throw the client off.
+        let mut reply = reply
+            .unwrap_or_default()
+            .replace(|c| c == '\n' || c == '\r', " ");
+        reply.push('\n');
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::borg::test_behavior;
+    use crate::config::MainBehavior;
+    use crate::dictionary::Dictionary;
+
+    #[tokio::test]
+    async fn test_serve_tcp() {
+        let behavior = MainBehavior {
+            speaking: true,
+            learning: true,
+            ..test_behavior()
+        };
+        let borg = Arc::new(Mutex::new(Borg::new(Dictionary::new_empty(), behavior)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, borg, true));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let (reader, mut writer) = stream.split();
+        let mut lines = BufReader::new(reader).lines();
+
+        writer
+            .write_all(b"Hello world. Hello world!\n")
+            .await
+            .unwrap();
+        let reply = lines.next_line().await.unwrap().unwrap();
+        assert!(reply.starts_with("hello world"), "{:?}", reply);
+
+        writer
+            .write_all(b"Something else entirely\n")
+            .await
+            .unwrap();
+        assert_eq!(Some(String::new()), lines.next_line().await.unwrap());
+    }
+}
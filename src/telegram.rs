@@ -1,6 +1,6 @@
 use std::{error, fmt, sync::Arc};
 
-use carapax::types::Message;
+use carapax::types::{Message, MessageData};
 use carapax::{
     longpoll::LongPoll, Api, ApiError, Dispatcher, ErrorPolicy, HandlerResult, LoggingErrorHandler,
 };
@@ -9,12 +9,21 @@ use futures::lock::Mutex;
 use crate::{
     borg::Borg,
     config,
-    config::{BehaviorOverride, BehaviorOverrideValueResolver},
+    config::{BehaviorOverride, BehaviorOverrideValueResolver, MessageKind},
+    learn_queue::LearnQueue,
 };
 use carapax::handler;
 use carapax::methods::SendMessage;
 use futures::TryFutureExt;
 
+/////////////////////////////////////////////////////////////////////////////
+// Constants
+/////////////////////////////////////////////////////////////////////////////
+
+// The longest message Telegram accepts, in characters. Longer replies are
+// sent in several messages.
+const MAX_MESSAGE_CHARS: usize = 4096;
+
 /////////////////////////////////////////////////////////////////////////////
 // RunError
 /////////////////////////////////////////////////////////////////////////////
@@ -105,6 +114,7 @@ pub struct Context {
     borg: Arc<Mutex<Borg>>,
     platform_config: config::TelegramPlatform,
     api: Api,
+    learn_queue: Option<LearnQueue>,
 }
 
 /////////////////////////////////////////////////////////////////////////////
@@ -115,12 +125,14 @@ impl Context {
     pub fn new(
         platform_config: config::TelegramPlatform,
         borg: Arc<Mutex<Borg>>,
+        learn_queue: Option<LearnQueue>,
     ) -> Result<Context, ApiError> {
         let token = platform_config.token.clone();
         Api::new(token).map(|api| Context {
             borg,
             platform_config,
             api,
+            learn_queue,
         })
     }
 
@@ -162,23 +174,49 @@ async fn handle(context: &Arc<Mutex<Context>>, message: Message) -> HandlerResul
             let input = text.data.as_str();
             let user_id = &user.id.to_string();
             let chat_id = message.get_chat_id();
+            // The text of the message this one replies to, which it may quote.
+            let quoted = message
+                .reply_to
+                .as_ref()
+                .and_then(|m| m.get_text())
+                .map(|t| t.data.as_str());
             let mut borg = context.borg.lock().await;
 
-            if borg.should_learn(user_id, input, &behavior) {
-                borg.learn(input);
+            // Messages with text other than text messages are media captions.
+            let kind = match message.data {
+                MessageData::Text(..) => MessageKind::Text,
+                _ => MessageKind::Caption,
+            };
+            let learn = borg.learns_kind(kind) && borg.should_learn(user_id, input, &behavior);
+            let reply = borg.should_reply_to(user_id, input, &behavior);
+            let response = match (reply, learn) {
+                // Learns the message first if the behavior says to.
+                (true, true) => borg.handle_quoting(user_id, input, quoted, kind),
+                (true, false) => borg.respond_to_user(user_id, input),
+                (false, _) => None,
+            };
+            // Messages not learned before replying are learned after, so
+            // that the reply isn't built from the message it answers.
+            if learn && !(reply && borg.learns_before_responding()) {
+                match (&context.learn_queue, quoted) {
+                    (Some(queue), Some(quoted)) => {
+                        queue.push(user_id, &borg.unquote(input, quoted))
+                    }
+                    (Some(queue), None) => queue.push(user_id, input),
+                    (None, Some(quoted)) => {
+                        borg.learn_quoting(user_id, input, quoted);
+                    }
+                    (None, None) => {
+                        borg.learn_from(user_id, input);
+                    }
+                }
             }
 
-            if borg.should_reply_to(user_id, input, &behavior) {
-                if let Some(response) = borg.respond_to(input) {
-                    match context
-                        .api
-                        .execute(SendMessage::new(chat_id, response))
-                        .await
-                    {
-                        Ok(..) => {}
-                        Err(e) => {
-                            error!("ExecuteError: {}", e);
-                        }
+            if let Some(response) = response {
+                for chunk in crate::util::chunk_reply(&response, MAX_MESSAGE_CHARS) {
+                    if let Err(e) = context.api.execute(SendMessage::new(chat_id, chunk)).await {
+                        error!("ExecuteError: {}", e);
+                        break;
                     }
                 }
             }
@@ -0,0 +1,82 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+use crate::config::LearnLimit;
+
+/////////////////////////////////////////////////////////////////////////////
+// LearnThrottle Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// LearnThrottle keeps track of how many sentences each source has taught
+/// recently, so that floods of messages can be kept out of the dictionary.
+#[derive(Debug, Default)]
+pub struct LearnThrottle {
+    history: HashMap<String, VecDeque<(SystemTime, usize)>>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// LearnThrottle Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl LearnThrottle {
+    pub fn new() -> LearnThrottle {
+        LearnThrottle::default()
+    }
+
+    // allow decides whether the source may teach the given number of
+    // sentences at the given time, and records them if so.
+    pub fn allow(
+        &mut self,
+        source: &str,
+        sentences: usize,
+        limit: &LearnLimit,
+        now: SystemTime,
+    ) -> bool {
+        let window = Duration::from_secs(limit.window_secs);
+        // Sources whose sentences all fell out of the window are forgotten.
+        self.history.retain(|_, history| {
+            while let Some((at, _)) = history.front() {
+                match now.duration_since(*at) {
+                    Ok(elapsed) if elapsed >= window => {
+                        history.pop_front();
+                    }
+                    _ => break,
+                }
+            }
+            !history.is_empty()
+        });
+
+        let history = self.history.entry(source.to_owned()).or_default();
+        let learned: usize = history.iter().map(|(_, n)| n).sum();
+        if learned + sentences > limit.max_sentences {
+            if history.is_empty() {
+                self.history.remove(source);
+            }
+            return false;
+        }
+        history.push_back((now, sentences));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forgets_sources() {
+        let limit = LearnLimit {
+            max_sentences: 2,
+            window_secs: 60,
+        };
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let mut throttle = LearnThrottle::new();
+        assert!(throttle.allow("a", 1, &limit, t0));
+        assert!(throttle.allow("b", 2, &limit, t0));
+        assert!(!throttle.allow("c", 3, &limit, t0));
+        assert_eq!(2, throttle.history.len());
+
+        assert!(throttle.allow("a", 1, &limit, t0 + Duration::from_secs(60)));
+        assert_eq!(1, throttle.history.len());
+    }
+}
@@ -24,3 +24,69 @@ pub fn unix_time() -> u64 {
         .expect("Time went backwards")
         .as_secs()
 }
+
+// chunk_reply splits the reply into messages of at most max_chars characters
+// each, breaking it between words, so that replies too long for a platform
+// can be sent in several messages. Words longer than max_chars are broken up.
+pub fn chunk_reply(reply: &str, max_chars: usize) -> Vec<String> {
+    let max_chars = max_chars.max(1);
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    let mut chunk_chars = 0;
+    for word in reply.split_whitespace() {
+        let word_chars = word.chars().count();
+        if chunk_chars > 0 && chunk_chars + 1 + word_chars <= max_chars {
+            chunk.push(' ');
+            chunk.push_str(word);
+            chunk_chars += 1 + word_chars;
+            continue;
+        }
+        if chunk_chars > 0 {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+        let mut pieces: Vec<String> = word
+            .chars()
+            .collect::<Vec<_>>()
+            .chunks(max_chars)
+            .map(|piece| piece.iter().collect())
+            .collect();
+        // The last piece may have room for the words after it.
+        chunk = pieces.pop().unwrap_or_default();
+        chunk_chars = chunk.chars().count();
+        chunks.extend(pieces);
+    }
+    if chunk_chars > 0 {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_reply() {
+        let reply = "the quick brown fox jumps over the lazy dog";
+        assert_eq!(vec![reply.to_string()], chunk_reply(reply, 100));
+        assert_eq!(
+            vec!["the quick", "brown fox", "jumps", "over the", "lazy dog"],
+            chunk_reply(reply, 9)
+        );
+
+        let long: String = (0..500).map(|i| format!("word{} ", i)).collect();
+        let chunks = chunk_reply(&long, 100);
+        assert_eq!(41, chunks.len());
+        assert!(chunks
+            .iter()
+            .all(|c| !c.is_empty() && c.chars().count() <= 100));
+        assert_eq!(long.trim(), chunks.join(" "));
+
+        assert_eq!(
+            vec!["abc", "def", "g h", "ij"],
+            chunk_reply("abcdefg h ij", 3)
+        );
+        assert_eq!(vec!["ñ", "ñ"], chunk_reply("ññ", 1));
+        assert!(chunk_reply("   ", 10).is_empty());
+    }
+}